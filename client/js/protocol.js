@@ -0,0 +1,25 @@
+// Browser side of the websocket protocol spoken by spectators and players,
+// see src/protocol.rs for the messages. Every message carries the protocol
+// version in `v`.
+var PROTOCOL_VERSION = 1;
+
+// Parses a message from the server, null if it's for another version
+function decodeMessage(text) {
+    var message = JSON.parse(text);
+    if (message.v !== PROTOCOL_VERSION) {
+        console.error(`Unsupported protocol version ${message.v}, expected ${PROTOCOL_VERSION}`);
+        return null;
+    }
+    return message;
+}
+
+function encodeMessage(type, fields) {
+    return JSON.stringify(Object.assign({ v: PROTOCOL_VERSION, type: type }, fields));
+}
+
+// Evaluation score for people, from white's point of view
+function formatScore(score) {
+    if (score.mate !== undefined) return `#${score.mate}`;
+    var pawns = score.cp / 100;
+    return (pawns > 0 ? "+" : "") + pawns.toFixed(2);
+}
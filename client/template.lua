@@ -3,12 +3,20 @@ MyRandomBot = {}
 
 ---Your bot must have a chooseMove method
 ---Note that the `:` notation provides self as an implicit argument
----@param chess_game any
+---@param chess_game table the current game, `chess_game.fen` is the position in FEN.
+---`side` is "white" or "black", `history` has the moves played so far in uci
+---notation starting from `start_fen`, `opponent` is who you're playing and
+---`draw_offered` says whether they offered a draw with their last move.
+---In games with a clock it also has `white_ms`, `black_ms`, `increment_ms`,
+---`moves_to_go` and `move_time_ms` (the last two only for some time controls)
 ---@param legal_moves string[] list of legal moves in uci notation
----@return string move to make in uci notation
+---@return string|table move to make in uci notation, or a table with the move
+---in `move` and optionally `score` (centipawns, from your point of view) or
+---`mate`, `depth`, `pv` (a list of uci moves), `resign` and `offer_draw`.
+---Offering a draw when `draw_offered` is set accepts it
 function MyRandomBot:chooseMove(chess_game, legal_moves)
     return legal_moves[ math.random( #legal_moves ) ]
 end
 
 ---Return must return your bot from the script
-return MyRandomBot
\ No newline at end of file
+return MyRandomBot
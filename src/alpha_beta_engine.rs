@@ -0,0 +1,735 @@
+// A chess engine of our own, so there is something worth playing against
+// without installing stockfish. Iterative deepening alpha-beta with a
+// quiescence search, a transposition table and piece-square tables. Below
+// the top skill level it searches shallower and picks among the moves close
+// to the best one at random.
+use rand::Rng;
+use shakmaty::uci::Uci;
+use shakmaty::zobrist::{Zobrist64, ZobristHash};
+use shakmaty::{CastlingMode, Chess, Color, EnPassantMode, Move, MoveList, Position, Role, Square};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::chess_engine::{ChooseMove, SearchInfo, SearchReply, SearchRequest, StopSignal};
+use crate::chess_game::ChessGame;
+use crate::clock::TimeLeft;
+use crate::protocol::Score;
+
+pub const MAX_SKILL: u8 = 20;
+
+const INFINITY: i32 = 1_000_000;
+const MATE: i32 = 100_000;
+// scores past this are mates, the distance to mate is taken off MATE
+const MATE_BOUND: i32 = MATE - 1_000;
+const MAX_PLY: usize = 100;
+// nodes searched between looking at the clock
+const CHECK_EVERY: u64 = 1024;
+
+#[derive(Clone, Debug)]
+pub struct AlphaBetaConfig {
+    /// Deepest iteration searched, in plies
+    pub max_depth: u32,
+    /// Nodes searched per move at most, no limit when `None`
+    pub max_nodes: Option<u64>,
+    /// Time per move in games without a clock, and the most it thinks in
+    /// games with one
+    pub move_time: Duration,
+    /// 0 to `MAX_SKILL`, lower levels see less and play the best move less
+    /// often
+    pub skill: u8,
+    /// Size of the transposition table, rounded up to a power of two
+    pub hash_entries: usize,
+}
+
+impl Default for AlphaBetaConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AlphaBetaConfig {
+    /// Full strength
+    pub fn new() -> Self {
+        Self {
+            max_depth: 64,
+            max_nodes: None,
+            move_time: Duration::from_secs(1),
+            skill: MAX_SKILL,
+            hash_entries: 1 << 16,
+        }
+    }
+
+    /// Search depth to go with a skill level, level 0 only looks one move
+    /// ahead
+    pub fn with_skill(skill: u8) -> Self {
+        let skill = skill.min(MAX_SKILL);
+        let max_depth = if skill == MAX_SKILL {
+            64
+        } else {
+            1 + u32::from(skill) / 3
+        };
+        Self {
+            max_depth,
+            skill,
+            ..Self::new()
+        }
+    }
+
+    // How far off the best move a move may be and still get picked, in
+    // centipawns
+    fn randomness(&self) -> i32 {
+        i32::from(MAX_SKILL - self.skill.min(MAX_SKILL)) * 12
+    }
+}
+
+pub struct AlphaBetaEngine {
+    config: AlphaBetaConfig,
+    // kept between moves, most of what was searched last move is still
+    // useful
+    table: Mutex<TranspositionTable>,
+}
+
+impl AlphaBetaEngine {
+    pub fn new(config: AlphaBetaConfig) -> Self {
+        let table = TranspositionTable::new(config.hash_entries);
+        Self {
+            config,
+            table: Mutex::new(table),
+        }
+    }
+
+    // Time to spend on this move, a slice of what's left on the clock
+    fn budget(&self, time: Option<&TimeLeft>) -> Duration {
+        let Some(time) = time else {
+            return self.config.move_time;
+        };
+        let budget = match time.move_time {
+            Some(move_time) => move_time.mul_f32(0.9),
+            None => {
+                let moves_left = time.moves_to_go.unwrap_or(30).max(1);
+                (time.own() / moves_left + time.increment / 2).min(time.own() / 2)
+            }
+        };
+        budget.min(self.config.move_time)
+    }
+}
+
+impl ChooseMove for AlphaBetaEngine {
+    fn choose_move(&self, fen: &str, legal_moves: &MoveList) -> Option<Move> {
+        self.choose_move_timed(fen, legal_moves, None)
+    }
+
+    fn choose_move_timed(
+        &self,
+        fen: &str,
+        legal_moves: &MoveList,
+        time: Option<&TimeLeft>,
+    ) -> Option<Move> {
+        let game = ChessGame::from_fen(fen).ok()?;
+        let request = SearchRequest {
+            legal_moves: legal_moves.clone(),
+            time: time.copied(),
+            ..SearchRequest::new(&game)
+        };
+        self.search(&request).best_move
+    }
+
+    fn search(&self, request: &SearchRequest) -> SearchReply {
+        if request.legal_moves.is_empty() {
+            return SearchReply::default();
+        }
+
+        let stop = request
+            .stop
+            .clone()
+            .with_deadline(Instant::now() + self.budget(request.time.as_ref()));
+        let mut table = self.table.lock().unwrap();
+        let mut search = Search::new(&mut table, &self.config, stop, game_positions(request));
+        search.run(request)
+    }
+}
+
+// Hashes of the positions the game went through before this one, to spot
+// repetitions
+fn game_positions(request: &SearchRequest) -> Vec<u64> {
+    let Ok(mut position) = ChessGame::from_fen(&request.start_fen).map(|g| g.game) else {
+        return Vec::new();
+    };
+    let mut hashes = Vec::with_capacity(request.history.len());
+    for uci in &request.history {
+        let Some(m) = uci
+            .parse::<Uci>()
+            .ok()
+            .and_then(|uci| uci.to_move(&position).ok())
+        else {
+            break;
+        };
+        hashes.push(hash(&position));
+        position.play_unchecked(&m);
+    }
+    hashes
+}
+
+fn hash(position: &Chess) -> u64 {
+    position.zobrist_hash::<Zobrist64>(EnPassantMode::Legal).0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Bound {
+    Exact,
+    /// The score is at least this much
+    Lower,
+    /// The score is at most this much
+    Upper,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    key: u64,
+    depth: i32,
+    score: i32,
+    bound: Bound,
+    best: Option<Move>,
+}
+
+struct TranspositionTable {
+    entries: Vec<Option<Entry>>,
+}
+
+impl TranspositionTable {
+    fn new(size: usize) -> Self {
+        Self {
+            entries: vec![None; size.max(1).next_power_of_two()],
+        }
+    }
+
+    fn index(&self, key: u64) -> usize {
+        (key as usize) & (self.entries.len() - 1)
+    }
+
+    fn get(&self, key: u64) -> Option<&Entry> {
+        self.entries[self.index(key)]
+            .as_ref()
+            .filter(|e| e.key == key)
+    }
+
+    // Deeper searches of the same position win, anything else replaces
+    // what was there
+    fn put(&mut self, entry: Entry) {
+        let index = self.index(entry.key);
+        let slot = &mut self.entries[index];
+        if slot
+            .as_ref()
+            .is_some_and(|old| old.key == entry.key && old.depth > entry.depth)
+        {
+            return;
+        }
+        *slot = Some(entry);
+    }
+}
+
+// Mate scores are stored relative to the position rather than the root, so
+// they stay right when the position comes up at another ply
+fn to_table(score: i32, ply: usize) -> i32 {
+    match score {
+        s if s > MATE_BOUND => s + ply as i32,
+        s if s < -MATE_BOUND => s - ply as i32,
+        s => s,
+    }
+}
+
+fn from_table(score: i32, ply: usize) -> i32 {
+    match score {
+        s if s > MATE_BOUND => s - ply as i32,
+        s if s < -MATE_BOUND => s + ply as i32,
+        s => s,
+    }
+}
+
+struct Search<'a> {
+    table: &'a mut TranspositionTable,
+    config: &'a AlphaBetaConfig,
+    stop: StopSignal,
+    stopped: bool,
+    nodes: u64,
+    /// Positions before the one being searched, from the game and the
+    /// search path
+    positions: Vec<u64>,
+    /// Quiet moves that caused a cutoff, two per ply
+    killers: Vec<[Option<Move>; 2]>,
+    /// Best line found from each ply on
+    lines: Vec<Vec<Move>>,
+}
+
+impl<'a> Search<'a> {
+    fn new(
+        table: &'a mut TranspositionTable,
+        config: &'a AlphaBetaConfig,
+        stop: StopSignal,
+        positions: Vec<u64>,
+    ) -> Self {
+        Self {
+            table,
+            config,
+            stop,
+            stopped: false,
+            nodes: 0,
+            positions,
+            killers: vec![[None, None]; MAX_PLY + 1],
+            lines: vec![Vec::new(); MAX_PLY + 2],
+        }
+    }
+
+    // Deepens one ply at a time until a limit is hit, the move of the
+    // deepest finished iteration is played. Every iteration is reported
+    fn run(&mut self, request: &SearchRequest) -> SearchReply {
+        let started = Instant::now();
+        let root = &request.position;
+        let mut moves = root.legal_moves();
+        order_moves(&mut moves, None, &[None, None]);
+        let mut reply = SearchReply::new(moves.first().cloned());
+        // for the side to move, unlike the reported score
+        let mut own_score = None;
+
+        let randomness = self.config.randomness();
+        let mut rng = rand::thread_rng();
+        // fixed for the whole search, so every iteration agrees on which
+        // moves the engine fancies
+        let noise: Vec<(Move, i32)> = moves
+            .iter()
+            .map(|m| (m.clone(), rng.gen_range(-randomness..=randomness)))
+            .collect();
+
+        for depth in 1..=self.config.max_depth.max(1) {
+            let Some((best, score)) = self.root(root, depth as i32, &noise) else {
+                break;
+            };
+            let info = SearchInfo {
+                depth: Some(depth),
+                score: Some(score_for_people(score)),
+                nodes: Some(self.nodes),
+                nps: Some(self.nodes * 1000 / (started.elapsed().as_millis() as u64).max(1)),
+                pv: uci_line(&self.lines[0]),
+            };
+            request.report(&info);
+            reply = SearchReply {
+                best_move: Some(best),
+                score: info.score,
+                depth: info.depth,
+                pv: info.pv,
+                ..SearchReply::default()
+            };
+            own_score = Some(score);
+            // no point in looking further once a mate is found, or when
+            // there's only the one move
+            if score.abs() > MATE_BOUND || moves.len() == 1 || self.out_of_budget() {
+                break;
+            }
+        }
+        // takes a draw it's offered unless it thinks it's better
+        reply.offer_draw = request.draw_offered && own_score.is_some_and(|s| s <= 0);
+        reply
+    }
+
+    // Searches every root move, returns the best along with its score or
+    // `None` if the search was stopped before it was done
+    fn root(&mut self, root: &Chess, depth: i32, noise: &[(Move, i32)]) -> Option<(Move, i32)> {
+        let key = hash(root);
+        let tt_move = self.table.get(key).and_then(|e| e.best.clone());
+        let mut moves = root.legal_moves();
+        order_moves(&mut moves, tt_move.as_ref(), &self.killers[0]);
+
+        let mut alpha = -INFINITY;
+        let mut best: Option<(Move, i32, i32)> = None;
+        self.positions.push(key);
+        for m in moves {
+            let bonus = noise
+                .iter()
+                .find(|(n, _)| *n == m)
+                .map_or(0, |(_, bonus)| *bonus);
+            let mut child = root.clone();
+            child.play_unchecked(&m);
+            // the move only has to beat the best so far including its bonus
+            let score = -self.negamax(&child, depth - 1, 1, -INFINITY, -(alpha - bonus));
+            if self.stopped {
+                self.positions.pop();
+                return None;
+            }
+            // mates aren't up for debate
+            let noisy = if score.abs() > MATE_BOUND {
+                score
+            } else {
+                score + bonus
+            };
+            if best.is_none() || noisy > alpha {
+                alpha = noisy;
+                self.extend_line(0, &m);
+                best = Some((m, score, noisy));
+            }
+        }
+        self.positions.pop();
+
+        // with the bonuses the move played isn't necessarily the best one,
+        // the position is worth at least as much though
+        let (best, score, _) = best?;
+        self.table.put(Entry {
+            key,
+            depth,
+            score: to_table(score, 0),
+            bound: Bound::Lower,
+            best: Some(best.clone()),
+        });
+        Some((best, score))
+    }
+
+    fn negamax(
+        &mut self,
+        position: &Chess,
+        depth: i32,
+        ply: usize,
+        mut alpha: i32,
+        beta: i32,
+    ) -> i32 {
+        self.lines[ply].clear();
+        if self.should_stop() {
+            return 0;
+        }
+        self.nodes += 1;
+
+        let key = hash(position);
+        if position.halfmoves() >= 100
+            || position.is_insufficient_material()
+            || self.repeated(key, position.halfmoves())
+        {
+            return 0;
+        }
+        if ply >= MAX_PLY {
+            return evaluate(position);
+        }
+
+        let in_check = position.is_check();
+        // don't stop looking in the middle of a check
+        let depth = if in_check { depth + 1 } else { depth };
+        if depth <= 0 {
+            return self.quiesce(position, ply, alpha, beta);
+        }
+
+        let entry = self.table.get(key).cloned();
+        if let Some(entry) = &entry {
+            if entry.depth >= depth {
+                let score = from_table(entry.score, ply);
+                match entry.bound {
+                    Bound::Exact => return score,
+                    Bound::Lower if score >= beta => return score,
+                    Bound::Upper if score <= alpha => return score,
+                    _ => {}
+                }
+            }
+        }
+
+        let mut moves = position.legal_moves();
+        if moves.is_empty() {
+            return if in_check { -MATE + ply as i32 } else { 0 };
+        }
+        let tt_move = entry.and_then(|e| e.best);
+        order_moves(&mut moves, tt_move.as_ref(), &self.killers[ply]);
+
+        let original_alpha = alpha;
+        let mut best_score = -INFINITY;
+        let mut best_move = None;
+        self.positions.push(key);
+        for m in moves {
+            let mut child = position.clone();
+            child.play_unchecked(&m);
+            let score = -self.negamax(&child, depth - 1, ply + 1, -beta, -alpha);
+            if self.stopped {
+                self.positions.pop();
+                return 0;
+            }
+            if score > best_score {
+                best_score = score;
+                best_move = Some(m.clone());
+            }
+            if score > alpha {
+                alpha = score;
+                self.extend_line(ply, &m);
+            }
+            if alpha >= beta {
+                if !m.is_capture() && self.killers[ply][0].as_ref() != Some(&m) {
+                    self.killers[ply] = [Some(m), self.killers[ply][0].take()];
+                }
+                break;
+            }
+        }
+        self.positions.pop();
+
+        let bound = if best_score <= original_alpha {
+            Bound::Upper
+        } else if best_score >= beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        };
+        self.table.put(Entry {
+            key,
+            depth,
+            score: to_table(best_score, ply),
+            bound,
+            best: best_move,
+        });
+        best_score
+    }
+
+    // Only looks at captures and promotions until the position is quiet, so
+    // the evaluation isn't taken in the middle of an exchange
+    fn quiesce(&mut self, position: &Chess, ply: usize, mut alpha: i32, beta: i32) -> i32 {
+        self.lines[ply].clear();
+        if self.should_stop() {
+            return 0;
+        }
+        self.nodes += 1;
+        if ply >= MAX_PLY {
+            return evaluate(position);
+        }
+
+        let in_check = position.is_check();
+        let mut moves = position.legal_moves();
+        if moves.is_empty() {
+            return if in_check { -MATE + ply as i32 } else { 0 };
+        }
+
+        // the side to move doesn't have to capture, unless it's in check
+        if !in_check {
+            let standing = evaluate(position);
+            if standing >= beta {
+                return standing;
+            }
+            alpha = alpha.max(standing);
+            moves.retain(|m| m.is_capture() || m.is_promotion());
+        }
+        order_moves(&mut moves, None, &[None, None]);
+
+        for m in moves {
+            let mut child = position.clone();
+            child.play_unchecked(&m);
+            let score = -self.quiesce(&child, ply + 1, -beta, -alpha);
+            if self.stopped {
+                return 0;
+            }
+            if score >= beta {
+                return score;
+            }
+            alpha = alpha.max(score);
+        }
+        alpha
+    }
+
+    // Whether the position came up before since the last capture or pawn
+    // move, once is enough to call it a draw
+    fn repeated(&self, key: u64, halfmoves: u32) -> bool {
+        self.positions
+            .iter()
+            .rev()
+            .take(halfmoves as usize)
+            .any(|&k| k == key)
+    }
+
+    fn out_of_budget(&self) -> bool {
+        self.stop.is_stopped() || self.config.max_nodes.is_some_and(|max| self.nodes >= max)
+    }
+
+    fn should_stop(&mut self) -> bool {
+        if !self.stopped && self.nodes.is_multiple_of(CHECK_EVERY) {
+            self.stopped = self.out_of_budget();
+        }
+        self.stopped
+    }
+
+    // `m` turned out best at `ply`, the line from there is `m` followed by
+    // the best line after it
+    fn extend_line(&mut self, ply: usize, m: &Move) {
+        let (before, after) = self.lines.split_at_mut(ply + 1);
+        let line = &mut before[ply];
+        line.clear();
+        line.push(m.clone());
+        line.extend(after[0].iter().cloned());
+    }
+}
+
+fn uci_line(line: &[Move]) -> Vec<String> {
+    line.iter()
+        .map(|m| m.to_uci(CastlingMode::Standard).to_string())
+        .collect()
+}
+
+// Mates are counted in moves rather than plies
+fn score_for_people(score: i32) -> Score {
+    if score > MATE_BOUND {
+        Score::Mate((MATE - score + 1) / 2)
+    } else if score < -MATE_BOUND {
+        Score::Mate(-(MATE + score + 1) / 2)
+    } else {
+        Score::Cp(score)
+    }
+}
+
+// Best move from the table first, then captures of the most valuable piece
+// by the least valuable one, promotions, killers and the rest
+fn order_moves(moves: &mut MoveList, tt_move: Option<&Move>, killers: &[Option<Move>; 2]) {
+    moves.sort_by_cached_key(|m| {
+        let priority = if Some(m) == tt_move {
+            1_000_000
+        } else if let Some(captured) = m.capture() {
+            100_000 + 10 * value(captured) - value(m.role())
+        } else if let Some(promotion) = m.promotion() {
+            90_000 + value(promotion)
+        } else if killers.iter().any(|k| k.as_ref() == Some(m)) {
+            80_000
+        } else {
+            0
+        };
+        -priority
+    });
+}
+
+fn value(role: Role) -> i32 {
+    match role {
+        Role::Pawn => 100,
+        Role::Knight => 320,
+        Role::Bishop => 330,
+        Role::Rook => 500,
+        Role::Queen => 900,
+        Role::King => 20_000,
+    }
+}
+
+// Material and piece placement from the side to move's point of view. The
+// king's table blends from hiding in the middlegame to centralizing in the
+// endgame as pieces come off
+fn evaluate(position: &Chess) -> i32 {
+    let board = position.board();
+    // 24 with every piece on the board, 0 with only kings and pawns
+    let phase = (board.knights().count() + board.bishops().count()) as i32
+        + 2 * board.rooks().count() as i32
+        + 4 * board.queens().count() as i32;
+    let phase = phase.min(24);
+
+    let mut score = 0;
+    for (square, piece) in board.clone() {
+        let index = table_index(square, piece.color);
+        let placement = match piece.role {
+            Role::Pawn => PAWN[index],
+            Role::Knight => KNIGHT[index],
+            Role::Bishop => BISHOP[index],
+            Role::Rook => ROOK[index],
+            Role::Queen => QUEEN[index],
+            Role::King => {
+                (KING_MIDDLEGAME[index] * phase + KING_ENDGAME[index] * (24 - phase)) / 24
+            }
+        };
+        let worth = value(piece.role) + placement;
+        score += match piece.color {
+            Color::White => worth,
+            Color::Black => -worth,
+        };
+    }
+
+    match position.turn() {
+        Color::White => score,
+        Color::Black => -score,
+    }
+}
+
+// The tables below are laid out the way the board looks from white's side,
+// rank 8 first
+fn table_index(square: Square, color: Color) -> usize {
+    let square = usize::from(square);
+    match color {
+        Color::White => square ^ 56,
+        Color::Black => square,
+    }
+}
+
+#[rustfmt::skip]
+const PAWN: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+    50, 50, 50, 50, 50, 50, 50, 50,
+    10, 10, 20, 30, 30, 20, 10, 10,
+     5,  5, 10, 25, 25, 10,  5,  5,
+     0,  0,  0, 20, 20,  0,  0,  0,
+     5, -5,-10,  0,  0,-10, -5,  5,
+     5, 10, 10,-20,-20, 10, 10,  5,
+     0,  0,  0,  0,  0,  0,  0,  0,
+];
+
+#[rustfmt::skip]
+const KNIGHT: [i32; 64] = [
+    -50,-40,-30,-30,-30,-30,-40,-50,
+    -40,-20,  0,  0,  0,  0,-20,-40,
+    -30,  0, 10, 15, 15, 10,  0,-30,
+    -30,  5, 15, 20, 20, 15,  5,-30,
+    -30,  0, 15, 20, 20, 15,  0,-30,
+    -30,  5, 10, 15, 15, 10,  5,-30,
+    -40,-20,  0,  5,  5,  0,-20,-40,
+    -50,-40,-30,-30,-30,-30,-40,-50,
+];
+
+#[rustfmt::skip]
+const BISHOP: [i32; 64] = [
+    -20,-10,-10,-10,-10,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5, 10, 10,  5,  0,-10,
+    -10,  5,  5, 10, 10,  5,  5,-10,
+    -10,  0, 10, 10, 10, 10,  0,-10,
+    -10, 10, 10, 10, 10, 10, 10,-10,
+    -10,  5,  0,  0,  0,  0,  5,-10,
+    -20,-10,-10,-10,-10,-10,-10,-20,
+];
+
+#[rustfmt::skip]
+const ROOK: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+     5, 10, 10, 10, 10, 10, 10,  5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+     0,  0,  0,  5,  5,  0,  0,  0,
+];
+
+#[rustfmt::skip]
+const QUEEN: [i32; 64] = [
+    -20,-10,-10, -5, -5,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5,  5,  5,  5,  0,-10,
+     -5,  0,  5,  5,  5,  5,  0, -5,
+      0,  0,  5,  5,  5,  5,  0, -5,
+    -10,  5,  5,  5,  5,  5,  0,-10,
+    -10,  0,  5,  0,  0,  0,  0,-10,
+    -20,-10,-10, -5, -5,-10,-10,-20,
+];
+
+#[rustfmt::skip]
+const KING_MIDDLEGAME: [i32; 64] = [
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -20,-30,-30,-40,-40,-30,-30,-20,
+    -10,-20,-20,-20,-20,-20,-20,-10,
+     20, 20,  0,  0,  0,  0, 20, 20,
+     20, 30, 10,  0,  0, 10, 30, 20,
+];
+
+#[rustfmt::skip]
+const KING_ENDGAME: [i32; 64] = [
+    -50,-40,-30,-20,-20,-30,-40,-50,
+    -30,-20,-10,  0,  0,-10,-20,-30,
+    -30,-10, 20, 30, 30, 20,-10,-30,
+    -30,-10, 30, 40, 40, 30,-10,-30,
+    -30,-10, 30, 40, 40, 30,-10,-30,
+    -30,-10, 20, 30, 30, 20,-10,-30,
+    -30,-30,  0,  0,  0,  0,-30,-30,
+    -50,-30,-30,-30,-30,-30,-30,-50,
+];
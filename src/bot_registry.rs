@@ -0,0 +1,255 @@
+// Keeps track of the bots users have submitted through the text editor and the
+// UCI engines the server was started with, and hands out fresh engine
+// instances for them when a game starts
+use anyhow::{anyhow, bail, Result};
+use dashmap::DashMap;
+use log::info;
+use serde::Serialize;
+use shakmaty::{Color, Position};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::alpha_beta_engine::{AlphaBetaConfig, AlphaBetaEngine, MAX_SKILL};
+use crate::chess_engine::{ChooseMove, Engine, RandomEngine, SearchRequest, DEFAULT_PACING};
+use crate::chess_game::ChessGame;
+use crate::lua_engine::LuaEngine;
+use crate::storage::StoredBot;
+use crate::uci_engine::{UciEngine, UciEngineConfig};
+
+/// Id of the bot that is always available, and used when a game doesn't ask
+/// for a specific one
+pub const RANDOM_BOT_ID: &str = "random";
+
+/// Skill levels of the built-in engine listed as bots, any level up to
+/// `MAX_SKILL` can be played by id, e.g. `engine-7`
+pub const ENGINE_LEVELS: [u8; 4] = [1, 6, 12, MAX_SKILL];
+const ENGINE_BOT_PREFIX: &str = "engine-";
+
+/// Stands in for the human in player vs bot games, e.g. on the leaderboard
+pub const PLAYER_ID: &str = "player";
+
+// number of plies played in the validation game, kept short because the
+// bot gets a full time limit for every move
+const SMOKE_TEST_PLIES: usize = 16;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum BotKind {
+    Builtin,
+    Lua,
+    Uci,
+    /// Not a bot at all, see `PLAYER_ID`
+    Human,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BotInfo {
+    pub id: String,
+    pub name: String,
+    pub kind: BotKind,
+}
+
+enum BotSource {
+    Lua(String),
+    Uci(UciEngineConfig),
+}
+
+struct RegisteredBot {
+    info: BotInfo,
+    source: BotSource,
+}
+
+pub struct BotRegistry {
+    bots: DashMap<String, RegisteredBot>,
+    /// How long the random bot waits before each move
+    random_pacing: Duration,
+}
+
+impl Default for BotRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BotRegistry {
+    pub fn new() -> Self {
+        Self::with_random_pacing(DEFAULT_PACING)
+    }
+
+    pub fn with_random_pacing(random_pacing: Duration) -> Self {
+        Self {
+            bots: DashMap::new(),
+            random_pacing,
+        }
+    }
+
+    /// Validates a lua script and stores it under a new id
+    pub fn register(&self, name: Option<String>, script: String) -> Result<BotInfo> {
+        smoke_test(&script)?;
+
+        let id = Uuid::new_v4();
+        let name = match name.map(|n| n.trim().to_string()) {
+            Some(n) if !n.is_empty() => n,
+            _ => format!("bot-{}", &id.to_string()[..8]),
+        };
+
+        let info = BotInfo {
+            id: id.to_string(),
+            name,
+            kind: BotKind::Lua,
+        };
+        self.insert(info.clone(), BotSource::Lua(script));
+        Ok(info)
+    }
+
+    /// Validates a lua script given on the command line, its id comes from
+    /// the name so its games and ratings carry over from one start to the
+    /// next
+    pub fn register_lua(&self, name: &str, script: String) -> Result<BotInfo> {
+        smoke_test(&script)?;
+        let info = BotInfo {
+            id: format!("lua-{name}"),
+            name: name.to_string(),
+            kind: BotKind::Lua,
+        };
+        self.insert(info.clone(), BotSource::Lua(script));
+        Ok(info)
+    }
+
+    /// Brings back a bot stored before a restart under its old id, it was
+    /// validated when it was first registered
+    pub fn restore(&self, bot: &StoredBot) -> BotInfo {
+        let info = BotInfo {
+            id: bot.id.clone(),
+            name: bot.name.clone(),
+            kind: BotKind::Lua,
+        };
+        self.insert(info.clone(), BotSource::Lua(bot.script.clone()));
+        info
+    }
+
+    /// Makes an external UCI engine available as a bot, it is started once
+    /// to check that it works
+    pub fn register_uci(&self, name: &str, config: UciEngineConfig) -> Result<BotInfo> {
+        UciEngine::new(config.clone())?;
+        let info = BotInfo {
+            id: format!("uci-{name}"),
+            name: name.to_string(),
+            kind: BotKind::Uci,
+        };
+        self.insert(info.clone(), BotSource::Uci(config));
+        Ok(info)
+    }
+
+    fn insert(&self, info: BotInfo, source: BotSource) {
+        info!("Registered bot {} ({})", info.name, info.id);
+        self.bots
+            .insert(info.id.clone(), RegisteredBot { info, source });
+    }
+
+    pub fn list(&self) -> Vec<BotInfo> {
+        let mut bots = vec![random_bot()];
+        bots.extend(ENGINE_LEVELS.map(engine_bot));
+        let mut registered: Vec<BotInfo> = self.bots.iter().map(|b| b.info.clone()).collect();
+        registered.sort_by(|a, b| a.name.cmp(&b.name));
+        bots.extend(registered);
+        bots
+    }
+
+    pub fn info(&self, id: &str) -> Result<BotInfo> {
+        if id == RANDOM_BOT_ID {
+            return Ok(random_bot());
+        }
+        if let Some(skill) = engine_level(id) {
+            return Ok(engine_bot(skill));
+        }
+
+        let bot = self.bots.get(id).ok_or(anyhow!("Unknown bot {id}"))?;
+        Ok(bot.info.clone())
+    }
+
+    /// Creates a new engine instance for the given bot id
+    pub fn engine(&self, id: &str) -> Result<Engine> {
+        if id == RANDOM_BOT_ID {
+            return Ok(Box::new(RandomEngine::with_pacing(self.random_pacing)));
+        }
+        if let Some(skill) = engine_level(id) {
+            let config = AlphaBetaConfig::with_skill(skill);
+            return Ok(Box::new(AlphaBetaEngine::new(config)));
+        }
+
+        let bot = self.bots.get(id).ok_or(anyhow!("Unknown bot {id}"))?;
+        match &bot.source {
+            BotSource::Lua(script) => Ok(Box::new(LuaEngine::new(script)?)),
+            BotSource::Uci(config) => Ok(Box::new(UciEngine::new(config.clone())?)),
+        }
+    }
+}
+
+pub fn player() -> BotInfo {
+    BotInfo {
+        id: PLAYER_ID.to_string(),
+        name: "Player".to_string(),
+        kind: BotKind::Human,
+    }
+}
+
+fn engine_bot(skill: u8) -> BotInfo {
+    BotInfo {
+        id: format!("{ENGINE_BOT_PREFIX}{skill}"),
+        name: format!("Alpha-Beta (level {skill})"),
+        kind: BotKind::Builtin,
+    }
+}
+
+// Skill level in an id like `engine-12`
+fn engine_level(id: &str) -> Option<u8> {
+    let skill = id.strip_prefix(ENGINE_BOT_PREFIX)?.parse().ok()?;
+    (skill <= MAX_SKILL).then_some(skill)
+}
+
+fn random_bot() -> BotInfo {
+    BotInfo {
+        id: RANDOM_BOT_ID.to_string(),
+        name: "Random Mover".to_string(),
+        kind: BotKind::Builtin,
+    }
+}
+
+// Loading the script catches syntax errors and a missing chooseMove, the
+// short games against the random engine, one with each color, catch bots
+// that blow up or answer with garbage once they're actually asked for a move.
+// They're asked the way live games ask, through `search`
+fn smoke_test(script: &str) -> Result<()> {
+    let bot = LuaEngine::new(script)?;
+    let opponent = RandomEngine::with_pacing(Duration::ZERO);
+
+    for color in [Color::White, Color::Black] {
+        let mut game = ChessGame::new();
+        for ply in 0..SMOKE_TEST_PLIES {
+            if game.game_over() {
+                break;
+            }
+
+            if game.game.turn() != color {
+                match opponent.choose_move(&game.fen(), &game.get_legal_moves()) {
+                    Some(m) => game.make_move(&m),
+                    None => break,
+                }
+                continue;
+            }
+
+            let request = SearchRequest::new(&game);
+            let reply = bot.search(&request);
+            if reply.resign {
+                break;
+            }
+            match reply.best_move {
+                Some(m) if request.legal_moves.contains(&m) => game.make_move(&m),
+                _ => bail!("Bot failed to make a legal move as {color} at ply {ply}"),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,24 +1,232 @@
 use rand::Rng;
-use shakmaty::{Move, MoveList, Position};
-use std::sync::mpsc::Sender;
+use shakmaty::{Chess, Color, Move, MoveList, Position};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use log::{error, info};
 
-use crate::chess_game::ChessGame;
-use crate::websocket::Notification;
+use crate::chess_game::{ChessGame, GameOutcome, GameResult, Termination};
+use crate::clock::TimeLeft;
+use crate::protocol::{Score, ServerMessage};
+use crate::websocket::{broadcast, GameChannel};
+
+/// Tells a bot to stop thinking and answer with the best move it has, either
+/// because someone asked or because its time is up
+#[derive(Clone, Debug, Default)]
+pub struct StopSignal {
+    stopped: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl StopSignal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Same signal, that also goes off at `deadline` if that's sooner
+    pub fn with_deadline(self, deadline: Instant) -> Self {
+        let deadline = self.deadline.map_or(deadline, |d| d.min(deadline));
+        Self {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Stops every bot holding a clone of this signal
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed) || self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+}
+
+/// How a bot's search is going, reported while it thinks
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchInfo {
+    pub depth: Option<u32>,
+    /// From the bot's point of view
+    pub score: Option<Score>,
+    pub nodes: Option<u64>,
+    /// Nodes per second
+    pub nps: Option<u64>,
+    /// Line the bot expects in UCI notation, starting with its move
+    pub pv: Vec<String>,
+}
+
+/// Gets a bot's progress reports, e.g. to pass them on to spectators
+pub type InfoSink = Arc<dyn Fn(&SearchInfo) + Send + Sync>;
+
+/// Everything a bot gets to know when it's asked for a move
+#[derive(Clone)]
+pub struct SearchRequest {
+    pub position: Chess,
+    /// `position` in FEN
+    pub fen: String,
+    pub legal_moves: MoveList,
+    /// Position the game started from, `history` was played from there
+    pub start_fen: String,
+    /// Moves played so far in UCI notation
+    pub history: Vec<String>,
+    /// Clocks in games with a time control
+    pub time: Option<TimeLeft>,
+    /// Name of the player or bot on the other side
+    pub opponent: String,
+    /// The opponent offered a draw with their last move, offering one back
+    /// accepts it
+    pub draw_offered: bool,
+    pub stop: StopSignal,
+    /// Where progress reports go, nowhere when `None`
+    pub on_info: Option<InfoSink>,
+}
+
+impl SearchRequest {
+    /// Request for the side to move in `game`. The stop signal goes off when
+    /// their flag falls
+    pub fn new(game: &ChessGame) -> Self {
+        let side = game.game.turn();
+        let time = game.clock().time_left();
+        let mut stop = StopSignal::new();
+        if let Some(time) = &time {
+            stop = stop.with_deadline(Instant::now() + time.move_time.unwrap_or(time.own()));
+        }
+        Self {
+            position: game.game.clone(),
+            fen: game.fen(),
+            legal_moves: game.get_legal_moves(),
+            start_fen: game.start_fen(),
+            history: game.history().iter().map(|m| m.uci.clone()).collect(),
+            time,
+            opponent: match side {
+                Color::White => game.black.clone(),
+                Color::Black => game.white.clone(),
+            },
+            draw_offered: game.draw_offer() == Some(!side),
+            stop,
+            on_info: None,
+        }
+    }
+
+    /// Same request, with the bot's progress sent to everyone following the
+    /// game as `evaluation` messages
+    pub fn with_spectators(self, channel: &GameChannel) -> Self {
+        let (channel, ply, side) = (channel.clone(), self.history.len(), self.side());
+        let on_info: InfoSink = Arc::new(move |info: &SearchInfo| {
+            if let Some(evaluation) = ServerMessage::evaluation(ply, side, info) {
+                broadcast(&channel, evaluation);
+            }
+        });
+        Self {
+            on_info: Some(on_info),
+            ..self
+        }
+    }
+
+    pub fn side(&self) -> Color {
+        self.position.turn()
+    }
+
+    /// Passes on how the search is going, bots call this as often as they
+    /// have something new to say
+    pub fn report(&self, info: &SearchInfo) {
+        if let Some(on_info) = &self.on_info {
+            on_info(info);
+        }
+    }
+}
+
+/// A bot's answer, everything but the move is optional
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchReply {
+    /// `None` when the bot couldn't come up with a move, which loses unless
+    /// it resigned or took a draw
+    pub best_move: Option<Move>,
+    /// From the bot's point of view, unlike the scores sent to spectators
+    pub score: Option<Score>,
+    pub depth: Option<u32>,
+    /// Line the bot expects in UCI notation, starting with its move
+    pub pv: Vec<String>,
+    pub resign: bool,
+    /// Offers a draw along with the move, or takes the one on the table
+    pub offer_draw: bool,
+}
+
+impl SearchReply {
+    pub fn new(best_move: Option<Move>) -> Self {
+        Self {
+            best_move,
+            ..Self::default()
+        }
+    }
+}
 
 pub trait ChooseMove {
     fn choose_move(&self, fen: &str, legal_moves: &MoveList) -> Option<Move>;
+
+    /// Same as `choose_move`, with the time left on the clocks when the game
+    /// has a time control. Engines that don't manage their time can ignore it
+    fn choose_move_timed(
+        &self,
+        fen: &str,
+        legal_moves: &MoveList,
+        _time: Option<&TimeLeft>,
+    ) -> Option<Move> {
+        self.choose_move(fen, legal_moves)
+    }
+
+    /// How long to wait before asking for a move, so people can follow the
+    /// game. Whoever runs the game waits it out, not the engine, so pacing
+    /// doesn't hold on to a thread
+    fn pacing(&self, _time: Option<&TimeLeft>) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Picks a move knowing the whole game, this is what the server calls.
+    /// Simple bots only look at the position and clocks, for them it's
+    /// passed on to `choose_move_timed`
+    fn search(&self, request: &SearchRequest) -> SearchReply {
+        SearchReply::new(self.choose_move_timed(
+            &request.fen,
+            &request.legal_moves,
+            request.time.as_ref(),
+        ))
+    }
+}
+
+/// An engine picked at runtime, e.g. a bot from the registry
+pub type Engine = Box<dyn ChooseMove + Send + Sync>;
+
+/// Engine shared with the thread running its game
+pub type SharedEngine = Arc<dyn ChooseMove + Send + Sync>;
+
+/// How long the random engine pretends to think by default, so spectators
+/// can follow the game
+pub const DEFAULT_PACING: Duration = Duration::from_millis(250);
+
+pub struct RandomEngine {
+    pacing: Duration,
 }
 
-pub struct RandomEngine {}
+impl Default for RandomEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl RandomEngine {
     pub fn new() -> Self {
-        RandomEngine {}
+        Self::with_pacing(DEFAULT_PACING)
+    }
+
+    pub fn with_pacing(pacing: Duration) -> Self {
+        RandomEngine { pacing }
     }
 }
 
@@ -27,52 +235,131 @@ impl ChooseMove for RandomEngine {
         if legal_moves.is_empty() {
             None
         } else {
-            thread::sleep(Duration::from_millis(250)); // Delay for 250 ms
             let mut rng = rand::thread_rng();
             let random_index = rng.gen_range(0..legal_moves.len());
             legal_moves.get(random_index).cloned()
         }
     }
+
+    fn pacing(&self, time: Option<&TimeLeft>) -> Duration {
+        // never lose on time just for the show
+        match time {
+            Some(time) => self.pacing.min(time.own() / 10),
+            None => self.pacing,
+        }
+    }
 }
 
-pub fn engine_vs_engine<T: ChooseMove>(
+/// Plays `white` against `black` until the game is over, the engines can be
+/// of different types. The game is only locked to look at the position and
+/// to play the move, not while an engine thinks, so spectators and the
+/// server can read it in the meantime. Blocks until the game is over,
+/// `GameRunner::play` is the async version
+pub fn engine_vs_engine(
     game: Arc<RwLock<ChessGame>>,
-    engine1: Arc<T>,
-    engine2: Arc<T>,
-    sender_channel: Sender<Notification>,
+    white: SharedEngine,
+    black: SharedEngine,
+    channel: GameChannel,
 ) {
     info!("Engine vs Engine Started...");
 
-    loop {
-        let mut game = game.write().unwrap(); // Lock the game for the current scope
+    while let Some(turn) = next_turn(&game, &channel) {
+        let engine = turn.engine(&white, &black);
+        thread::sleep(engine.pacing(turn.request.time.as_ref()));
+        let reply = engine.search(&turn.request);
+        play_turn(&game, turn, reply, &channel);
+    }
+}
 
-        // Check for game end conditions
-        if game.game_over() {
-            break;
-        }
+/// Everything an engine needs to pick a move, taken from the game so it can
+/// think without holding the lock
+pub struct Turn {
+    pub color: Color,
+    pub request: SearchRequest,
+    /// Moves played before this one
+    plies: usize,
+}
 
-        // Alternate turns between Engine 1 and Engine 2
-        for engine in [&engine1, &engine2].iter() {
-            let (legal_moves, fen) = (game.get_legal_moves(), game.fen());
+impl Turn {
+    /// The game may start from a position with black to move, so this goes
+    /// by the side to move rather than alternating
+    pub fn engine<'a>(&self, white: &'a SharedEngine, black: &'a SharedEngine) -> &'a SharedEngine {
+        match self.color {
+            Color::White => white,
+            Color::Black => black,
+        }
+    }
+}
 
-            if let Some(m) = engine.choose_move(&fen, &legal_moves) {
-                game.make_move(&m);
-                send_notification(&sender_channel, game.fen());
-            } else {
-                info!("Game over, other engine wins or stalemate");
-                return;
-            }
+/// The turn of the side to move, `None` once the game is over
+pub fn next_turn(game: &RwLock<ChessGame>, channel: &GameChannel) -> Option<Turn> {
+    let mut game = game.write().unwrap();
 
-            if game.game_over() {
-                return;
-            }
-        }
+    // Check for game end conditions, the game could have started from a
+    // finished position. Ending it stops the clock
+    if let Some(outcome) = game.outcome() {
+        game.end(outcome);
+        info!("Game over: {outcome:?}");
+        broadcast(channel, ServerMessage::GameOver { outcome });
+        return None;
     }
+
+    Some(Turn {
+        color: game.game.turn(),
+        request: SearchRequest::new(&game).with_spectators(channel),
+        plies: game.history().len(),
+    })
 }
 
-fn send_notification(sender: &Sender<Notification>, fen: String) {
-    match sender.send(Notification(fen)) {
-        Ok(_) => {}
-        Err(e) => error!("Error sending notification: {}", e),
+/// Plays the move an engine chose on its turn, a move that isn't legal or
+/// no move at all loses the game. Engines can also resign, or take a draw
+/// their opponent offered instead of moving
+pub fn play_turn(game: &RwLock<ChessGame>, turn: Turn, reply: SearchReply, channel: &GameChannel) {
+    let mut game = game.write().unwrap();
+    // the game moved on without the engine, e.g. the server adjudicated it
+    // while shutting down. `next_turn` sorts it out
+    if game.outcome().is_some() || game.history().len() != turn.plies {
+        return;
+    }
+
+    let Turn { color, request, .. } = turn;
+    if reply.resign {
+        info!("{color} engine resigned");
+        game.end(GameOutcome::loss_for(color, Termination::Resignation));
+        return;
+    }
+    if reply.offer_draw && request.draw_offered {
+        info!("{color} engine accepted a draw");
+        game.end(GameOutcome::new(GameResult::Draw, Termination::Agreement));
+        return;
+    }
+
+    let fen = request.fen;
+    match reply.best_move {
+        Some(m) if request.legal_moves.contains(&m) => {
+            game.make_move(&m);
+            // a move made after the flag fell isn't played, the game is
+            // over and gets reported by `next_turn`
+            if game.history().len() > turn.plies {
+                broadcast(channel, ServerMessage::position(&game));
+                if reply.offer_draw {
+                    game.offer_draw(color);
+                    broadcast(
+                        channel,
+                        ServerMessage::DrawOffered {
+                            by: color.to_string(),
+                        },
+                    );
+                }
+            }
+        }
+        Some(m) => {
+            error!("{color} engine played illegal move {m} in {fen}");
+            game.end(GameOutcome::loss_for(color, Termination::IllegalMove));
+        }
+        None => {
+            error!("{color} engine failed to move in {fen}");
+            game.end(GameOutcome::loss_for(color, Termination::EngineCrash));
+        }
     }
 }
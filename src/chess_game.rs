@@ -1,13 +1,141 @@
 // 99% of the code in this file is just serialization/deserialization code
 // the only interesting bit is the logic at the botton for actually maintaining
 // the game state
-use log::{error, info};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::info;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use shakmaty::{Chess, Move, MoveList, Position, Role, Square};
+use shakmaty::fen::{Epd, Fen};
+use shakmaty::san::SanPlus;
+use shakmaty::uci::Uci;
+use shakmaty::zobrist::{Zobrist64, ZobristHash};
+use shakmaty::{CastlingMode, Chess, Color, EnPassantMode, Move, MoveList, Position, Role, Square};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::clock::{Clock, ClockState, TimeControl};
 
 pub struct ChessGame {
     pub game: Chess,
-    pub moves_without_capture: u32,
+    /// Position the game started from
+    start: Chess,
+    /// Every move played so far, in order
+    history: Vec<PlayedMove>,
+    pub started_at: DateTime<Utc>,
+    /// Names of the players (or bots), used when exporting the game
+    pub white: String,
+    pub black: String,
+    /// Number of times each position has occurred, keyed by zobrist hash
+    repetitions: HashMap<Zobrist64, u32>,
+    /// Whether the 50-move rule and threefold repetition end the game on
+    /// their own. Bots can't claim draws, so by default the server claims
+    /// them on their behalf
+    pub claim_draws: bool,
+    /// Set when the game ended for a reason outside the rules of chess,
+    /// e.g. a resignation or an engine crashing
+    outcome: Option<GameOutcome>,
+    clock: Clock,
+    /// Side with a draw offer standing, it lapses once the opponent moves
+    draw_offer: Option<Color>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PlayedMove {
+    pub uci: String,
+    pub san: String,
+    /// Position after the move
+    pub fen: String,
+    pub played_at: DateTime<Utc>,
+    /// Time left on the mover's clock after the move, `None` in games
+    /// without a time control
+    #[serde(default)]
+    pub clock_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum GameResult {
+    WhiteWins,
+    BlackWins,
+    Draw,
+}
+
+impl GameResult {
+    pub fn win_for(color: Color) -> Self {
+        match color {
+            Color::White => GameResult::WhiteWins,
+            Color::Black => GameResult::BlackWins,
+        }
+    }
+
+    pub fn winner(&self) -> Option<Color> {
+        match self {
+            GameResult::WhiteWins => Some(Color::White),
+            GameResult::BlackWins => Some(Color::Black),
+            GameResult::Draw => None,
+        }
+    }
+
+    // Result in the notation used by PGN
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GameResult::WhiteWins => "1-0",
+            GameResult::BlackWins => "0-1",
+            GameResult::Draw => "1/2-1/2",
+        }
+    }
+}
+
+/// Why a game ended
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Termination {
+    Checkmate,
+    Stalemate,
+    InsufficientMaterial,
+    /// 50 moves by each side without a capture or pawn move (claimable)
+    FiftyMoveRule,
+    /// 75 moves by each side without a capture or pawn move (automatic)
+    SeventyFiveMoveRule,
+    /// Same position occurred three times (claimable)
+    ThreefoldRepetition,
+    /// Same position occurred five times (automatic)
+    FivefoldRepetition,
+    Resignation,
+    /// Both sides agreed to a draw
+    Agreement,
+    TimeForfeit,
+    /// Ended by the server, e.g. the game was stopped before it finished.
+    /// These games aren't rated
+    Adjudication,
+    /// The engine failed to produce a move
+    EngineCrash,
+    /// The engine answered with a move that isn't legal
+    IllegalMove,
+    /// The player stopped making moves and the game was aborted
+    Abandoned,
+    /// Played somewhere else and imported without saying how it ended
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GameOutcome {
+    pub result: GameResult,
+    pub termination: Termination,
+}
+
+impl GameOutcome {
+    pub fn new(result: GameResult, termination: Termination) -> Self {
+        Self {
+            result,
+            termination,
+        }
+    }
+
+    // The game was lost by `color`, e.g. they resigned or their engine crashed
+    pub fn loss_for(color: Color, termination: Termination) -> Self {
+        Self::new(GameResult::win_for(!color), termination)
+    }
 }
 
 // Serde calls this the definition of the remote type. It is just a copy of the
@@ -168,6 +296,14 @@ pub fn movelist_to_vec(moves: MoveList) -> Vec<Move> {
     vec
 }
 
+// `Move`'s Display impl is SAN-like ("e2-e4"), bots expect plain UCI ("e2e4")
+pub fn uci_moves(moves: &MoveList) -> Vec<String> {
+    moves
+        .iter()
+        .map(|m| m.to_uci(CastlingMode::Standard).to_string())
+        .collect()
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "Move")]
 pub enum MoveDef {
@@ -212,24 +348,196 @@ impl<'a> MoveWrapper<'a> {
     }
 }
 
+impl Default for ChessGame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ChessGame {
     // Creates a new chess game with the default position
     pub fn new() -> Self {
-        ChessGame {
-            game: Chess::default(),
-            moves_without_capture: 0,
+        Self::from_position(Chess::default())
+    }
+
+    // Creates a new chess game starting from the given position
+    pub fn from_fen(fen: &str) -> Result<Self> {
+        let game: Chess = Fen::from_ascii(fen.trim().as_bytes())
+            .map_err(|e| anyhow!("Invalid FEN {fen}: {e}"))?
+            .into_position(CastlingMode::Standard)
+            .map_err(|e| anyhow!("Illegal position {fen}: {e}"))?;
+
+        Ok(Self::from_position(game))
+    }
+
+    fn from_position(game: Chess) -> Self {
+        let mut chess_game = ChessGame {
+            start: game.clone(),
+            game,
+            history: Vec::new(),
+            started_at: Utc::now(),
+            white: "?".to_string(),
+            black: "?".to_string(),
+            repetitions: HashMap::new(),
+            claim_draws: true,
+            outcome: None,
+            clock: Clock::new(TimeControl::Unlimited),
+            draw_offer: None,
+        };
+        chess_game.record_position();
+        chess_game
+    }
+
+    // Replaces the clock and starts it for the side to move
+    pub fn set_time_control(&mut self, control: TimeControl) {
+        self.clock = Clock::new(control);
+        if self.outcome().is_none() {
+            self.clock.start(self.game.turn());
         }
     }
 
-    // Makes a move, if it is legal
+    // Starts the turn of the side to move over, for engines that had to
+    // wait before they could think
+    pub fn restart_clock(&mut self) {
+        self.clock.restart();
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    // Clock state for clients, `None` in games without a time control
+    pub fn clock_state(&self) -> Option<ClockState> {
+        (!self.clock.is_unlimited()).then(|| self.clock.state())
+    }
+
+    // Makes a move, if it is legal. If the mover ran out of time the move
+    // isn't played and the game ends on time instead
     pub fn make_move(&mut self, m: &Move) {
-        if m.is_capture() {
-            self.moves_without_capture = 0;
+        let mover = self.game.turn();
+        if let Err(flagged) = self.clock.press() {
+            info!("{flagged} ran out of time");
+            self.end(self.time_forfeit(flagged));
+            return;
+        }
+
+        // moving on declines the opponent's offer, an offer made before
+        // moving stands
+        if self.draw_offer == Some(!mover) {
+            self.draw_offer = None;
+        }
+        let uci = m.to_uci(CastlingMode::Standard).to_string();
+        let san = SanPlus::from_move_and_play_unchecked(&mut self.game, m).to_string();
+        let clock_ms =
+            (!self.clock.is_unlimited()).then(|| self.clock.remaining(mover).as_millis() as u64);
+        self.history.push(PlayedMove {
+            uci,
+            san,
+            fen: self.fen(),
+            played_at: Utc::now(),
+            clock_ms,
+        });
+
+        // positions before a capture or pawn move can never come back, no
+        // point in remembering them
+        if self.game.halfmoves() == 0 {
+            self.repetitions.clear();
+        }
+        self.record_position();
+
+        if self.game_end().is_some() {
+            self.clock.stop();
+        }
+    }
+
+    // Plays a move of a stored game, keeping when it was played and what the
+    // clock said
+    pub fn replay(&mut self, played: &PlayedMove) -> Result<()> {
+        let m = played
+            .uci
+            .parse::<Uci>()
+            .map_err(|e| anyhow!("Invalid move {}: {e}", played.uci))?
+            .to_move(&self.game)
+            .map_err(|e| anyhow!("Illegal move {}: {e}", played.uci))?;
+        self.make_move(&m);
+        if let Some(last) = self.history.last_mut() {
+            last.played_at = played.played_at;
+            last.clock_ms = played.clock_ms;
+        }
+        Ok(())
+    }
+
+    // Sets up the clock of a replayed game, each side gets back the time
+    // they had after their last move. Time between then and now isn't
+    // charged to anyone
+    pub fn resume_clock(&mut self, control: TimeControl) {
+        let mut clock = Clock::new(control);
+        let first = self.start.turn();
+        for (ply, played) in self.history.iter().enumerate() {
+            let mover = if ply.is_multiple_of(2) { first } else { !first };
+            clock.restore_move(mover, played.clock_ms.map(Duration::from_millis));
+        }
+        self.clock = clock;
+        if self.outcome().is_none() {
+            self.clock.start(self.game.turn());
+        }
+    }
+
+    // Goes back to the position after `ply`, dropping the moves played
+    // since. Each side gets back the time they had then
+    pub fn take_back(&mut self, ply: usize) {
+        let mut game = Self::from_position(self.start.clone());
+        game.started_at = self.started_at;
+        game.white = self.white.clone();
+        game.black = self.black.clone();
+        game.claim_draws = self.claim_draws;
+        for played in &self.history[..ply.min(self.history.len())] {
+            game.replay(played)
+                .expect("moves that were played are legal when replayed");
+        }
+        if !self.clock.is_unlimited() {
+            game.resume_clock(self.clock.control());
+        }
+        *self = game;
+    }
+
+    // Running out of time only loses if the opponent could still mate
+    fn time_forfeit(&self, flagged: Color) -> GameOutcome {
+        if self.game.has_insufficient_material(!flagged) {
+            GameOutcome::new(GameResult::Draw, Termination::TimeForfeit)
         } else {
-            self.moves_without_capture += 1;
+            GameOutcome::loss_for(flagged, Termination::TimeForfeit)
         }
+    }
 
-        self.game.play_unchecked(m);
+    fn record_position(&mut self) {
+        let hash = self.game.zobrist_hash(EnPassantMode::Legal);
+        *self.repetitions.entry(hash).or_insert(0) += 1;
+    }
+
+    // Moves played so far
+    pub fn history(&self) -> &[PlayedMove] {
+        &self.history
+    }
+
+    // Position the game started from
+    pub fn start_position(&self) -> &Chess {
+        &self.start
+    }
+
+    pub fn start_fen(&self) -> String {
+        Fen::from_position(self.start.clone(), EnPassantMode::Legal).to_string()
+    }
+
+    // Number of times the current position has occurred
+    pub fn repetition_count(&self) -> u32 {
+        let hash = self.game.zobrist_hash(EnPassantMode::Legal);
+        self.repetitions.get(&hash).copied().unwrap_or(0)
+    }
+
+    // Plies since the last capture or pawn move
+    pub fn halfmove_clock(&self) -> u32 {
+        self.game.halfmoves()
     }
 
     // Returns a list of legal moves
@@ -237,30 +545,113 @@ impl ChessGame {
         self.game.legal_moves()
     }
 
-    // Returns the FEN representation of the current position
+    // Returns the FEN representation of the current position, including side
+    // to move, castling rights, en passant square and move counters
     pub fn fen(&self) -> String {
+        Fen::from_position(self.game.clone(), EnPassantMode::Legal).to_string()
+    }
+
+    // Same as `fen` without the move counters
+    pub fn epd(&self) -> String {
+        Epd::from_position(self.game.clone(), EnPassantMode::Legal).to_string()
+    }
+
+    // Returns just the piece placement part of the FEN, which is what
+    // chessboard.js expects
+    pub fn board_fen(&self) -> String {
         self.game.board().to_string()
     }
 
     // Returns the UCI representation of the legal moves
     pub fn uci(&self) -> Vec<String> {
-        self.get_legal_moves()
-            .iter()
-            .map(|m| m.to_string())
-            .collect()
+        uci_moves(&self.get_legal_moves())
     }
 
-    pub fn game_over(&self) -> bool {
-        if self.game.is_checkmate()
-            || self.game.is_stalemate()
-            || self.game.is_insufficient_material()
-            || self.game.outcome().is_some()
-            || self.moves_without_capture >= 50
-        {
-            info!("Game over, endgame condition reached");
-            true
+    // Draw the side to move could claim right now
+    pub fn claimable_draw(&self) -> Option<Termination> {
+        if self.repetition_count() >= 3 {
+            Some(Termination::ThreefoldRepetition)
+        } else if self.halfmove_clock() >= 100 {
+            Some(Termination::FiftyMoveRule)
         } else {
-            false
+            None
+        }
+    }
+
+    // Returns the rule of chess that ended the game, if any
+    pub fn game_end(&self) -> Option<Termination> {
+        // checkmate takes precedence, even on the move that triggers the 75
+        // move rule
+        if self.game.is_checkmate() {
+            Some(Termination::Checkmate)
+        } else if self.game.is_stalemate() {
+            Some(Termination::Stalemate)
+        } else if self.game.is_insufficient_material() {
+            Some(Termination::InsufficientMaterial)
+        } else if self.repetition_count() >= 5 {
+            Some(Termination::FivefoldRepetition)
+        } else if self.halfmove_clock() >= 150 {
+            Some(Termination::SeventyFiveMoveRule)
+        } else if self.claim_draws {
+            self.claimable_draw()
+        } else {
+            None
+        }
+    }
+
+    // Result of the game, `None` while it is still being played
+    pub fn outcome(&self) -> Option<GameOutcome> {
+        if self.outcome.is_some() {
+            return self.outcome;
+        }
+
+        if let Some(termination) = self.game_end() {
+            return Some(match termination {
+                // the side to move is the one that got mated
+                Termination::Checkmate => GameOutcome::loss_for(self.game.turn(), termination),
+                _ => GameOutcome::new(GameResult::Draw, termination),
+            });
+        }
+
+        // the flag can fall while nobody is looking, e.g. a player who
+        // never comes back to make their move
+        self.clock.flagged().map(|color| self.time_forfeit(color))
+    }
+
+    // Ends the game for a reason the board can't tell us about, an ending
+    // that already happened (e.g. a fallen flag) takes precedence
+    pub fn end(&mut self, outcome: GameOutcome) {
+        self.outcome = Some(self.outcome().unwrap_or(outcome));
+        self.clock.stop();
+    }
+
+    // Ends the game if a flag fell while nobody was looking, returns the
+    // outcome only the first time
+    pub fn end_if_flagged(&mut self) -> Option<GameOutcome> {
+        if self.outcome.is_some() || self.game_end().is_some() {
+            return None;
+        }
+        let outcome = self.time_forfeit(self.clock.flagged()?);
+        self.end(outcome);
+        Some(outcome)
+    }
+
+    // Offers the opponent a draw, stands until they make their move
+    pub fn offer_draw(&mut self, color: Color) {
+        self.draw_offer = Some(color);
+    }
+
+    pub fn draw_offer(&self) -> Option<Color> {
+        self.draw_offer
+    }
+
+    pub fn game_over(&self) -> bool {
+        match self.outcome() {
+            Some(outcome) => {
+                info!("Game over, endgame condition reached: {outcome:?}");
+                true
+            }
+            None => false,
         }
     }
 }
@@ -0,0 +1,312 @@
+// Chess clocks. Time is measured with `Instant`, so changes to the system
+// clock don't hand anyone extra time.
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use shakmaty::{ByColor, Color};
+use std::time::{Duration, Instant};
+
+/// How much time each side gets, all durations are in milliseconds
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TimeControl {
+    /// No clock at all
+    #[default]
+    Unlimited,
+    /// `base_ms` for the game, `increment_ms` added after every move
+    #[serde(rename_all = "camelCase")]
+    Increment { base_ms: u64, increment_ms: u64 },
+    /// Every move has to be made in `move_ms`, unused time is lost
+    #[serde(rename_all = "camelCase")]
+    MoveTime { move_ms: u64 },
+    /// `moves` moves have to be made in `base_ms`, after which the clock is
+    /// topped up with `base_ms` again
+    #[serde(rename_all = "camelCase")]
+    MovesIn { moves: u32, base_ms: u64 },
+    /// Bronstein delay: the time used on a move is given back afterwards, up
+    /// to `delay_ms`
+    #[serde(rename_all = "camelCase")]
+    Bronstein { base_ms: u64, delay_ms: u64 },
+    /// Simple (US) delay: the clock only starts running after `delay_ms`
+    #[serde(rename_all = "camelCase")]
+    Delay { base_ms: u64, delay_ms: u64 },
+}
+
+impl TimeControl {
+    fn initial(&self) -> Duration {
+        let ms = match *self {
+            TimeControl::Unlimited => 0,
+            TimeControl::MoveTime { move_ms } => move_ms,
+            TimeControl::Increment { base_ms, .. }
+            | TimeControl::MovesIn { base_ms, .. }
+            | TimeControl::Bronstein { base_ms, .. }
+            | TimeControl::Delay { base_ms, .. } => base_ms,
+        };
+        Duration::from_millis(ms)
+    }
+
+    // Time that doesn't come off the clock at the start of every move
+    fn delay(&self) -> Duration {
+        match *self {
+            TimeControl::Delay { delay_ms, .. } => Duration::from_millis(delay_ms),
+            _ => Duration::ZERO,
+        }
+    }
+
+    // Value for the PGN TimeControl tag, delays don't have a notation of
+    // their own so they're written as increments
+    pub fn pgn_tag(&self) -> String {
+        let secs = |ms: u64| ms as f64 / 1000.0;
+        match *self {
+            TimeControl::Unlimited => "-".to_string(),
+            TimeControl::Increment {
+                base_ms,
+                increment_ms,
+            } => format!("{}+{}", secs(base_ms), secs(increment_ms)),
+            TimeControl::MoveTime { move_ms } => format!("1/{}", secs(move_ms)),
+            TimeControl::MovesIn { moves, base_ms } => format!("{moves}/{}", secs(base_ms)),
+            TimeControl::Bronstein { base_ms, delay_ms }
+            | TimeControl::Delay { base_ms, delay_ms } => {
+                format!("{}+{}", secs(base_ms), secs(delay_ms))
+            }
+        }
+    }
+
+    // Reads the notation `pgn_tag` writes, delays come back as increments
+    pub fn from_pgn_tag(tag: &str) -> Result<Self> {
+        let ms = |secs: &str| -> Result<u64> {
+            let secs: f64 = secs
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid time control {tag}"))?;
+            Ok((secs * 1000.0).round() as u64)
+        };
+
+        let tag = tag.trim();
+        if tag == "-" {
+            return Ok(TimeControl::Unlimited);
+        }
+        if let Some((base, increment)) = tag.split_once('+') {
+            return Ok(TimeControl::Increment {
+                base_ms: ms(base)?,
+                increment_ms: ms(increment)?,
+            });
+        }
+        if let Some((moves, base)) = tag.split_once('/') {
+            let moves: u32 = moves
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid time control {tag}"))?;
+            return Ok(match moves {
+                0 => bail!("Invalid time control {tag}"),
+                1 => TimeControl::MoveTime { move_ms: ms(base)? },
+                moves => TimeControl::MovesIn {
+                    moves,
+                    base_ms: ms(base)?,
+                },
+            });
+        }
+        Ok(TimeControl::Increment {
+            base_ms: ms(tag)?,
+            increment_ms: 0,
+        })
+    }
+}
+
+/// Clock state sent to clients, times in milliseconds
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClockState {
+    pub white_ms: u64,
+    pub black_ms: u64,
+    /// Side whose clock is running, `None` once the game is over
+    pub running: Option<String>,
+}
+
+/// What an engine needs to know to manage its time, mirrors the arguments
+/// of the UCI `go` command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeLeft {
+    /// Side whose clock is running
+    pub side_to_move: Color,
+    pub white: Duration,
+    pub black: Duration,
+    /// Added after every move, delays are reported here too
+    pub increment: Duration,
+    pub moves_to_go: Option<u32>,
+    /// Set for fixed time per move controls, the engine should use exactly this
+    pub move_time: Option<Duration>,
+}
+
+impl TimeLeft {
+    /// Time left for the side to move
+    pub fn own(&self) -> Duration {
+        match self.side_to_move {
+            Color::White => self.white,
+            Color::Black => self.black,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Clock {
+    control: TimeControl,
+    remaining: ByColor<Duration>,
+    moves: ByColor<u32>,
+    // side whose clock is running and since when
+    running: Option<(Color, Instant)>,
+}
+
+impl Clock {
+    pub fn new(control: TimeControl) -> Self {
+        let initial = control.initial();
+        Self {
+            control,
+            remaining: ByColor {
+                white: initial,
+                black: initial,
+            },
+            moves: ByColor { white: 0, black: 0 },
+            running: None,
+        }
+    }
+
+    pub fn control(&self) -> TimeControl {
+        self.control
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.control == TimeControl::Unlimited
+    }
+
+    /// Starts the clock of `color`
+    pub fn start(&mut self, color: Color) {
+        if self.is_unlimited() {
+            return;
+        }
+        if let TimeControl::MoveTime { move_ms } = self.control {
+            *self.remaining.get_mut(color) = Duration::from_millis(move_ms);
+        }
+        self.running = Some((color, Instant::now()));
+    }
+
+    /// Starts the running turn over from now, the time since it started
+    /// isn't charged
+    pub fn restart(&mut self) {
+        if let Some((_, started)) = &mut self.running {
+            *started = Instant::now();
+        }
+    }
+
+    /// Stops the clock for good, e.g. because the game is over
+    pub fn stop(&mut self) {
+        if let Some((color, started)) = self.running.take() {
+            let used = self.charged(started.elapsed());
+            let remaining = self.remaining.get_mut(color);
+            *remaining = remaining.saturating_sub(used);
+        }
+    }
+
+    /// Ends the turn of the side whose clock is running and starts the
+    /// opponent's. Returns the side to move if their flag fell before
+    /// they moved.
+    pub fn press(&mut self) -> Result<(), Color> {
+        let Some((color, started)) = self.running.take() else {
+            return Ok(());
+        };
+
+        let elapsed = started.elapsed();
+        let used = self.charged(elapsed);
+        let remaining = *self.remaining.get(color);
+        if used > remaining {
+            *self.remaining.get_mut(color) = Duration::ZERO;
+            return Err(color);
+        }
+
+        let mut left = remaining - used;
+        *self.moves.get_mut(color) += 1;
+        match self.control {
+            TimeControl::Increment { increment_ms, .. } => {
+                left += Duration::from_millis(increment_ms);
+            }
+            TimeControl::MovesIn { moves, base_ms }
+                if moves > 0 && self.moves.get(color).is_multiple_of(moves) =>
+            {
+                left += Duration::from_millis(base_ms);
+            }
+            TimeControl::Bronstein { delay_ms, .. } => {
+                left += elapsed.min(Duration::from_millis(delay_ms));
+            }
+            _ => {}
+        }
+        *self.remaining.get_mut(color) = left;
+
+        self.start(!color);
+        Ok(())
+    }
+
+    /// Counts a move `color` made before the clock existed, e.g. in a game
+    /// restored after a restart. `remaining` is their time after the move
+    pub fn restore_move(&mut self, color: Color, remaining: Option<Duration>) {
+        *self.moves.get_mut(color) += 1;
+        if let Some(remaining) = remaining {
+            *self.remaining.get_mut(color) = remaining;
+        }
+    }
+
+    // Time that comes off the clock for thinking `elapsed`
+    fn charged(&self, elapsed: Duration) -> Duration {
+        elapsed.saturating_sub(self.control.delay())
+    }
+
+    /// Time left for `color` right now, including the running turn
+    pub fn remaining(&self, color: Color) -> Duration {
+        let remaining = *self.remaining.get(color);
+        match self.running {
+            Some((running, started)) if running == color => {
+                remaining.saturating_sub(self.charged(started.elapsed()))
+            }
+            _ => remaining,
+        }
+    }
+
+    /// Side that has run out of time without moving
+    pub fn flagged(&self) -> Option<Color> {
+        let (color, started) = self.running?;
+        (self.charged(started.elapsed()) > *self.remaining.get(color)).then_some(color)
+    }
+
+    pub fn state(&self) -> ClockState {
+        ClockState {
+            white_ms: self.remaining(Color::White).as_millis() as u64,
+            black_ms: self.remaining(Color::Black).as_millis() as u64,
+            running: self.running.map(|(color, _)| color.to_string()),
+        }
+    }
+
+    /// Time information for the engine about to move, `None` without a limit
+    /// or once the clock is stopped
+    pub fn time_left(&self) -> Option<TimeLeft> {
+        let (side_to_move, _) = self.running?;
+        let (increment, moves_to_go, move_time) = match self.control {
+            TimeControl::Increment { increment_ms, .. } => (increment_ms, None, None),
+            TimeControl::MoveTime { move_ms } => (0, None, Some(Duration::from_millis(move_ms))),
+            TimeControl::MovesIn { moves, .. } => {
+                let played = *self.moves.get(side_to_move) % moves.max(1);
+                (0, Some(moves - played), None)
+            }
+            TimeControl::Bronstein { delay_ms, .. } | TimeControl::Delay { delay_ms, .. } => {
+                (delay_ms, None, None)
+            }
+            // an unlimited clock is never started
+            TimeControl::Unlimited => unreachable!(),
+        };
+
+        Some(TimeLeft {
+            side_to_move,
+            white: self.remaining(Color::White),
+            black: self.remaining(Color::Black),
+            increment: Duration::from_millis(increment),
+            moves_to_go,
+            move_time,
+        })
+    }
+}
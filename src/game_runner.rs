@@ -0,0 +1,145 @@
+// Bot games run as tasks on the server's tokio runtime instead of getting
+// threads of their own. Engines still need a thread while they think, those
+// come from the blocking pool with at most `threads` of them thinking at
+// once, so a server can host hundreds of games with only a few cores busy.
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use shakmaty::{Color, Position};
+use tokio::runtime::Handle;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::task::JoinHandle;
+
+use crate::chess_engine::{next_turn, play_turn, SearchReply, SharedEngine};
+use crate::chess_game::ChessGame;
+use crate::player_vs_bot::PlayerSeat;
+use crate::websocket::GameChannel;
+
+#[derive(Clone)]
+pub struct GameRunner {
+    runtime: Handle,
+    /// One permit per engine allowed to think
+    slots: Arc<Semaphore>,
+    threads: usize,
+}
+
+impl GameRunner {
+    /// Runs games on the current tokio runtime, panics outside of one
+    pub fn new(threads: usize) -> Self {
+        let threads = threads.max(1);
+        Self {
+            runtime: Handle::current(),
+            slots: Arc::new(Semaphore::new(threads)),
+            threads,
+        }
+    }
+
+    /// Engines that can think at the same time
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Spawns a task on the runner's runtime, e.g. a game and whatever has to
+    /// happen once it's over
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.runtime.spawn(task)
+    }
+
+    /// Waits for a free slot and runs `f` on a blocking thread
+    pub async fn compute<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let _slot = self.slot().await?;
+        self.blocking(f).await
+    }
+
+    async fn slot(&self) -> Result<SemaphorePermit<'_>> {
+        self.slots
+            .acquire()
+            .await
+            .map_err(|_| anyhow!("The compute pool is closed"))
+    }
+
+    async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.runtime
+            .spawn_blocking(f)
+            .await
+            .map_err(|e| anyhow!("Computation failed: {e}"))
+    }
+
+    /// Plays `white` against `black` until the game is over, same as
+    /// `engine_vs_engine` without tying up a thread between moves
+    pub async fn play(
+        &self,
+        game: Arc<RwLock<ChessGame>>,
+        white: SharedEngine,
+        black: SharedEngine,
+        channel: GameChannel,
+    ) {
+        info!("Engine vs Engine Started...");
+
+        loop {
+            tokio::time::sleep(pacing(&game, &white, &black)).await;
+            // an engine's turn only starts once it has a slot, waiting for
+            // one doesn't cost it any time
+            let slot = match self.slot().await {
+                Ok(slot) => slot,
+                Err(e) => {
+                    error!("{e}");
+                    return;
+                }
+            };
+            game.write().unwrap().restart_clock();
+            let Some(turn) = next_turn(&game, &channel) else {
+                break;
+            };
+
+            let engine = turn.engine(&white, &black).clone();
+            let request = turn.request.clone();
+            // an engine that panics didn't come up with a move
+            let reply = self
+                .blocking(move || engine.search(&request))
+                .await
+                .unwrap_or_else(|e| {
+                    error!("{} engine crashed: {e}", turn.color);
+                    SearchReply::default()
+                });
+            drop(slot);
+            play_turn(&game, turn, reply, &channel);
+        }
+    }
+
+    /// Lets the bot in a player's game reply if it's its turn, once it's
+    /// done pacing and there's a slot free
+    pub async fn bot_reply(&self, seat: &PlayerSeat) -> Result<()> {
+        tokio::time::sleep(seat.bot_pacing()).await;
+        let seat = seat.clone();
+        self.compute(move || seat.bot_reply()).await?
+    }
+}
+
+// How long the engine to move wants to wait before it thinks, pacing is
+// waited out here rather than on a thread from the pool
+fn pacing(game: &RwLock<ChessGame>, white: &SharedEngine, black: &SharedEngine) -> Duration {
+    let game = game.read().unwrap();
+    if game.outcome().is_some() {
+        return Duration::ZERO;
+    }
+    let engine = match game.game.turn() {
+        Color::White => white,
+        Color::Black => black,
+    };
+    engine.pacing(game.clock().time_left().as_ref())
+}
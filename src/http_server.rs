@@ -1,11 +1,11 @@
-use actix::Addr;
 use actix_web_actors::ws;
-use std::sync::mpsc;
 use std::{
     collections::HashMap,
+    path::PathBuf,
     sync::{Arc, Mutex, RwLock},
 };
-use tokio::task::JoinSet;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
 
 use actix_cors::Cors;
 use actix_files as fs;
@@ -24,18 +24,51 @@ use uuid::Uuid;
 
 use shakmaty::uci::Uci;
 
-use crate::chess_engine;
-use crate::player_vs_bot::PlayerGame;
-use crate::websocket::MyWebSocket;
-use crate::{chess_engine::engine_vs_engine, chess_game::ChessGame};
+use crate::bot_registry::{self, BotRegistry, RANDOM_BOT_ID};
+use crate::game_runner::GameRunner;
+use crate::lifecycle::{Lifecycle, LifecycleConfig};
+use crate::lobby::{self, GameFilter, GamePage};
+use crate::pgn;
+use crate::player_vs_bot::{PlayerGame, PlayerSeat};
+use crate::protocol::ServerMessage;
+use crate::rating::{Comparison, LeaderboardEntry, Ratings};
+use crate::sprt::{run_sprt, SprtConfig, SprtMatch};
+use crate::storage::{GameMode, Storage, StoredBot, StoredGame};
+use crate::tournament::{run_tournament, GameLauncher, Tournament, TournamentConfig};
+use crate::websocket::{game_channel, GameChannel, MyWebSocket, Snapshots};
+use crate::{
+    chess_engine::SharedEngine,
+    chess_game::{ChessGame, GameOutcome, GameResult, PlayedMove, Termination},
+    clock::{ClockState, TimeControl},
+};
 
 pub type GameMap = DashMap<Uuid, Arc<RwLock<ChessGame>>>;
-pub type Connection = Addr<MyWebSocket>;
-pub type SharedState = Arc<RwLock<Vec<Connection>>>;
+pub type ActiveProcesses = Arc<Mutex<HashMap<Uuid, JoinHandle<()>>>>;
+pub type TournamentMap = DashMap<Uuid, Arc<RwLock<Tournament>>>;
+pub type SprtMap = DashMap<Uuid, Arc<RwLock<SprtMatch>>>;
+
+// Where opening files sent with tournaments and SPRT matches are read from,
+// they can only use inline positions without one
+struct OpeningsDir(Option<PathBuf>);
 
 #[derive(Deserialize, Debug)]
 struct NewGameArgs {
     mode: String,
+    /// Bot ids from `/bots`, defaults to the random bot. In player vs bot
+    /// games the player is white so only `black` is used
+    white: Option<String>,
+    black: Option<String>,
+    /// Starting position, defaults to the standard one
+    fen: Option<String>,
+    /// Defaults to no clock
+    time_control: Option<TimeControl>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewBotArgs {
+    name: Option<String>,
+    /// Lua source following the contract in `client/template.lua`
+    script: String,
 }
 
 #[get("/ping")]
@@ -43,66 +76,111 @@ async fn ping() -> impl Responder {
     HttpResponse::Ok().body("pong".to_string())
 }
 
+// one extractor per piece of shared state
+#[allow(clippy::too_many_arguments)]
 #[post("/new_game")]
 async fn new_game(
     app_data: web::Data<GameMap>,
-    active_processes: web::Data<Arc<Mutex<HashMap<Uuid, JoinSet<()>>>>>,
+    active_processes: web::Data<ActiveProcesses>,
     active_player_games: web::Data<DashMap<Uuid, PlayerGame>>,
-    connections: web::Data<DashMap<Uuid, SharedState>>,
+    connections: web::Data<DashMap<Uuid, GameChannel>>,
+    bots: web::Data<BotRegistry>,
+    ratings: web::Data<Ratings>,
+    storage: web::Data<dyn Storage>,
+    runner: web::Data<GameRunner>,
     req_body: Json<NewGameArgs>,
 ) -> impl Responder {
     info!("recieved request!");
     // Generate a new UUID
     let new_game_id = Uuid::new_v4();
 
+    let white_id = req_body.white.as_deref().unwrap_or(RANDOM_BOT_ID);
+    let black_id = req_body.black.as_deref().unwrap_or(RANDOM_BOT_ID);
+    let (black, black_info) = match (bots.engine(black_id), bots.info(black_id)) {
+        (Ok(engine), Ok(info)) => (engine, info),
+        (Err(e), _) | (_, Err(e)) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    let mut start = match &req_body.fen {
+        Some(fen) => match ChessGame::from_fen(fen) {
+            Ok(game) => game,
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+        },
+        None => ChessGame::new(),
+    };
+    start.black = black_info.name.clone();
+    if let Some(time_control) = req_body.time_control {
+        start.set_time_control(time_control);
+    }
+
+    // only for the player of a game against a bot
+    let mut seat_token = None;
     match req_body.mode.as_str() {
         "playerVsBot" => {
-            // TODO: allow bot id in request body to select bot to play here
-            let bot = chess_engine::RandomEngine::new();
-            let game = PlayerGame::new(bot);
+            start.white = bot_registry::player().name;
+            let game = PlayerGame::new(black, black_info, start);
+            store_game(&**storage, game.stored(new_game_id));
+            seat_token = Some(game.seat_token);
             info!("Starting Player vs Bot Game: {new_game_id}");
+            let channel = game_channel();
+            connections.insert(new_game_id, channel.clone());
             active_player_games.insert(new_game_id, game);
+
+            // the bot may have the first move
+            let seat = PlayerSeat::new(
+                new_game_id,
+                active_player_games.into_inner(),
+                channel,
+                storage.into_inner(),
+                ratings.into_inner(),
+            );
+            if let Err(e) = runner.bot_reply(&seat).await {
+                error!("Bot failed to play the opening move: {e}");
+            }
         }
         "botVsBot" => {
-            let game = Arc::new(RwLock::new(ChessGame::new()));
-            let engine1 = Arc::new(crate::chess_engine::RandomEngine::new());
-            let engine2 = Arc::new(crate::chess_engine::RandomEngine::new());
-
-            let game_clone = game.clone();
-            let engine1_clone = engine1.clone();
-            let engine2_clone = engine2.clone();
-
-            let (tx, rx) = mpsc::channel();
-
-            let mut game_join_set = JoinSet::new();
+            let (white, white_info) = match (bots.engine(white_id), bots.info(white_id)) {
+                (Ok(engine), Ok(info)) => (engine, info),
+                (Err(e), _) | (_, Err(e)) => return HttpResponse::BadRequest().body(e.to_string()),
+            };
 
-            game_join_set.spawn_blocking(move || {
-                engine_vs_engine(game_clone, engine1_clone, engine2_clone, tx);
-            });
+            start.white = white_info.name.clone();
+            let (white_bot, black_bot) = (white_info.id.clone(), black_info.id.clone());
+            let stored = move |game: &ChessGame| StoredGame {
+                white_bot: Some(white_bot.clone()),
+                black_bot: Some(black_bot.clone()),
+                ..StoredGame::new(new_game_id, GameMode::BotVsBot, game)
+            };
+            store_game(&**storage, stored(&start));
+            let game = Arc::new(RwLock::new(start));
+            let white: SharedEngine = Arc::from(white);
+            let black: SharedEngine = Arc::from(black);
 
-            let new_game_connections: SharedState = Arc::new(RwLock::new(Vec::new()));
-            connections.insert(new_game_id, new_game_connections.clone());
-            game_join_set.spawn_blocking(move || {
-                loop {
-                    let result = match rx.recv() {
-                        Ok(message) => message,
-                        Err(e) => {
-                            error!("Game over, terminating the thread: {:?}", e);
-                            break; // Exit the loop and end the thread
-                        }
-                    };
+            let channel = game_channel();
+            connections.insert(new_game_id, channel.clone());
 
-                    let connections_clone = new_game_connections.read().unwrap();
-                    for conn in connections_clone.iter() {
-                        conn.do_send(result.clone());
-                    }
+            // the game is a task on the runner, its moves go out on the
+            // channel to everyone watching
+            store_moves(
+                storage.into_inner(),
+                &runner,
+                new_game_id,
+                game.clone(),
+                &channel,
+            );
+            let game_clone = game.clone();
+            let player = runner.clone();
+            let task = runner.spawn(async move {
+                player.play(game_clone.clone(), white, black, channel).await;
+                let outcome = game_clone.read().unwrap().outcome();
+                if let Some(outcome) = outcome {
+                    ratings.record(new_game_id, &white_info, &black_info, outcome);
                 }
             });
 
-            // not doing anything with set right now, but save in case in the future
-            // we want to do some graceful shutdown logic
+            // dropped once the game is archived
             let mut active_tasks = active_processes.lock().unwrap();
-            active_tasks.insert(new_game_id, game_join_set);
+            active_tasks.insert(new_game_id, task);
 
             info!("inserted game {} into active tasks", new_game_id);
             app_data.insert(new_game_id, game);
@@ -114,19 +192,292 @@ async fn new_game(
 
     // Return the new game ID to the client
     info!("reached return ");
-    HttpResponse::Ok().json(serde_json::json!({ "game_id": new_game_id.to_string() }))
+    HttpResponse::Ok().json(serde_json::json!({
+        "game_id": new_game_id.to_string(),
+        "seat_token": seat_token,
+    }))
+}
+
+#[post("/bots")]
+async fn new_bot(
+    bots: web::Data<BotRegistry>,
+    storage: web::Data<dyn Storage>,
+    req_body: Json<NewBotArgs>,
+) -> impl Responder {
+    let NewBotArgs { name, script } = req_body.into_inner();
+
+    // validating plays a short game, keep it off the async workers
+    let source = script.clone();
+    let result = web::block(move || bots.register(name, script)).await;
+    match result {
+        Ok(Ok(bot)) => {
+            let stored = StoredBot {
+                id: bot.id.clone(),
+                name: bot.name.clone(),
+                script: source,
+            };
+            if let Err(e) = storage.save_bot(&stored) {
+                error!("Failed to store bot {}: {e}", bot.id);
+            }
+            HttpResponse::Ok().json(bot)
+        }
+        Ok(Err(e)) => HttpResponse::BadRequest().body(format!("Invalid bot: {e}")),
+        Err(e) => {
+            error!("Bot validation panicked: {e}");
+            HttpResponse::InternalServerError().body("Failed to validate bot")
+        }
+    }
+}
+
+#[get("/bots")]
+async fn list_bots(bots: web::Data<BotRegistry>) -> impl Responder {
+    HttpResponse::Ok().json(bots.list())
+}
+
+// Storage problems shouldn't take the game down with them
+fn store_game(storage: &dyn Storage, game: StoredGame) {
+    if let Err(e) = storage.save_game(&game) {
+        error!("Failed to store game {}: {e}", game.id);
+    }
+}
+
+// Stores the moves of a bot game as they're played and its result once it's
+// over, so a crash only loses the last move. Looks at the game whenever
+// something happens in it
+fn store_moves(
+    storage: Arc<dyn Storage>,
+    runner: &GameRunner,
+    game_id: Uuid,
+    game: Arc<RwLock<ChessGame>>,
+    channel: &GameChannel,
+) {
+    let mut updates = channel.subscribe();
+    runner.spawn(async move {
+        let mut closed = false;
+        loop {
+            // under the lock, so the shutdown storing the result at the same
+            // time can't store the same moves twice
+            let (outcome, stored) = {
+                let game = game.read().unwrap();
+                let outcome = game.outcome();
+                let stored = storage.game(game_id).and_then(|stored| {
+                    let (known, ended) =
+                        stored.map_or((0, false), |s| (s.moves.len(), s.outcome.is_some()));
+                    let moves = &game.history()[known.min(game.history().len())..];
+                    if moves.is_empty() && (outcome.is_none() || ended) {
+                        return Ok(());
+                    }
+                    storage.record_moves(game_id, moves, outcome)
+                });
+                (outcome, stored)
+            };
+            if let Err(e) = stored {
+                error!("Failed to store moves of {game_id}: {e}");
+            }
+            if outcome.is_some() || closed {
+                break;
+            }
+            closed = matches!(updates.recv().await, Err(RecvError::Closed));
+        }
+    });
+}
+
+// Makes a game played somewhere else (e.g. by a tournament) spectatable,
+// everything sent on the returned channel goes out to its spectators. The
+// game is stored when it starts and then move by move
+fn host_game(
+    app_data: &GameMap,
+    connections: &DashMap<Uuid, GameChannel>,
+    storage: Arc<dyn Storage>,
+    runner: &GameRunner,
+    game_id: Uuid,
+    game: Arc<RwLock<ChessGame>>,
+) -> GameChannel {
+    let channel = game_channel();
+    connections.insert(game_id, channel.clone());
+    app_data.insert(game_id, game.clone());
+    let stored = StoredGame::new(game_id, GameMode::BotVsBot, &game.read().unwrap());
+    store_game(&*storage, stored);
+    store_moves(storage, runner, game_id, game, &channel);
+    channel
+}
+
+// one extractor per piece of shared state
+#[allow(clippy::too_many_arguments)]
+#[post("/tournaments")]
+async fn new_tournament(
+    app_data: web::Data<GameMap>,
+    connections: web::Data<DashMap<Uuid, GameChannel>>,
+    tournaments: web::Data<TournamentMap>,
+    bots: web::Data<BotRegistry>,
+    ratings: web::Data<Ratings>,
+    storage: web::Data<dyn Storage>,
+    runner: web::Data<GameRunner>,
+    openings_dir: web::Data<OpeningsDir>,
+    req_body: Json<TournamentConfig>,
+) -> impl Responder {
+    let mut config = req_body.into_inner();
+    config.openings = match config
+        .openings
+        .map(|o| o.within(openings_dir.0.as_deref()))
+        .transpose()
+    {
+        Ok(openings) => openings,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let tournament_id = Uuid::new_v4();
+    let tournament = match Tournament::new(tournament_id, config, &bots) {
+        Ok(tournament) => Arc::new(RwLock::new(tournament)),
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    tournaments.insert(tournament_id, tournament.clone());
+
+    let storage = storage.into_inner();
+    let pool = runner.get_ref().clone();
+    let launcher: GameLauncher = Arc::new(move |game_id, game| {
+        host_game(
+            &app_data,
+            &connections,
+            storage.clone(),
+            &runner,
+            game_id,
+            game,
+        )
+    });
+    let (bots, ratings) = (bots.into_inner(), ratings.into_inner());
+    pool.spawn(run_tournament(
+        tournament,
+        bots,
+        ratings,
+        launcher,
+        pool.clone(),
+    ));
+
+    HttpResponse::Ok().json(serde_json::json!({ "tournament_id": tournament_id.to_string() }))
+}
+
+#[get("/tournaments")]
+async fn list_tournaments(tournaments: web::Data<TournamentMap>) -> impl Responder {
+    let mut summaries: Vec<_> = tournaments
+        .iter()
+        .map(|t| t.read().unwrap().summary())
+        .collect();
+    summaries.sort_by_key(|s| s.name.clone());
+    HttpResponse::Ok().json(summaries)
+}
+
+#[get("/tournaments/{uuid}")]
+/// Pairings, results and crosstable of a tournament
+async fn tournament_details(
+    tournaments: web::Data<TournamentMap>,
+    uuid: web::Path<Uuid>,
+) -> impl Responder {
+    let Some(tournament) = tournaments.get(&uuid) else {
+        return HttpResponse::NotFound().body(format!("No tournament {uuid}"));
+    };
+
+    let tournament = tournament.read().unwrap();
+    HttpResponse::Ok().json(json!({
+        "tournament": &*tournament,
+        "crosstable": tournament.crosstable(),
+    }))
+}
+
+// one extractor per piece of shared state
+#[allow(clippy::too_many_arguments)]
+#[post("/sprt")]
+async fn new_sprt(
+    app_data: web::Data<GameMap>,
+    connections: web::Data<DashMap<Uuid, GameChannel>>,
+    matches: web::Data<SprtMap>,
+    bots: web::Data<BotRegistry>,
+    ratings: web::Data<Ratings>,
+    storage: web::Data<dyn Storage>,
+    runner: web::Data<GameRunner>,
+    openings_dir: web::Data<OpeningsDir>,
+    req_body: Json<SprtConfig>,
+) -> impl Responder {
+    let mut config = req_body.into_inner();
+    config.openings = match config.openings.within(openings_dir.0.as_deref()) {
+        Ok(openings) => openings,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let sprt_id = Uuid::new_v4();
+    let sprt = match SprtMatch::new(sprt_id, config, &bots) {
+        Ok(sprt) => Arc::new(RwLock::new(sprt)),
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    matches.insert(sprt_id, sprt.clone());
+
+    let storage = storage.into_inner();
+    let pool = runner.get_ref().clone();
+    let launcher: GameLauncher = Arc::new(move |game_id, game| {
+        host_game(
+            &app_data,
+            &connections,
+            storage.clone(),
+            &runner,
+            game_id,
+            game,
+        )
+    });
+    let (bots, ratings) = (bots.into_inner(), ratings.into_inner());
+    pool.spawn(run_sprt(sprt, bots, ratings, launcher, pool.clone()));
+
+    HttpResponse::Ok().json(serde_json::json!({ "sprt_id": sprt_id.to_string() }))
+}
+
+#[get("/sprt")]
+async fn list_sprts(matches: web::Data<SprtMap>) -> impl Responder {
+    let mut summaries: Vec<_> = matches
+        .iter()
+        .map(|s| s.read().unwrap().summary())
+        .collect();
+    summaries.sort_by_key(|s| (s.candidate.clone(), s.baseline.clone()));
+    HttpResponse::Ok().json(summaries)
+}
+
+#[get("/sprt/{uuid}")]
+/// Status, LLR, pentanomial statistics and Elo estimate of a match
+async fn sprt_details(matches: web::Data<SprtMap>, uuid: web::Path<Uuid>) -> impl Responder {
+    let Some(sprt) = matches.get(&uuid) else {
+        return HttpResponse::NotFound().body(format!("No SPRT match {uuid}"));
+    };
+    let sprt = sprt.read().unwrap();
+    HttpResponse::Ok().json(&*sprt)
+}
+
+#[get("/tournament/{uuid}")]
+async fn tournament_page(
+    tournaments: web::Data<TournamentMap>,
+    hb: web::Data<Handlebars<'_>>,
+    uuid: web::Path<Uuid>,
+) -> impl Responder {
+    if !tournaments.contains_key(&uuid) {
+        return HttpResponse::NotFound().body(format!("No tournament {uuid}"));
+    }
+
+    let data = json!({ "tournament_id": uuid.to_string() });
+    let body = hb
+        .render("tournament_template", &data)
+        .unwrap_or_else(|err| {
+            error!("Template rendering error: {}", err);
+            "Template rendering error".to_string()
+        });
+
+    HttpResponse::Ok().content_type("text/html").body(body)
 }
 
 #[get("/spectate/{uuid}")]
 async fn spectate_game(
-    app_data: web::Data<GameMap>,
+    lifecycle: web::Data<Lifecycle>,
     hb: web::Data<Handlebars<'_>>,
     info: web::Path<Uuid>,
 ) -> impl Responder {
     let game_uuid = info.into_inner();
 
     // Fetch the game data
-    let game_data = match app_data.get(&game_uuid) {
+    let game_data = match lifecycle.game(game_uuid) {
         Some(game) => game,
         None => return HttpResponse::NotFound().body("Game not found"),
     };
@@ -136,7 +487,7 @@ async fn spectate_game(
         Err(_) => return HttpResponse::InternalServerError().body("Failed to lock game data"),
     };
 
-    let position = gd_lock.fen();
+    let position = gd_lock.board_fen();
     let css_content = std::fs::read_to_string("./client/css/chessboard-1.0.0.min.css").unwrap();
     let js_content = std::fs::read_to_string("./client/js/chessboard-1.0.0.js").unwrap();
 
@@ -157,32 +508,117 @@ async fn spectate_game(
     HttpResponse::Ok().content_type("text/html").body(body)
 }
 
+#[derive(Deserialize, Debug)]
+pub struct SpectateArgs {
+    /// Moves the client already has, when reconnecting
+    #[serde(default)]
+    pub from_ply: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PlayArgs {
+    /// Seat token `/new_game` answered with
+    pub token: Uuid,
+    #[serde(default)]
+    pub from_ply: usize,
+}
+
+// Whether `token` is the seat token of the player game `game_id`
+fn holds_seat(player_games: &DashMap<Uuid, PlayerGame>, game_id: Uuid, token: Uuid) -> bool {
+    player_games
+        .get(&game_id)
+        .is_some_and(|game| game.seat_token == token)
+}
+
 pub async fn ws_index(
     req: HttpRequest,
     stream: web::Payload,
     uuid: web::Path<Uuid>, // Extract UUID from the path
-    connections: web::Data<DashMap<Uuid, SharedState>>,
+    args: web::Query<SpectateArgs>,
+    connections: web::Data<DashMap<Uuid, GameChannel>>,
+    player_games: web::Data<DashMap<Uuid, PlayerGame>>,
+    lifecycle: web::Data<Lifecycle>,
 ) -> Result<HttpResponse, Error> {
     info!("New Connection to Game: {}", &uuid);
-    match connections.get(&uuid) {
+    let game_id = uuid.into_inner();
+    match connections.get(&game_id) {
         Some(game_conns) => {
-            let game_conns: SharedState = game_conns.clone();
-            let ws = MyWebSocket::new(game_conns);
+            let game_conns = game_conns.clone();
+            let snapshots: Snapshots = Box::new(move |from_ply| {
+                if let Some(game) = player_games.get(&game_id) {
+                    return Some(ServerMessage::snapshot(&game.game, from_ply));
+                }
+                let game = lifecycle.game(game_id)?;
+                let game = game.read().unwrap();
+                Some(ServerMessage::snapshot(&game, from_ply))
+            });
+            let ws = MyWebSocket::new(game_id, game_conns, snapshots, args.from_ply);
             ws::start(ws, &req, stream)
         }
         None => {
-            let err_msg = format!("Room {} not found", &uuid);
+            let err_msg = format!("Room {} not found", &game_id);
             let err = std::io::Error::new(std::io::ErrorKind::NotFound, err_msg);
-            return Err(err.into());
+            Err(err.into())
         }
     }
 }
 
+// The player's own connection to a game against a bot. It gets everything
+// spectators get and can send moves, resign, offer draws and ask for
+// takebacks
+#[allow(clippy::too_many_arguments)]
+pub async fn play_ws(
+    req: HttpRequest,
+    stream: web::Payload,
+    uuid: web::Path<Uuid>,
+    args: web::Query<PlayArgs>,
+    connections: web::Data<DashMap<Uuid, GameChannel>>,
+    player_games: web::Data<DashMap<Uuid, PlayerGame>>,
+    storage: web::Data<dyn Storage>,
+    ratings: web::Data<Ratings>,
+    runner: web::Data<GameRunner>,
+) -> Result<HttpResponse, Error> {
+    let game_id = uuid.into_inner();
+    if !player_games.contains_key(&game_id) {
+        return Err(actix_web::error::ErrorNotFound(format!(
+            "No active game for {game_id}"
+        )));
+    }
+    if !holds_seat(&player_games, game_id, args.token) {
+        return Err(actix_web::error::ErrorForbidden(
+            "Only the player can join with this token",
+        ));
+    }
+    info!("Player connected to game: {game_id}");
+
+    let game_conns = connections
+        .entry(game_id)
+        .or_insert_with(game_channel)
+        .clone();
+    let player_games = player_games.into_inner();
+    let seat = PlayerSeat::new(
+        game_id,
+        player_games.clone(),
+        game_conns.clone(),
+        storage.into_inner(),
+        ratings.into_inner(),
+    );
+    let snapshots: Snapshots = Box::new(move |from_ply| {
+        let game = player_games.get(&game_id)?;
+        Some(ServerMessage::snapshot(&game.game, from_ply))
+    });
+    let ws = MyWebSocket::new(game_id, game_conns, snapshots, args.from_ply)
+        .with_seat(seat, runner.get_ref().clone());
+    ws::start(ws, &req, stream)
+}
+
 #[derive(Deserialize, Debug)]
 struct PlayGameArgs {
     #[serde(rename = "move", deserialize_with = "parse_uci")]
     /// Move in UCI notation
     player_move: Uci,
+    /// Seat token `/new_game` answered with
+    token: Uuid,
 }
 
 fn parse_uci<'de, D>(deserializer: D) -> Result<Uci, D::Error>
@@ -195,48 +631,272 @@ where
 
 #[derive(Serialize, Debug)]
 struct PlayGameResponse {
-    /// State in fen representation
+    /// Piece placement part of the fen, for chessboard.js
     board_state: String,
+    /// Full fen of the position after the bot's reply
+    fen: String,
+    /// Set once the game is over
+    outcome: Option<GameOutcome>,
+    clock: Option<ClockState>,
+}
+
+#[derive(Serialize, Debug)]
+struct GameHistoryResponse {
+    start_fen: String,
+    moves: Vec<PlayedMove>,
+    outcome: Option<GameOutcome>,
+}
+
+#[derive(Serialize, Debug)]
+struct GameResultResponse {
+    game_id: Uuid,
+    fen: String,
+    outcome: Option<GameOutcome>,
+    clock: Option<ClockState>,
 }
 
 #[post("/play/{uuid}")]
 /// Play a given move against a bot
 pub async fn player_vs_bot(
     active_player_games: web::Data<DashMap<Uuid, PlayerGame>>,
+    connections: web::Data<DashMap<Uuid, GameChannel>>,
+    ratings: web::Data<Ratings>,
+    storage: web::Data<dyn Storage>,
+    runner: web::Data<GameRunner>,
     req_body: Json<PlayGameArgs>,
     uuid: web::Path<Uuid>,
 ) -> actix_web::Result<Json<PlayGameResponse>> {
-    let Some(mut game) = active_player_games.get_mut(&uuid) else {
+    if !active_player_games.contains_key(&uuid) {
         return Err(actix_web::error::ErrorBadRequest(format!(
             "No active game for {uuid}"
         )));
-    };
+    }
+    if !holds_seat(&active_player_games, *uuid, req_body.token) {
+        return Err(actix_web::error::ErrorForbidden(
+            "Only the player can play with this token",
+        ));
+    }
 
     log::debug!("Player Trying to play: {}", req_body.player_move);
 
-    let player_move = req_body
-        .player_move
-        .to_move(&game.game.game /*lmao*/)
-        .map_err(|e| {
-            actix_web::error::ErrorBadRequest(format!(
-                "Error Playing Move {}: {e}",
-                req_body.player_move
-            ))
-        })?;
-
-    match game.play_move(player_move) {
-        Ok(_) => {}
+    // same as a move over the player's websocket, except the bot's reply
+    // comes back in the response
+    let seat = PlayerSeat::new(
+        *uuid,
+        active_player_games.into_inner(),
+        connections
+            .entry(*uuid)
+            .or_insert_with(game_channel)
+            .clone(),
+        storage.into_inner(),
+        ratings.into_inner(),
+    );
+    // the bot thinks on the runner's pool rather than on this worker
+    let played = match seat.play(&req_body.player_move) {
+        Ok(true) => runner.bot_reply(&seat).await,
+        Ok(false) => Ok(()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = played {
+        error!("Error playing move: {}", e);
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "Error Playing Move {}: {e}",
+            req_body.player_move
+        )));
+    }
+
+    let Some(game) = seat.games.get(&uuid) else {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "No active game for {uuid}"
+        )));
+    };
+    Ok(Json(PlayGameResponse {
+        board_state: game.game.board_fen(),
+        fen: game.fen(),
+        outcome: game.game.outcome(),
+        clock: game.game.clock_state(),
+    }))
+}
+
+#[get("/leaderboard")]
+/// Ratings of every bot that finished a game, strongest first
+async fn leaderboard(ratings: web::Data<Ratings>) -> Json<Vec<LeaderboardEntry>> {
+    Json(ratings.leaderboard())
+}
+
+#[derive(Deserialize, Debug)]
+struct CompareArgs {
+    a: String,
+    b: String,
+}
+
+#[get("/leaderboard/compare")]
+/// Head to head record and likelihood of superiority of bot `a` over `b`
+async fn compare_bots(
+    ratings: web::Data<Ratings>,
+    query: web::Query<CompareArgs>,
+) -> actix_web::Result<Json<Comparison>> {
+    ratings
+        .compare(&query.a, &query.b)
+        .map(Json)
+        .ok_or(actix_web::error::ErrorNotFound(format!(
+            "{} and {} need to be rated first",
+            query.a, query.b
+        )))
+}
+
+#[get("/games")]
+/// Games with their players, position and result, newest first. Filtered by
+/// mode, status, bot and start time and paged with offset and limit
+async fn list_games(
+    lifecycle: web::Data<Lifecycle>,
+    filter: web::Query<GameFilter>,
+) -> actix_web::Result<Json<GamePage>> {
+    let games = lifecycle
+        .summaries()
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(Json(lobby::list_games(games, &filter)))
+}
+
+#[get("/lobby")]
+/// Live bot games to pick one to spectate
+async fn lobby_page() -> impl Responder {
+    match std::fs::read_to_string("./client/lobby.html") {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
         Err(e) => {
-            error!("Error playing move: {}", e);
-            return Err(actix_web::error::ErrorBadRequest(format!(
-                "Error Playing Move {}: {e}",
-                req_body.player_move
-            )));
+            error!("Failed to read the lobby page: {e}");
+            HttpResponse::InternalServerError().finish()
         }
     }
+}
 
-    Ok(Json(PlayGameResponse {
-        board_state: game.fen(),
+#[get("/game/{uuid}/pgn")]
+/// Moves played so far in PGN, works for finished and running games
+async fn game_pgn(
+    lifecycle: web::Data<Lifecycle>,
+    active_player_games: web::Data<DashMap<Uuid, PlayerGame>>,
+    uuid: web::Path<Uuid>,
+) -> impl Responder {
+    let game_id = uuid.into_inner();
+
+    let pgn = if let Some(game) = active_player_games.get(&game_id) {
+        pgn::to_pgn(&game.game)
+    } else if let Some(game) = lifecycle.game(game_id) {
+        pgn::to_pgn(&game.read().unwrap())
+    } else {
+        return HttpResponse::NotFound().body(format!("No game for {game_id}"));
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/x-chess-pgn")
+        .insert_header((
+            http::header::CONTENT_DISPOSITION,
+            format!("inline; filename=\"{game_id}.pgn\""),
+        ))
+        .body(pgn)
+}
+
+#[post("/games/import")]
+/// Loads the games in a PGN so they can be replayed on the spectate page.
+/// Nothing is imported unless every game can be
+async fn import_game(
+    app_data: web::Data<GameMap>,
+    connections: web::Data<DashMap<Uuid, GameChannel>>,
+    storage: web::Data<dyn Storage>,
+    req_body: String,
+) -> impl Responder {
+    let games: Vec<ChessGame> = match pgn::split_games(&req_body)
+        .into_iter()
+        .enumerate()
+        .map(|(i, game)| pgn::from_pgn(game).map_err(|e| format!("Game {}: {e}", i + 1)))
+        .collect()
+    {
+        Ok(games) => games,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    if games.is_empty() {
+        return HttpResponse::BadRequest().body("No games in the PGN");
+    }
+
+    let mut game_ids = Vec::new();
+    for game in games {
+        let game_id = Uuid::new_v4();
+        info!(
+            "Imported game {game_id}: {} vs {}, {} plies",
+            game.white,
+            game.black,
+            game.history().len()
+        );
+
+        store_game(
+            &**storage,
+            StoredGame::new(game_id, GameMode::Imported, &game),
+        );
+
+        // nothing will ever be sent, but the spectate page expects to be able
+        // to connect
+        connections.insert(game_id, game_channel());
+        app_data.insert(game_id, Arc::new(RwLock::new(game)));
+        game_ids.push(game_id.to_string());
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "game_id": game_ids[0],
+        "game_ids": game_ids,
+    }))
+}
+
+#[get("/game/{uuid}/history")]
+/// Every move of the game along with the position it led to
+async fn game_history(
+    lifecycle: web::Data<Lifecycle>,
+    active_player_games: web::Data<DashMap<Uuid, PlayerGame>>,
+    uuid: web::Path<Uuid>,
+) -> actix_web::Result<Json<GameHistoryResponse>> {
+    let game_id = uuid.into_inner();
+
+    let history = |game: &ChessGame| GameHistoryResponse {
+        start_fen: game.start_fen(),
+        moves: game.history().to_vec(),
+        outcome: game.outcome(),
+    };
+
+    if let Some(game) = active_player_games.get(&game_id) {
+        Ok(Json(history(&game.game)))
+    } else if let Some(game) = lifecycle.game(game_id) {
+        Ok(Json(history(&game.read().unwrap())))
+    } else {
+        Err(actix_web::error::ErrorNotFound(format!(
+            "No game for {game_id}"
+        )))
+    }
+}
+
+#[get("/game/{uuid}/result")]
+/// Current position and, once it is over, the result of any game
+async fn game_result(
+    lifecycle: web::Data<Lifecycle>,
+    active_player_games: web::Data<DashMap<Uuid, PlayerGame>>,
+    uuid: web::Path<Uuid>,
+) -> actix_web::Result<Json<GameResultResponse>> {
+    let game_id = uuid.into_inner();
+
+    let (fen, outcome, clock) = if let Some(game) = active_player_games.get(&game_id) {
+        (game.fen(), game.game.outcome(), game.game.clock_state())
+    } else if let Some(game) = lifecycle.game(game_id) {
+        let game = game.read().unwrap();
+        (game.fen(), game.outcome(), game.clock_state())
+    } else {
+        return Err(actix_web::error::ErrorNotFound(format!(
+            "No game for {game_id}"
+        )));
+    };
+
+    Ok(Json(GameResultResponse {
+        game_id,
+        fen,
+        outcome,
+        clock,
     }))
 }
 
@@ -258,7 +918,7 @@ async fn play_game_entry(
     // Create data to fill the template
     let data = json!({
         "game_id": uuid.to_string(),
-        "position": game.fen(),
+        "position": game.game.board_fen(),
         "style": css_content,
         "board_js":js_content
     });
@@ -272,9 +932,95 @@ async fn play_game_entry(
     Ok(HttpResponse::Ok().content_type("text/html").body(body))
 }
 
-pub async fn start_server(hostname: String, port: u16) -> std::io::Result<()> {
+// Picks the unfinished player games back up, anything else that was cut
+// short by the restart is adjudicated. Finished games stay archived in the
+// storage until someone asks for them
+fn restore_games(
+    storage: &Arc<dyn Storage>,
+    bots: &BotRegistry,
+    player_games: &Arc<DashMap<Uuid, PlayerGame>>,
+    connections: &DashMap<Uuid, GameChannel>,
+    ratings: &Arc<Ratings>,
+    runner: &GameRunner,
+) {
+    let stored_games = match storage.games() {
+        Ok(games) => games,
+        Err(e) => {
+            error!("Failed to load stored games: {e}");
+            return;
+        }
+    };
+
+    let (mut resumed, mut adjudicated) = (0, 0);
+    for stored in stored_games.iter().filter(|g| g.outcome.is_none()) {
+        let game = match stored.restore() {
+            Ok(game) => game,
+            Err(e) => {
+                error!("{e}");
+                continue;
+            }
+        };
+
+        if stored.mode == GameMode::PlayerVsBot {
+            let bot = stored.black_bot.as_deref().unwrap_or(RANDOM_BOT_ID);
+            match (bots.engine(bot), bots.info(bot)) {
+                (Ok(engine), Ok(info)) => {
+                    let channel = game_channel();
+                    connections.insert(stored.id, channel.clone());
+                    let mut game = PlayerGame::new(engine, info, game);
+                    // games stored before seats had tokens get a new one
+                    game.seat_token = stored.seat_token.unwrap_or(game.seat_token);
+                    player_games.insert(stored.id, game);
+                    resumed += 1;
+
+                    // the bot moves straight away if it was its turn
+                    let seat = PlayerSeat::new(
+                        stored.id,
+                        player_games.clone(),
+                        channel,
+                        storage.clone(),
+                        ratings.clone(),
+                    );
+                    let replier = runner.clone();
+                    runner.spawn(async move {
+                        if let Err(e) = replier.bot_reply(&seat).await {
+                            error!("Bot failed to move in game {}: {e}", seat.game_id);
+                        }
+                    });
+                    continue;
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("Can't resume game {}: {e}", stored.id);
+                }
+            }
+        }
+
+        let outcome = GameOutcome::new(GameResult::Draw, Termination::Adjudication);
+        if let Err(e) = storage.record_moves(stored.id, &[], Some(outcome)) {
+            error!("Failed to store result of {}: {e}", stored.id);
+        }
+        adjudicated += 1;
+    }
+    info!(
+        "{} stored games, {resumed} player games can be continued, {adjudicated} adjudicated",
+        stored_games.len()
+    );
+}
+
+// one argument per command line setting
+#[allow(clippy::too_many_arguments)]
+pub async fn start_server(
+    hostname: String,
+    port: u16,
+    bots: BotRegistry,
+    ratings: Ratings,
+    storage: Arc<dyn Storage>,
+    lifecycle_config: LifecycleConfig,
+    engine_threads: usize,
+    openings_dir: Option<PathBuf>,
+) -> std::io::Result<()> {
     // Init an empty hashmap to store all the ongoing processes
-    let active = Arc::new(Mutex::new(HashMap::<Uuid, JoinSet<()>>::new()));
+    let active = Arc::new(Mutex::new(HashMap::<Uuid, JoinHandle<()>>::new()));
     let active_tasks = web::Data::new(active);
 
     let player_bot_games = web::Data::new(DashMap::<Uuid, PlayerGame>::new());
@@ -290,12 +1036,45 @@ pub async fn start_server(hostname: String, port: u16) -> std::io::Result<()> {
     handlebars
         .register_template_file("game_template", "./client/game.html")
         .unwrap(); // lmao fix
+    handlebars
+        .register_template_file("tournament_template", "./client/tournament.html")
+        .unwrap();
     let handlebars_ref = web::Data::new(handlebars);
 
     // Active Spectator connections
-    let connections: DashMap<Uuid, SharedState> = DashMap::new();
+    let connections: DashMap<Uuid, GameChannel> = DashMap::new();
     let connections_data = web::Data::new(connections);
 
+    // bot games run on this runtime rather than the workers', so they can
+    // finish after the server stopped taking requests
+    let runner_data = web::Data::new(GameRunner::new(engine_threads));
+    let ratings_data = web::Data::new(ratings);
+    restore_games(
+        &storage,
+        &bots,
+        &player_bot_games.clone().into_inner(),
+        &connections_data,
+        &ratings_data.clone().into_inner(),
+        &runner_data,
+    );
+
+    let bots_data = web::Data::new(bots);
+    let tournaments_data = web::Data::new(TournamentMap::new());
+    let sprt_data = web::Data::new(SprtMap::new());
+    let openings_data = web::Data::new(OpeningsDir(openings_dir));
+    let lifecycle = Arc::new(Lifecycle::new(
+        lifecycle_config,
+        games_data.clone().into_inner(),
+        player_bot_games.clone().into_inner(),
+        connections_data.clone().into_inner(),
+        Arc::clone(&active_tasks),
+        storage.clone(),
+        ratings_data.clone().into_inner(),
+    ));
+    lifecycle.spawn();
+    let lifecycle_data = web::Data::from(lifecycle.clone());
+    let storage_data: web::Data<dyn Storage> = web::Data::from(storage);
+
     info!("Starting server on {}:{}", hostname, port);
     let allowed_origin = format!("http://{}:{}", &hostname, &port);
     HttpServer::new(move || {
@@ -319,10 +1098,36 @@ pub async fn start_server(hostname: String, port: u16) -> std::io::Result<()> {
             .app_data(active_tasks.clone())
             .app_data(connections_data.clone())
             .app_data(player_bot_games.clone())
+            .app_data(bots_data.clone())
+            .app_data(tournaments_data.clone())
+            .app_data(sprt_data.clone())
+            .app_data(ratings_data.clone())
+            .app_data(storage_data.clone())
+            .app_data(lifecycle_data.clone())
+            .app_data(runner_data.clone())
+            .app_data(openings_data.clone())
             .route("/ws/{uuid}", web::get().to(ws_index))
+            .route("/play/{uuid}/ws", web::get().to(play_ws))
             .service(spectate_game)
             .service(new_game)
+            .service(new_bot)
+            .service(list_bots)
             .service(player_vs_bot)
+            .service(game_result)
+            .service(list_games)
+            .service(lobby_page)
+            .service(game_pgn)
+            .service(game_history)
+            .service(import_game)
+            .service(new_tournament)
+            .service(list_tournaments)
+            .service(tournament_details)
+            .service(tournament_page)
+            .service(new_sprt)
+            .service(list_sprts)
+            .service(sprt_details)
+            .service(leaderboard)
+            .service(compare_bots)
             .service(play_game_entry)
             .service(fs::Files::new("/", "./client/").index_file("index.html"))
             // .service(fs::Files::new("/img", "./client/img"))
@@ -330,7 +1135,7 @@ pub async fn start_server(hostname: String, port: u16) -> std::io::Result<()> {
                 web::scope("/img")
                     .wrap(
                         middleware::DefaultHeaders::new()
-                            .header("Cache-Control", "public, max-age=86400"),
+                            .add(("Cache-Control", "public, max-age=86400")),
                     )
                     .service(fs::Files::new("", "./client/img").use_last_modified(true)),
             )
@@ -338,5 +1143,13 @@ pub async fn start_server(hostname: String, port: u16) -> std::io::Result<()> {
     .workers(4) // Set the number of worker threads
     .bind(("0.0.0.0", port))?
     .run()
-    .await
+    .await?;
+
+    // the server stops on ctrl-c, let the bot games wrap up before exiting
+    let report = tokio::task::spawn_blocking(move || lifecycle.shutdown()).await?;
+    info!(
+        "Shut down, {} games finished and {} adjudicated",
+        report.finished, report.adjudicated
+    );
+    Ok(())
 }
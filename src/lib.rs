@@ -1,7 +1,22 @@
+pub mod alpha_beta_engine;
+pub mod bot_registry;
 pub mod browser;
 pub mod chess_engine;
 pub mod chess_game;
+pub mod clock;
+pub mod game_runner;
 pub mod http_server;
+pub mod lifecycle;
+pub mod lobby;
+pub mod lua_engine;
+pub mod openings;
+pub mod pgn;
 pub mod player_vs_bot;
+pub mod protocol;
+pub mod rating;
+pub mod sprt;
+pub mod storage;
+pub mod tournament;
 pub mod types;
+pub mod uci_engine;
 pub mod websocket;
@@ -0,0 +1,301 @@
+// Keeps the maps of live games from growing forever. Finished games are
+// archived: they stay in those maps for a while so spectators can see the
+// end, then only live on in the storage and get loaded back when someone asks
+// for them. That drops their channels, tasks and seats, the storage itself
+// still holds every game it was given, the file store in memory too. Player
+// games nobody moves in are aborted, and on shutdown running bot games get a
+// chance to finish before they're adjudicated, which leaves them unrated.
+use anyhow::Result;
+use dashmap::DashMap;
+use log::{error, info};
+use shakmaty::Color;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::bot_registry;
+use crate::chess_game::{ChessGame, GameOutcome, GameResult, Termination};
+use crate::http_server::{ActiveProcesses, GameMap};
+use crate::lobby::GameSummary;
+use crate::player_vs_bot::PlayerGame;
+use crate::protocol::ServerMessage;
+use crate::rating::Ratings;
+use crate::storage::{GameMode, Storage, StoredGame};
+use crate::websocket::{broadcast, game_channel, GameChannel};
+
+#[derive(Clone, Copy, Debug)]
+pub struct LifecycleConfig {
+    /// Player games without a move for this long are aborted
+    pub idle_timeout: Duration,
+    /// How long finished games are kept in memory
+    pub archive_after: Duration,
+    pub sweep_interval: Duration,
+    /// How long running bot games get to finish when the server stops
+    pub shutdown_grace: Duration,
+}
+
+impl Default for LifecycleConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(30 * 60),
+            archive_after: Duration::from_secs(10 * 60),
+            sweep_interval: Duration::from_secs(60),
+            shutdown_grace: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What a sweep did
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SweepReport {
+    pub abandoned: usize,
+    pub archived: usize,
+}
+
+/// What happened to the games still running at shutdown
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub finished: usize,
+    pub adjudicated: usize,
+}
+
+pub struct Lifecycle {
+    config: LifecycleConfig,
+    games: Arc<GameMap>,
+    player_games: Arc<DashMap<Uuid, PlayerGame>>,
+    connections: Arc<DashMap<Uuid, GameChannel>>,
+    processes: ActiveProcesses,
+    storage: Arc<dyn Storage>,
+    ratings: Arc<Ratings>,
+    /// When each game was first seen finished
+    finished: DashMap<Uuid, Instant>,
+    stopping: AtomicBool,
+}
+
+impl Lifecycle {
+    pub fn new(
+        config: LifecycleConfig,
+        games: Arc<GameMap>,
+        player_games: Arc<DashMap<Uuid, PlayerGame>>,
+        connections: Arc<DashMap<Uuid, GameChannel>>,
+        processes: ActiveProcesses,
+        storage: Arc<dyn Storage>,
+        ratings: Arc<Ratings>,
+    ) -> Self {
+        Self {
+            config,
+            games,
+            player_games,
+            connections,
+            processes,
+            storage,
+            ratings,
+            finished: DashMap::new(),
+            stopping: AtomicBool::new(false),
+        }
+    }
+
+    pub fn config(&self) -> LifecycleConfig {
+        self.config
+    }
+
+    /// A bot game or finished game, loaded back from the storage if it was
+    /// archived. Running player games are in the player game map
+    pub fn game(&self, id: Uuid) -> Option<Arc<RwLock<ChessGame>>> {
+        if let Some(game) = self.games.get(&id) {
+            return Some(game.clone());
+        }
+
+        let stored = match self.storage.game(id) {
+            Ok(stored) => stored?,
+            Err(e) => {
+                error!("Failed to load game {id}: {e}");
+                return None;
+            }
+        };
+        let game = match stored.restore() {
+            Ok(game) => Arc::new(RwLock::new(game)),
+            Err(e) => {
+                error!("{e}");
+                return None;
+            }
+        };
+        // archived again on a later sweep
+        self.finished.insert(id, Instant::now());
+        self.connections.entry(id).or_insert_with(game_channel);
+        Some(self.games.entry(id).or_insert(game).clone())
+    }
+
+    /// Every game there is, running ones as they stand right now
+    pub fn summaries(&self) -> Result<Vec<GameSummary>> {
+        let mut summaries = Vec::new();
+        for stored in self.storage.games()? {
+            let mut summary = GameSummary::new(&stored);
+            if let Some(game) = self.player_games.get(&stored.id) {
+                summary.update(&game.game);
+            } else if let Some(game) = self.games.get(&stored.id).map(|g| g.clone()) {
+                summary.update(&game.read().unwrap());
+            }
+            summaries.push(summary);
+        }
+        Ok(summaries)
+    }
+
+    /// Aborts idle player games and archives games that have been over for a
+    /// while
+    pub fn sweep(&self) -> SweepReport {
+        let mut report = SweepReport::default();
+
+        let idle: Vec<Uuid> = self
+            .player_games
+            .iter()
+            .filter(|g| {
+                g.game.outcome().is_none() && g.last_active.elapsed() >= self.config.idle_timeout
+            })
+            .map(|g| *g.key())
+            .collect();
+        for id in idle {
+            if let Some(mut game) = self.player_games.get_mut(&id) {
+                // the player is always white
+                let outcome = GameOutcome::loss_for(Color::White, Termination::Abandoned);
+                game.game.end(outcome);
+                game.stop_bot();
+                self.store_result(id, &game.game);
+                self.ratings
+                    .record(id, &bot_registry::player(), &game.opponent, outcome);
+                info!(
+                    "Aborted player game {id}, no move in {:?}",
+                    game.last_active.elapsed()
+                );
+                // the player's connection may still be open
+                if let Some(channel) = self.connections.get(&id) {
+                    broadcast(&channel, ServerMessage::GameOver { outcome });
+                }
+                report.abandoned += 1;
+            }
+        }
+
+        let over = self
+            .games
+            .iter()
+            .filter(|g| g.read().unwrap().outcome().is_some())
+            .map(|g| *g.key())
+            .chain(
+                self.player_games
+                    .iter()
+                    .filter(|g| g.game.outcome().is_some())
+                    .map(|g| *g.key()),
+            )
+            .collect::<Vec<_>>();
+        for id in over {
+            self.finished.entry(id).or_insert_with(Instant::now);
+        }
+
+        let expired: Vec<Uuid> = self
+            .finished
+            .iter()
+            .filter(|f| f.value().elapsed() >= self.config.archive_after)
+            .map(|f| *f.key())
+            .collect();
+        for id in expired {
+            self.archive(id);
+            report.archived += 1;
+        }
+
+        report
+    }
+
+    // Everything about a finished game is in the storage by now, drop it from
+    // the live maps
+    fn archive(&self, id: Uuid) {
+        self.finished.remove(&id);
+        self.games.remove(&id);
+        self.player_games.remove(&id);
+        self.processes.lock().unwrap().remove(&id);
+        self.connections.remove(&id);
+        log::debug!("Archived game {id}");
+    }
+
+    // Stores the moves the storage doesn't have yet along with the result.
+    // Bot games are stored move by move on the runner, which may not have
+    // caught up before the process exits
+    fn store_result(&self, id: Uuid, game: &ChessGame) {
+        let stored = self.storage.game(id).and_then(|stored| match stored {
+            Some(stored) => {
+                let known = stored.moves.len().min(game.history().len());
+                self.storage
+                    .record_moves(id, &game.history()[known..], game.outcome())
+            }
+            None => self
+                .storage
+                .save_game(&StoredGame::new(id, GameMode::BotVsBot, game)),
+        });
+        if let Err(e) = stored {
+            error!("Failed to store result of {id}: {e}");
+        }
+    }
+
+    /// Sweeps every `sweep_interval` until the server shuts down
+    pub fn spawn(self: &Arc<Self>) {
+        let lifecycle = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(lifecycle.config.sweep_interval);
+            if lifecycle.stopping.load(Ordering::SeqCst) {
+                return;
+            }
+            let report = lifecycle.sweep();
+            if report != SweepReport::default() {
+                info!("Lifecycle sweep: {report:?}");
+            }
+        });
+    }
+
+    /// Gives running bot games `shutdown_grace` to finish and adjudicates
+    /// whatever is still going as a draw. Unfinished player games are left
+    /// alone, they're picked up again after a restart
+    pub fn shutdown(&self) -> ShutdownReport {
+        self.stopping.store(true, Ordering::SeqCst);
+        let running = || -> Vec<Uuid> {
+            self.games
+                .iter()
+                .filter(|g| g.read().unwrap().outcome().is_none())
+                .map(|g| *g.key())
+                .collect()
+        };
+
+        let waiting = running();
+        if waiting.is_empty() {
+            return ShutdownReport::default();
+        }
+        info!(
+            "Waiting up to {:?} for {} running games",
+            self.config.shutdown_grace,
+            waiting.len()
+        );
+        let deadline = Instant::now() + self.config.shutdown_grace;
+        while Instant::now() < deadline && !running().is_empty() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let mut report = ShutdownReport::default();
+        for id in running() {
+            let Some(game) = self.games.get(&id).map(|g| g.clone()) else {
+                continue;
+            };
+            // engines think without the lock, a move that comes in after this is
+            // dropped by `play_turn`
+            let mut game = game.write().unwrap();
+            if game.outcome().is_some() {
+                continue;
+            }
+            let outcome = GameOutcome::new(GameResult::Draw, Termination::Adjudication);
+            game.end(outcome);
+            self.store_result(id, &game);
+            report.adjudicated += 1;
+        }
+        report.finished = waiting.len() - report.adjudicated;
+        report
+    }
+}
@@ -0,0 +1,166 @@
+// Listing games for `GET /games` and the lobby page. Every game ends up in
+// the storage, running games are overlaid with their live state since the
+// storage only catches up with bot games once they're over.
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+
+use crate::chess_game::{ChessGame, GameOutcome};
+use crate::clock::TimeControl;
+use crate::storage::{GameMode, StoredGame};
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 200;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum GameStatus {
+    Live,
+    Finished,
+}
+
+impl GameStatus {
+    fn of(outcome: Option<GameOutcome>) -> Self {
+        match outcome {
+            Some(_) => GameStatus::Finished,
+            None => GameStatus::Live,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GameSummary {
+    pub id: Uuid,
+    pub mode: GameMode,
+    pub status: GameStatus,
+    pub white: String,
+    pub black: String,
+    pub white_bot: Option<String>,
+    pub black_bot: Option<String>,
+    /// Half moves played
+    pub moves: usize,
+    pub fen: String,
+    pub outcome: Option<GameOutcome>,
+    pub time_control: TimeControl,
+    pub started_at: DateTime<Utc>,
+}
+
+impl GameSummary {
+    pub fn new(stored: &StoredGame) -> Self {
+        Self {
+            id: stored.id,
+            mode: stored.mode,
+            status: GameStatus::of(stored.outcome),
+            white: stored.white.clone(),
+            black: stored.black.clone(),
+            white_bot: stored.white_bot.clone(),
+            black_bot: stored.black_bot.clone(),
+            moves: stored.moves.len(),
+            fen: stored
+                .moves
+                .last()
+                .map_or(stored.start_fen.clone(), |m| m.fen.clone()),
+            outcome: stored.outcome,
+            time_control: stored.time_control,
+            started_at: stored.started_at,
+        }
+    }
+
+    /// Takes the position and result from the game being played
+    pub fn update(&mut self, game: &ChessGame) {
+        self.moves = game.history().len();
+        self.fen = game.fen();
+        self.outcome = game.outcome();
+        self.status = GameStatus::of(self.outcome);
+    }
+
+    /// Whether `bot` played in the game, by id or name
+    pub fn has_participant(&self, bot: &str) -> bool {
+        [&self.white_bot, &self.black_bot]
+            .into_iter()
+            .flatten()
+            .chain([&self.white, &self.black])
+            .any(|p| p.eq_ignore_ascii_case(bot))
+    }
+}
+
+// Times can be given in full or as a day, which starts at midnight UTC
+fn parse_time<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(text) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    if let Ok(time) = DateTime::parse_from_rfc3339(&text) {
+        return Ok(Some(time.with_timezone(&Utc)));
+    }
+    NaiveDate::parse_from_str(&text, "%Y-%m-%d")
+        .map(|day| Some(day.and_hms_opt(0, 0, 0).unwrap().and_utc()))
+        .map_err(|_| {
+            D::Error::custom(format!(
+                "Invalid time {text}, expected RFC 3339 or YYYY-MM-DD"
+            ))
+        })
+}
+
+/// Query of `GET /games`, everything is optional
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct GameFilter {
+    pub mode: Option<GameMode>,
+    pub status: Option<GameStatus>,
+    /// Bot id or name that played either side
+    pub bot: Option<String>,
+    /// Games started at or after this time
+    #[serde(default, deserialize_with = "parse_time")]
+    pub from: Option<DateTime<Utc>>,
+    /// Games started before this time
+    #[serde(default, deserialize_with = "parse_time")]
+    pub to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub offset: usize,
+    /// Defaults to `DEFAULT_PAGE_SIZE`, at most `MAX_PAGE_SIZE`
+    pub limit: Option<usize>,
+}
+
+impl GameFilter {
+    pub fn matches(&self, game: &GameSummary) -> bool {
+        self.mode.is_none_or(|mode| game.mode == mode)
+            && self.status.is_none_or(|status| game.status == status)
+            && self
+                .bot
+                .as_deref()
+                .is_none_or(|bot| game.has_participant(bot))
+            && self.from.is_none_or(|from| game.started_at >= from)
+            && self.to.is_none_or(|to| game.started_at < to)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct GamePage {
+    /// Games matching the filter, across all pages
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub games: Vec<GameSummary>,
+}
+
+/// The page of matching games the filter asks for, newest first
+pub fn list_games(games: Vec<GameSummary>, filter: &GameFilter) -> GamePage {
+    let mut games: Vec<GameSummary> = games.into_iter().filter(|g| filter.matches(g)).collect();
+    games.sort_by_key(|g| std::cmp::Reverse(g.started_at));
+
+    let limit = filter.limit();
+    GamePage {
+        total: games.len(),
+        offset: filter.offset,
+        limit,
+        games: games.into_iter().skip(filter.offset).take(limit).collect(),
+    }
+}
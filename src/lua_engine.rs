@@ -0,0 +1,235 @@
+// Runs user submitted bots written in lua, see `client/template.lua` for the
+// contract a script has to follow. Scripts run in a stripped down interpreter:
+// no io/os/package/debug libraries, no way to load other files, a memory cap
+// and a time limit on every call into the script.
+use anyhow::{anyhow, bail, Result};
+use log::{debug, error};
+use mlua::{ChunkMode, Function, HookTriggers, Lua, LuaOptions, RegistryKey, StdLib, Table, Value};
+use shakmaty::uci::Uci;
+use shakmaty::{CastlingMode, Move, MoveList};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::chess_engine::{ChooseMove, SearchReply, SearchRequest, StopSignal};
+use crate::chess_game::{uci_moves, ChessGame};
+use crate::clock::TimeLeft;
+use crate::protocol::Score;
+
+const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+const DEFAULT_TIME_LIMIT: Duration = Duration::from_secs(1);
+
+// base library functions that would give a script access to the filesystem
+// or let it load precompiled bytecode
+const UNSAFE_GLOBALS: [&str; 4] = ["dofile", "loadfile", "load", "require"];
+
+pub struct LuaEngine {
+    lua: Mutex<Lua>,
+    bot: RegistryKey,
+    // checked by the instruction hook, scripts still running once it goes
+    // off get killed
+    stop: Arc<Mutex<StopSignal>>,
+    time_limit: Duration,
+}
+
+impl LuaEngine {
+    /// Loads a bot script. Fails if the script doesn't compile, errors while
+    /// running, or doesn't return a table with a `chooseMove` function
+    pub fn new(script: &str) -> Result<Self> {
+        Self::with_time_limit(script, DEFAULT_TIME_LIMIT)
+    }
+
+    pub fn with_time_limit(script: &str, time_limit: Duration) -> Result<Self> {
+        let lua = sandbox()?;
+
+        let stop = StopSignal::new().with_deadline(Instant::now() + time_limit);
+        let stop = Arc::new(Mutex::new(stop));
+        let hook_stop = stop.clone();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(10_000),
+            move |_lua, _debug| {
+                if hook_stop.lock().unwrap().is_stopped() {
+                    Err(mlua::Error::RuntimeError("time limit exceeded".into()))
+                } else {
+                    Ok(())
+                }
+            },
+        );
+
+        let bot: Table = lua
+            .load(script)
+            .set_name("bot")
+            .set_mode(ChunkMode::Text)
+            .eval()
+            .map_err(|e| anyhow!("Failed to load bot: {e}"))?;
+
+        if !matches!(bot.get("chooseMove")?, Value::Function(_)) {
+            bail!("Bot has no chooseMove function");
+        }
+
+        let bot = lua.create_registry_value(bot)?;
+        Ok(Self {
+            lua: Mutex::new(lua),
+            bot,
+            stop,
+            time_limit,
+        })
+    }
+
+    // Calls `bot:chooseMove(chess_game, legal_moves)`, returns the move the
+    // script answered with along with the rest of its answer
+    fn call_bot(&self, request: &SearchRequest) -> Result<(Option<String>, SearchReply)> {
+        let lua = self.lua.lock().unwrap();
+
+        let chess_game = lua.create_table()?;
+        chess_game.set("fen", request.fen.as_str())?;
+        chess_game.set("side", request.side().to_string())?;
+        chess_game.set("start_fen", request.start_fen.as_str())?;
+        chess_game.set(
+            "history",
+            lua.create_sequence_from(request.history.clone())?,
+        )?;
+        chess_game.set("opponent", request.opponent.as_str())?;
+        chess_game.set("draw_offered", request.draw_offered)?;
+
+        // scripts can't overrun their clock any more than the time limit
+        let mut time_limit = self.time_limit;
+        if let Some(time) = &request.time {
+            chess_game.set("white_ms", time.white.as_millis() as u64)?;
+            chess_game.set("black_ms", time.black.as_millis() as u64)?;
+            chess_game.set("increment_ms", time.increment.as_millis() as u64)?;
+            chess_game.set("moves_to_go", time.moves_to_go)?;
+            chess_game.set("move_time_ms", time.move_time.map(|t| t.as_millis() as u64))?;
+            time_limit = time_limit.min(time.move_time.unwrap_or(time.own()));
+        }
+        *self.stop.lock().unwrap() = request
+            .stop
+            .clone()
+            .with_deadline(Instant::now() + time_limit);
+
+        let moves = lua.create_sequence_from(uci_moves(&request.legal_moves))?;
+
+        let bot: Table = lua.registry_value(&self.bot)?;
+        let choose_move: Function = bot.get("chooseMove")?;
+        let answer = match choose_move.call((bot, chess_game, moves))? {
+            Value::String(s) => (Some(s.to_str()?.to_string()), SearchReply::default()),
+            Value::Table(answer) => read_answer(&answer)?,
+            other => bail!(
+                "chooseMove returned {} instead of a string or table",
+                other.type_name()
+            ),
+        };
+        Ok(answer)
+    }
+}
+
+// Reads an answer like `{move = "e2e4", score = 35, depth = 3}`, see
+// `client/template.lua` for the fields
+fn read_answer(answer: &Table) -> Result<(Option<String>, SearchReply)> {
+    let score = match (answer.get("score")?, answer.get("mate")?) {
+        (_, Some(moves)) => Some(Score::Mate(moves)),
+        (Some(cp), None) => Some(Score::Cp(cp)),
+        (None, None) => None,
+    };
+    let reply = SearchReply {
+        score,
+        depth: answer.get("depth")?,
+        pv: answer
+            .get::<_, Option<Vec<String>>>("pv")?
+            .unwrap_or_default(),
+        resign: answer.get::<_, Option<bool>>("resign")?.unwrap_or(false),
+        offer_draw: answer
+            .get::<_, Option<bool>>("offer_draw")?
+            .unwrap_or(false),
+        ..SearchReply::default()
+    };
+    Ok((answer.get("move")?, reply))
+}
+
+impl ChooseMove for LuaEngine {
+    fn choose_move(&self, fen: &str, legal_moves: &MoveList) -> Option<Move> {
+        self.choose_move_timed(fen, legal_moves, None)
+    }
+
+    fn choose_move_timed(
+        &self,
+        fen: &str,
+        legal_moves: &MoveList,
+        time: Option<&TimeLeft>,
+    ) -> Option<Move> {
+        // scripts get a game without history, all there is is the position
+        let game = match ChessGame::from_fen(fen) {
+            Ok(game) => game,
+            Err(e) => {
+                error!("Lua bot was given a bad position: {e}");
+                return None;
+            }
+        };
+        let request = SearchRequest {
+            legal_moves: legal_moves.clone(),
+            time: time.copied(),
+            ..SearchRequest::new(&game)
+        };
+        self.search(&request).best_move
+    }
+
+    fn search(&self, request: &SearchRequest) -> SearchReply {
+        if request.legal_moves.is_empty() {
+            return SearchReply::default();
+        }
+
+        let (answer, mut reply) = match self.call_bot(request) {
+            Ok(answer) => answer,
+            Err(e) => {
+                error!("Lua bot failed to choose a move: {e}");
+                return SearchReply::default();
+            }
+        };
+        debug!("Lua bot answered {answer:?} {reply:?}");
+
+        // resigning or taking a draw doesn't need a move
+        let Some(answer) = answer else {
+            if !reply.resign && !reply.offer_draw {
+                error!("Lua bot returned no move");
+            }
+            return reply;
+        };
+        let Ok(uci) = Uci::from_ascii(answer.trim().as_bytes()) else {
+            error!("Lua bot returned malformed move {answer:?}");
+            return reply;
+        };
+
+        reply.best_move = request
+            .legal_moves
+            .iter()
+            .find(|m| m.to_uci(CastlingMode::Standard) == uci)
+            .cloned();
+        if reply.best_move.is_none() {
+            error!("Lua bot returned illegal move {answer}");
+        }
+        reply
+    }
+}
+
+fn sandbox() -> Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8,
+        LuaOptions::default(),
+    )?;
+    lua.set_memory_limit(MEMORY_LIMIT)?;
+
+    let globals = lua.globals();
+    for name in UNSAFE_GLOBALS {
+        globals.set(name, Value::Nil)?;
+    }
+
+    // scripts printing to stdout would end up in the server output, route
+    // them through the logger instead
+    let print = lua.create_function(|_, args: mlua::Variadic<String>| {
+        debug!("[lua] {}", args.join("\t"));
+        Ok(())
+    })?;
+    globals.set("print", print)?;
+    drop(globals);
+
+    Ok(lua)
+}
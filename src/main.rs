@@ -1,8 +1,23 @@
-use anyhow::Result;
-use clap::{Arg, Command}; // Note: It's `Command` in clap 3.x, not `App`
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgMatches, Command}; // Note: It's `Command` in clap 3.x, not `App`
+use server::bot_registry::BotRegistry;
 use server::browser::open_browser;
+use server::clock::TimeControl;
+use server::game_runner::GameRunner;
 use server::http_server;
-use tokio;
+use server::lifecycle::LifecycleConfig;
+use server::openings::{OpeningConfig, OpeningOrder, OpeningSource};
+use server::rating::{RatingConfig, Ratings};
+use server::sprt::{run_sprt, SprtConfig, SprtMatch, SprtStatus};
+use server::storage::{FileStorage, MemoryStorage, Storage};
+use server::tournament::GameLauncher;
+use server::uci_engine::UciEngineConfig;
+use server::websocket::game_channel;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use std::{fs, thread};
+use uuid::Uuid;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -28,6 +43,128 @@ async fn main() -> Result<()> {
                 .takes_value(true)
                 .default_value("8080"),
         )
+        .arg(
+            Arg::new("uci-engine")
+                .long("uci-engine")
+                .value_name("NAME=PATH")
+                .help("Makes a UCI engine available as a bot, can be repeated")
+                .takes_value(true)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::new("lua-bot")
+                .long("lua-bot")
+                .value_name("NAME=PATH")
+                .help("Registers a lua script as a bot, can be repeated")
+                .takes_value(true)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::new("random-pacing")
+                .long("random-pacing")
+                .value_name("MILLISECONDS")
+                .help("How long the random bot waits before each move")
+                .takes_value(true)
+                .default_value("250"),
+        )
+        .arg(
+            Arg::new("data-file")
+                .long("data-file")
+                .value_name("PATH")
+                .help("File games, submitted bots and ratings are stored in, kept in memory without one")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("openings-dir")
+                .long("openings-dir")
+                .value_name("PATH")
+                .help("Directory tournaments and SPRT matches started over HTTP may read opening files from")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .value_name("MINUTES")
+                .help("Player games without a move for this long are aborted")
+                .takes_value(true)
+                .default_value("30"),
+        )
+        .arg(
+            Arg::new("shutdown-grace")
+                .long("shutdown-grace")
+                .value_name("SECONDS")
+                .help("How long running bot games get to finish on shutdown before they're adjudicated")
+                .takes_value(true)
+                .default_value("30"),
+        )
+        .arg(
+            Arg::new("engine-threads")
+                .long("engine-threads")
+                .value_name("N")
+                .help("Bot moves computed at the same time across all games, defaults to the number of cores")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("ratings-file")
+                .long("ratings-file")
+                .value_name("PATH")
+                .help("Json file the bot ratings are kept in, they are lost on restart without one")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("elo-k")
+                .long("elo-k")
+                .value_name("K")
+                .help("K-factor of the Elo ratings")
+                .takes_value(true)
+                .default_value("20"),
+        )
+        .subcommand(
+            Command::new("sprt")
+                .about("Runs an SPRT match between two bots instead of starting the server")
+                .arg(
+                    Arg::new("candidate")
+                        .long("candidate")
+                        .value_name("BOT")
+                        .help("Id or name of the new version")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("baseline")
+                        .long("baseline")
+                        .value_name("BOT")
+                        .help("Id or name of the version it's tested against")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(sprt_arg("elo0", "Elo difference of H0").default_value("0"))
+                .arg(sprt_arg("elo1", "Elo difference of H1").default_value("5"))
+                .arg(sprt_arg("alpha", "False positive rate").default_value("0.05"))
+                .arg(sprt_arg("beta", "False negative rate").default_value("0.05"))
+                .arg(
+                    sprt_arg("time-control", "Like the PGN tag, e.g. 10+0.1 or 1/0.5")
+                        .default_value("-"),
+                )
+                .arg(sprt_arg("concurrency", "Pairs played at the same time").default_value("2"))
+                .arg(
+                    sprt_arg("max-pairs", "Stop without a decision after this many pairs")
+                        .default_value("1000"),
+                )
+                .arg(sprt_arg(
+                    "openings",
+                    "EPD, PGN or Polyglot .bin file, random openings without one",
+                ))
+                .arg(sprt_arg(
+                    "opening-plies",
+                    "Half moves played from a book, PGN or at random",
+                ))
+                .arg(
+                    sprt_arg("opening-order", "Whether EPD and PGN openings are sampled")
+                        .possible_values(["sequential", "random"])
+                        .default_value("sequential"),
+                ),
+        )
         .get_matches();
 
     init_logger();
@@ -39,7 +176,86 @@ async fn main() -> Result<()> {
         .parse::<u16>()
         .expect("Invalid port number");
 
-    let server_future = http_server::start_server(hostname.clone(), port.clone());
+    let random_pacing = matches
+        .value_of("random-pacing")
+        .unwrap()
+        .parse::<u64>()
+        .expect("Invalid random bot pacing");
+
+    let storage: Arc<dyn Storage> = match matches.value_of("data-file") {
+        Some(path) => Arc::new(FileStorage::open(path)?),
+        None => Arc::new(MemoryStorage::new()),
+    };
+
+    let bots = BotRegistry::with_random_pacing(Duration::from_millis(random_pacing));
+    for bot in storage.bots()? {
+        bots.restore(&bot);
+    }
+    for spec in matches.values_of("uci-engine").into_iter().flatten() {
+        let Some((name, path)) = spec.split_once('=') else {
+            anyhow::bail!("Invalid --uci-engine {spec}, expected NAME=PATH");
+        };
+        bots.register_uci(name, UciEngineConfig::new(path))?;
+    }
+    for spec in matches.values_of("lua-bot").into_iter().flatten() {
+        let Some((name, path)) = spec.split_once('=') else {
+            anyhow::bail!("Invalid --lua-bot {spec}, expected NAME=PATH");
+        };
+        bots.register_lua(name, fs::read_to_string(path)?)?;
+    }
+
+    let rating_config = RatingConfig {
+        k_factor: matches
+            .value_of("elo-k")
+            .unwrap()
+            .parse()
+            .expect("Invalid Elo K-factor"),
+        ..RatingConfig::default()
+    };
+    let ratings = match matches.value_of("ratings-file") {
+        Some(path) => Ratings::load(path, rating_config)?,
+        None => Ratings::with_storage(rating_config, storage.clone())?,
+    };
+
+    let engine_threads = match matches.value_of("engine-threads") {
+        Some(threads) => threads
+            .parse::<usize>()
+            .expect("Invalid engine thread count"),
+        None => thread::available_parallelism().map_or(4, |n| n.get()),
+    };
+
+    if let Some(args) = matches.subcommand_matches("sprt") {
+        return sprt(args, bots, ratings, engine_threads).await;
+    }
+
+    let lifecycle = LifecycleConfig {
+        idle_timeout: Duration::from_secs(
+            60 * matches
+                .value_of("idle-timeout")
+                .unwrap()
+                .parse::<u64>()
+                .expect("Invalid idle timeout"),
+        ),
+        shutdown_grace: Duration::from_secs(
+            matches
+                .value_of("shutdown-grace")
+                .unwrap()
+                .parse::<u64>()
+                .expect("Invalid shutdown grace period"),
+        ),
+        ..LifecycleConfig::default()
+    };
+
+    let server_future = http_server::start_server(
+        hostname.clone(),
+        port,
+        bots,
+        ratings,
+        storage,
+        lifecycle,
+        engine_threads,
+        matches.value_of("openings-dir").map(PathBuf::from),
+    );
     let open_browser_future = open_browser(format!("http://{}:{}", hostname, port));
 
     let (server_result, _browser_result) = tokio::join!(server_future, open_browser_future);
@@ -53,6 +269,119 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+fn sprt_arg<'a>(name: &'a str, help: &'a str) -> Arg<'a> {
+    Arg::new(name).long(name).help(help).takes_value(true)
+}
+
+// Accepts a bot id or name
+fn find_bot(bots: &BotRegistry, key: &str) -> Result<String> {
+    bots.list()
+        .into_iter()
+        .find(|b| b.id == key || b.name == key)
+        .map(|b| b.id)
+        .ok_or(anyhow!("Unknown bot {key}"))
+}
+
+// Plays the match without the server, printing the stats after every pair
+async fn sprt(
+    args: &ArgMatches,
+    bots: BotRegistry,
+    ratings: Ratings,
+    engine_threads: usize,
+) -> Result<()> {
+    let number = |name: &str| -> Result<f64> {
+        let value = args.value_of(name).unwrap();
+        value
+            .parse()
+            .map_err(|_| anyhow!("Invalid --{name} {value}"))
+    };
+
+    let mut config = SprtConfig::new(
+        find_bot(&bots, args.value_of("candidate").unwrap())?,
+        find_bot(&bots, args.value_of("baseline").unwrap())?,
+    );
+    config.elo0 = number("elo0")?;
+    config.elo1 = number("elo1")?;
+    config.alpha = number("alpha")?;
+    config.beta = number("beta")?;
+    config.concurrency = number("concurrency")? as usize;
+    config.max_pairs = number("max-pairs")? as u32;
+    config.time_control = Some(TimeControl::from_pgn_tag(
+        args.value_of("time-control").unwrap(),
+    )?);
+    let plies = match args.value_of("opening-plies") {
+        Some(_) => Some(number("opening-plies")? as u32),
+        None => None,
+    };
+    config.openings = match args.value_of("openings") {
+        Some(path) => OpeningConfig {
+            source: OpeningSource::from_path(path, plies)?,
+            order: match args.value_of("opening-order") {
+                Some("random") => OpeningOrder::Random,
+                _ => OpeningOrder::Sequential,
+            },
+        },
+        None => OpeningConfig {
+            source: OpeningSource::Random {
+                plies: plies.unwrap_or(8),
+            },
+            order: OpeningOrder::Random,
+        },
+    };
+
+    let sprt = Arc::new(RwLock::new(SprtMatch::new(Uuid::new_v4(), config, &bots)?));
+    // nobody is watching the games
+    let launcher: GameLauncher = Arc::new(|_, _| game_channel());
+    let runner = GameRunner::new(engine_threads);
+    let task = runner.spawn(run_sprt(
+        sprt.clone(),
+        Arc::new(bots),
+        Arc::new(ratings),
+        launcher,
+        runner.clone(),
+    ));
+
+    let mut reported = 0;
+    loop {
+        let finished = task.is_finished();
+        {
+            let s = sprt.read().unwrap();
+            if s.stats.pairs != reported || finished {
+                reported = s.stats.pairs;
+                let stats = &s.stats;
+                let elo = match (stats.elo, stats.elo_error) {
+                    (Some(elo), Some(error)) => format!("{elo:+.1} +/- {error:.1}"),
+                    _ => "-".to_string(),
+                };
+                println!(
+                    "pairs {} W/D/L {}/{}/{} pentanomial {:?} elo {elo} LLR {:.2} ({:.2}, {:.2})",
+                    stats.pairs,
+                    stats.wins,
+                    stats.draws,
+                    stats.losses,
+                    stats.pentanomial,
+                    stats.llr,
+                    stats.lower_bound,
+                    stats.upper_bound,
+                );
+            }
+            if finished {
+                match s.status {
+                    SprtStatus::AcceptedH1 => {
+                        println!("H1 accepted, {} is stronger", s.candidate.name)
+                    }
+                    SprtStatus::AcceptedH0 => {
+                        println!("H0 accepted, {} is not stronger", s.candidate.name)
+                    }
+                    _ => println!("No decision after {} pairs", s.stats.pairs),
+                }
+                return Ok(());
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
 fn init_logger() {
     env_logger::builder()
         .filter_level(log::LevelFilter::Debug)
@@ -0,0 +1,380 @@
+// Opening suites, so bot games don't all start from the initial position and
+// deterministic bots don't play the same game over and over. Positions come
+// from EPD or PGN files, from walking a Polyglot book or from random moves.
+//
+// Polyglot books are files of 16 byte big-endian entries: the Zobrist key of
+// the position, the move, its weight and 4 bytes of learning data we ignore.
+// Entries are sorted by key. shakmaty's Zobrist64 uses the Polyglot keys, see
+// http://hgm.nubati.net/book_format.html for the format.
+use anyhow::{anyhow, bail, Result};
+use rand::distributions::WeightedIndex;
+use rand::prelude::Distribution;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use shakmaty::zobrist::{Zobrist64, ZobristHash};
+use shakmaty::{Chess, EnPassantMode, Move, Position, Role};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::chess_game::ChessGame;
+use crate::pgn::{from_pgn, split_games};
+
+const BOOK_ENTRY_SIZE: usize = 16;
+
+fn default_plies() -> u32 {
+    8
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum OpeningSource {
+    /// FENs or EPDs given inline
+    Positions { positions: Vec<String> },
+    /// A file on the server with one EPD or FEN per line
+    Epd { path: String },
+    /// A PGN file on the server, every game gives the position after its
+    /// moves, or after `plies` half moves if it's set
+    Pgn { path: String, plies: Option<u32> },
+    /// A Polyglot book on the server, followed for up to `plies` half moves
+    /// picking moves by their weight
+    Polyglot {
+        path: String,
+        #[serde(default = "default_plies")]
+        plies: u32,
+    },
+    /// `plies` random half moves from the initial position
+    Random {
+        #[serde(default = "default_plies")]
+        plies: u32,
+    },
+}
+
+impl OpeningSource {
+    /// Picks the kind of file from its extension
+    pub fn from_path(path: &str, plies: Option<u32>) -> Result<Self> {
+        let path = path.to_string();
+        let extension = Path::new(&path)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        Ok(match extension.as_deref() {
+            Some("epd") | Some("fen") | Some("txt") => OpeningSource::Epd { path },
+            Some("pgn") => OpeningSource::Pgn { path, plies },
+            Some("bin") => OpeningSource::Polyglot {
+                path,
+                plies: plies.unwrap_or(default_plies()),
+            },
+            _ => bail!("Unknown opening file {path}, expected .epd, .pgn or .bin"),
+        })
+    }
+
+    fn path_mut(&mut self) -> Option<&mut String> {
+        match self {
+            OpeningSource::Epd { path }
+            | OpeningSource::Pgn { path, .. }
+            | OpeningSource::Polyglot { path, .. } => Some(path),
+            OpeningSource::Positions { .. } | OpeningSource::Random { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum OpeningOrder {
+    /// Positions are used in the order of the suite, starting over at the end
+    #[default]
+    Sequential,
+    /// Every opening is sampled from the suite
+    Random,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct OpeningConfig {
+    #[serde(flatten)]
+    pub source: OpeningSource,
+    /// Books and random openings are always random
+    #[serde(default)]
+    pub order: OpeningOrder,
+}
+
+impl OpeningConfig {
+    /// Same suite with its file looked up in `dir`, for suites sent over
+    /// HTTP. Files outside of `dir` are refused, as are all files without a
+    /// `dir`, so requests can't read whatever is on the server
+    pub fn within(mut self, dir: Option<&Path>) -> Result<Self> {
+        let Some(path) = self.source.path_mut() else {
+            return Ok(self);
+        };
+        let Some(dir) = dir else {
+            bail!("Opening files aren't enabled on this server, send the positions instead");
+        };
+        let dir = dir.canonicalize()?;
+        let file: PathBuf = dir
+            .join(&*path)
+            .canonicalize()
+            .map_err(|_| anyhow!("No opening file {path}"))?;
+        if !file.starts_with(&dir) {
+            bail!("Opening files have to be in the openings directory");
+        }
+        *path = file.to_string_lossy().into_owned();
+        Ok(self)
+    }
+}
+
+impl Default for OpeningConfig {
+    fn default() -> Self {
+        Self {
+            source: OpeningSource::Random {
+                plies: default_plies(),
+            },
+            order: OpeningOrder::Random,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BookEntry {
+    key: u64,
+    chess_move: u16,
+    weight: u16,
+}
+
+/// A Polyglot opening book
+#[derive(Clone, Debug)]
+pub struct PolyglotBook {
+    // sorted by key
+    entries: Vec<BookEntry>,
+}
+
+impl PolyglotBook {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes =
+            fs::read(path).map_err(|e| anyhow!("Can't read book {}: {e}", path.display()))?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if !bytes.len().is_multiple_of(BOOK_ENTRY_SIZE) {
+            bail!("Polyglot books are made of {BOOK_ENTRY_SIZE} byte entries");
+        }
+        let mut entries: Vec<_> = bytes
+            .chunks_exact(BOOK_ENTRY_SIZE)
+            .map(|entry| BookEntry {
+                key: u64::from_be_bytes(entry[0..8].try_into().unwrap()),
+                chess_move: u16::from_be_bytes([entry[8], entry[9]]),
+                weight: u16::from_be_bytes([entry[10], entry[11]]),
+            })
+            .collect();
+        // books should already be sorted, but lookups depend on it
+        entries.sort_by_key(|e| e.key);
+        Ok(Self { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Legal book moves in the position with their weights
+    pub fn moves(&self, position: &Chess) -> Vec<(Move, u16)> {
+        let key: Zobrist64 = position.zobrist_hash(EnPassantMode::Legal);
+        let start = self.entries.partition_point(|e| e.key < key.0);
+        let legal = position.legal_moves();
+
+        self.entries[start..]
+            .iter()
+            .take_while(|e| e.key == key.0)
+            .filter_map(|e| {
+                legal
+                    .iter()
+                    .find(|m| polyglot_move(m) == e.chess_move)
+                    .map(|m| (m.clone(), e.weight))
+            })
+            .collect()
+    }
+
+    /// Follows the book from the initial position for up to `plies` half
+    /// moves, returns the FEN of where it ended
+    pub fn walk(&self, plies: u32) -> String {
+        let mut rng = rand::thread_rng();
+        let mut game = ChessGame::new();
+        for _ in 0..plies {
+            let moves = self.moves(&game.game);
+            if moves.is_empty() {
+                break;
+            }
+            // entries can all have weight 0, pick uniformly then
+            let index = match WeightedIndex::new(moves.iter().map(|(_, w)| *w)) {
+                Ok(weights) => weights.sample(&mut rng),
+                Err(_) => rng.gen_range(0..moves.len()),
+            };
+            game.make_move(&moves[index].0);
+        }
+        game.fen()
+    }
+}
+
+// Polyglot encodes moves as to square in bits 0-5, from square in bits 6-11
+// and the promotion piece in bits 12-14. Castling is written as the king
+// capturing its own rook
+fn polyglot_move(m: &Move) -> u16 {
+    let (from, to) = match *m {
+        Move::Castle { king, rook } => (king, rook),
+        _ => (m.from().expect("no drops in standard chess"), m.to()),
+    };
+    let promotion = match m.promotion() {
+        Some(Role::Knight) => 1,
+        Some(Role::Bishop) => 2,
+        Some(Role::Rook) => 3,
+        Some(Role::Queen) => 4,
+        _ => 0,
+    };
+    (u32::from(to) | u32::from(from) << 6 | promotion << 12) as u16
+}
+
+// EPD lines are a FEN without the move counters followed by operations like
+// `bm e4;`, full FENs are accepted too
+fn parse_epd_line(line: &str) -> Result<String> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    if fields.len() < 4 {
+        bail!("expected at least 4 fields, got {}", fields.len());
+    }
+    let counters = match fields.get(4..6) {
+        Some([halfmoves, fullmoves])
+            if halfmoves.parse::<u32>().is_ok() && fullmoves.parse::<u32>().is_ok() =>
+        {
+            format!("{halfmoves} {fullmoves}")
+        }
+        _ => "0 1".to_string(),
+    };
+    let fen = format!("{} {counters}", fields[..4].join(" "));
+    // the error would repeat the line
+    ChessGame::from_fen(&fen).map_err(|_| anyhow!("not a legal position"))?;
+    Ok(fen)
+}
+
+/// Reads the positions of an EPD file, blank lines and lines starting with
+/// `#` are skipped. Errors give the line number, not the line
+pub fn parse_epd(text: &str) -> Result<Vec<String>> {
+    text.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'))
+        .map(|(i, l)| parse_epd_line(l).map_err(|e| anyhow!("Invalid EPD on line {}: {e}", i + 1)))
+        .collect()
+}
+
+/// The position after every game in a PGN file, or after `plies` half moves
+/// of it
+pub fn parse_pgn_openings(text: &str, plies: Option<u32>) -> Result<Vec<String>> {
+    split_games(text)
+        .into_iter()
+        .map(|pgn| {
+            let game = from_pgn(pgn)?;
+            let history = game.history();
+            let played = plies.map_or(history.len(), |p| history.len().min(p as usize));
+            Ok(match played {
+                0 => game.start_fen(),
+                n => history[n - 1].fen.clone(),
+            })
+        })
+        .collect()
+}
+
+// Plays random moves from the start position, avoiding openings that end
+// the game
+fn random_opening(plies: u32) -> String {
+    let mut rng = rand::thread_rng();
+    loop {
+        let mut game = ChessGame::new();
+        for _ in 0..plies {
+            let moves = game.get_legal_moves();
+            if moves.is_empty() {
+                break;
+            }
+            game.make_move(&moves[rng.gen_range(0..moves.len())]);
+        }
+        if !game.game_over() {
+            return game.fen();
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Suite {
+    Positions(Vec<String>),
+    Book { book: PolyglotBook, plies: u32 },
+    Random { plies: u32 },
+}
+
+/// Loaded opening suite, hands out starting positions as FENs
+#[derive(Clone, Debug)]
+pub struct OpeningSuite {
+    suite: Suite,
+    order: OpeningOrder,
+}
+
+impl OpeningSuite {
+    /// Reads and checks the files the config points to
+    pub fn load(config: &OpeningConfig) -> Result<Self> {
+        let read = |path: &str| {
+            fs::read_to_string(path).map_err(|e| anyhow!("Can't read openings {path}: {e}"))
+        };
+        let suite = match &config.source {
+            OpeningSource::Positions { positions } => Suite::Positions(
+                positions
+                    .iter()
+                    .enumerate()
+                    .map(|(i, p)| {
+                        parse_epd_line(p)
+                            .map_err(|e| anyhow!("Invalid opening position {}: {e}", i + 1))
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            OpeningSource::Epd { path } => Suite::Positions(parse_epd(&read(path)?)?),
+            OpeningSource::Pgn { path, plies } => {
+                Suite::Positions(parse_pgn_openings(&read(path)?, *plies)?)
+            }
+            OpeningSource::Polyglot { path, plies } => Suite::Book {
+                book: PolyglotBook::load(path)?,
+                plies: *plies,
+            },
+            OpeningSource::Random { plies } => Suite::Random { plies: *plies },
+        };
+        if let Suite::Positions(positions) = &suite {
+            if positions.is_empty() {
+                bail!("The opening suite has no positions");
+            }
+        }
+
+        Ok(Self {
+            suite,
+            order: config.order,
+        })
+    }
+
+    /// Number of positions in the suite, `None` for books and random openings
+    pub fn position_count(&self) -> Option<usize> {
+        match &self.suite {
+            Suite::Positions(positions) => Some(positions.len()),
+            _ => None,
+        }
+    }
+
+    /// Starting position for the `index`-th opening of a match or tournament
+    pub fn opening(&self, index: usize) -> String {
+        match &self.suite {
+            Suite::Positions(positions) => match self.order {
+                OpeningOrder::Sequential => positions[index % positions.len()].clone(),
+                OpeningOrder::Random => {
+                    positions[rand::thread_rng().gen_range(0..positions.len())].clone()
+                }
+            },
+            Suite::Book { book, plies } => book.walk(*plies),
+            Suite::Random { plies } => random_opening(*plies),
+        }
+    }
+}
@@ -0,0 +1,391 @@
+// PGN (Portable Game Notation) import and export, see
+// https://www.chessclub.com/help/PGN-spec for the format
+use chrono::{NaiveDate, TimeZone, Utc};
+use shakmaty::san::San;
+use shakmaty::{Chess, Color, Position};
+use std::fmt;
+
+use crate::chess_game::{ChessGame, GameOutcome, GameResult, Termination};
+
+// the spec asks for movetext lines of at most 80 characters
+const MAX_LINE_LENGTH: usize = 79;
+
+/// Renders the game, finished or not, as a PGN string
+pub fn to_pgn(game: &ChessGame) -> String {
+    let outcome = game.outcome();
+    let result = outcome.map_or("*", |o| o.result.as_str());
+
+    let mut tags = vec![
+        ("Event", "Rustiator game".to_string()),
+        ("Site", "Rustiator".to_string()),
+        ("Date", game.started_at.format("%Y.%m.%d").to_string()),
+        ("Round", "-".to_string()),
+        ("White", game.white.clone()),
+        ("Black", game.black.clone()),
+        ("Result", result.to_string()),
+        ("UTCDate", game.started_at.format("%Y.%m.%d").to_string()),
+        ("UTCTime", game.started_at.format("%H:%M:%S").to_string()),
+        ("TimeControl", game.clock().control().pgn_tag()),
+    ];
+    if let Some(termination) = termination_tag(outcome) {
+        tags.push(("Termination", termination.to_string()));
+    }
+
+    let start = game.start_position();
+    if *start != Chess::default() {
+        tags.push(("SetUp", "1".to_string()));
+        tags.push(("FEN", game.start_fen()));
+    }
+
+    let mut pgn = String::new();
+    for (name, value) in tags {
+        pgn.push_str(&format!("[{name} \"{}\"]\n", escape(&value)));
+    }
+    pgn.push('\n');
+
+    let mut tokens = Vec::new();
+    let mut turn = start.turn();
+    let mut move_number = start.fullmoves().get();
+    for (i, m) in game.history().iter().enumerate() {
+        // black moves get their number repeated when something other than
+        // white's move comes before them
+        if turn == Color::White {
+            tokens.push(format!("{move_number}."));
+        } else if i == 0 || m.clock_ms.is_some() {
+            tokens.push(format!("{move_number}..."));
+        }
+        tokens.push(m.san.clone());
+        if let Some(ms) = m.clock_ms {
+            tokens.push(format!("{{[%clk {}]}}", clock_comment(ms)));
+        }
+
+        if turn == Color::Black {
+            move_number += 1;
+        }
+        turn = !turn;
+    }
+    if let Some(outcome) = outcome {
+        tokens.push(format!("{{{}}}", describe(outcome)));
+    }
+    tokens.push(result.to_string());
+
+    pgn.push_str(&wrap(&tokens));
+    pgn.push('\n');
+    pgn
+}
+
+// h:mm:ss as used by the %clk command
+fn clock_comment(ms: u64) -> String {
+    let secs = ms / 1000;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn wrap(tokens: &[String]) -> String {
+    let mut out = String::new();
+    let mut line_length = 0;
+    for token in tokens {
+        if line_length > 0 && line_length + 1 + token.len() > MAX_LINE_LENGTH {
+            out.push('\n');
+            line_length = 0;
+        } else if line_length > 0 {
+            out.push(' ');
+            line_length += 1;
+        }
+        out.push_str(token);
+        line_length += token.len();
+    }
+    out
+}
+
+// Values defined by the PGN spec for the Termination tag, left out when we
+// don't know
+fn termination_tag(outcome: Option<GameOutcome>) -> Option<&'static str> {
+    let Some(outcome) = outcome else {
+        return Some("unterminated");
+    };
+
+    match outcome.termination {
+        Termination::TimeForfeit => Some("time forfeit"),
+        Termination::Adjudication => Some("adjudication"),
+        Termination::EngineCrash | Termination::Abandoned => Some("abandoned"),
+        Termination::IllegalMove => Some("rules infraction"),
+        Termination::Unknown => None,
+        _ => Some("normal"),
+    }
+}
+
+// Human readable ending, added as a comment after the last move
+fn describe(outcome: GameOutcome) -> String {
+    let winner = match outcome.result.winner() {
+        Some(Color::White) => "White wins",
+        Some(Color::Black) => "Black wins",
+        None => "Draw",
+    };
+    let reason = match outcome.termination {
+        Termination::Checkmate => "checkmate",
+        Termination::Stalemate => "stalemate",
+        Termination::InsufficientMaterial => "insufficient material",
+        Termination::FiftyMoveRule => "the 50-move rule",
+        Termination::SeventyFiveMoveRule => "the 75-move rule",
+        Termination::ThreefoldRepetition => "threefold repetition",
+        Termination::FivefoldRepetition => "fivefold repetition",
+        Termination::Resignation => "resignation",
+        Termination::Agreement => "agreement",
+        Termination::TimeForfeit => "time forfeit",
+        Termination::Adjudication => "adjudication",
+        Termination::EngineCrash => "engine failure",
+        Termination::IllegalMove => "illegal move",
+        Termination::Abandoned => "abandonment",
+        Termination::Unknown => return winner.to_string(),
+    };
+    format!("{winner} by {reason}")
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PgnError {
+    /// The text isn't PGN we understand, e.g. an unterminated tag
+    Syntax(String),
+    /// The SetUp/FEN tags don't describe a legal position
+    InvalidSetup(String),
+    /// A move in the movetext can't be played, `ply` counts from 1
+    IllegalMove {
+        ply: usize,
+        san: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for PgnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PgnError::Syntax(msg) => write!(f, "invalid PGN: {msg}"),
+            PgnError::InvalidSetup(msg) => write!(f, "invalid starting position: {msg}"),
+            PgnError::IllegalMove { ply, san, reason } => {
+                write!(f, "move {san} at ply {ply} can't be played: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PgnError {}
+
+/// Parses the first game in `pgn`, checking every move of the main line.
+/// Comments, NAGs and variations are skipped.
+pub fn from_pgn(pgn: &str) -> Result<ChessGame, PgnError> {
+    let (tags, movetext) = parse_tags(pgn)?;
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    };
+
+    let mut game = match tag("FEN") {
+        Some(fen) => ChessGame::from_fen(fen).map_err(|e| PgnError::InvalidSetup(e.to_string()))?,
+        None => ChessGame::new(),
+    };
+    if let Some(white) = tag("White") {
+        game.white = white.to_string();
+    }
+    if let Some(black) = tag("Black") {
+        game.black = black.to_string();
+    }
+    let date = tag("UTCDate").or(tag("Date")).unwrap_or_default();
+    if let Ok(date) = NaiveDate::parse_from_str(date, "%Y.%m.%d") {
+        game.started_at = Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap());
+    }
+    // the game is replayed as recorded, not cut short by draws the players
+    // didn't claim
+    game.claim_draws = false;
+
+    let mut result = tag("Result").and_then(parse_result);
+    let tokens = tokenize(movetext)?;
+    for token in tokens {
+        if token == "*" {
+            break;
+        } else if let Some(r) = parse_result(&token) {
+            result = Some(r);
+            break;
+        }
+
+        let ply = game.history().len() + 1;
+        let illegal = |reason: String| PgnError::IllegalMove {
+            ply,
+            san: token.clone(),
+            reason,
+        };
+
+        if let Some(outcome) = game.outcome() {
+            return Err(illegal(format!("game is already over ({outcome:?})")));
+        }
+        // castling is often written with zeros
+        let san: San = match token.trim_end_matches(['+', '#', '!', '?']) {
+            "0-0" => "O-O",
+            "0-0-0" => "O-O-O",
+            san => san,
+        }
+        .parse()
+        .map_err(|e| illegal(format!("{e}")))?;
+        let m = san
+            .to_move(&game.game)
+            .map_err(|e| illegal(format!("{e}")))?;
+        game.make_move(&m);
+    }
+
+    // the rules only know about mates and forced draws, anything else
+    // recorded in the result tag was decided some other way
+    if let Some(result) = result {
+        let termination = match tag("Termination").map(str::to_lowercase).as_deref() {
+            // decided over the board, by the players
+            Some("normal") if result == GameResult::Draw => Termination::Agreement,
+            Some("normal") => Termination::Resignation,
+            Some("time forfeit") => Termination::TimeForfeit,
+            Some("abandoned" | "death") => Termination::Abandoned,
+            Some("rules infraction") => Termination::IllegalMove,
+            Some("adjudication" | "emergency") => Termination::Adjudication,
+            _ => Termination::Unknown,
+        };
+        game.end(GameOutcome::new(result, termination));
+    }
+
+    Ok(game)
+}
+
+/// Splits a file with several games into one string per game, a game ends
+/// where the tags of the next one start
+pub fn split_games(pgn: &str) -> Vec<&str> {
+    let mut games = Vec::new();
+    let mut start = 0;
+    let mut in_movetext = false;
+    let mut offset = 0;
+
+    for line in pgn.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') && in_movetext {
+            games.push(&pgn[start..offset]);
+            start = offset;
+            in_movetext = false;
+        } else if !trimmed.is_empty() && !trimmed.starts_with('[') {
+            in_movetext = true;
+        }
+        offset += line.len();
+    }
+    if !pgn[start..].trim().is_empty() {
+        games.push(&pgn[start..]);
+    }
+    games
+}
+
+fn parse_result(token: &str) -> Option<GameResult> {
+    match token {
+        "1-0" => Some(GameResult::WhiteWins),
+        "0-1" => Some(GameResult::BlackWins),
+        "1/2-1/2" => Some(GameResult::Draw),
+        _ => None,
+    }
+}
+
+type Tags = Vec<(String, String)>;
+
+// Splits the tag pairs from the movetext
+fn parse_tags(pgn: &str) -> Result<(Tags, &str), PgnError> {
+    let mut tags = Vec::new();
+    let mut rest = pgn.trim_start_matches('\u{feff}');
+
+    loop {
+        rest = rest.trim_start();
+        if rest.starts_with('%') || rest.starts_with(';') {
+            rest = rest.split_once('\n').map_or("", |(_, r)| r);
+            continue;
+        }
+        let Some(tag) = rest.strip_prefix('[') else {
+            break;
+        };
+
+        let (name, value) = tag
+            .split_once('"')
+            .ok_or(PgnError::Syntax("tag without a value".to_string()))?;
+        let mut unescaped = String::new();
+        let mut chars = value.char_indices();
+        let end = loop {
+            match chars.next() {
+                Some((_, '\\')) => unescaped.extend(chars.next().map(|(_, c)| c)),
+                Some((i, '"')) => break i,
+                Some((_, c)) => unescaped.push(c),
+                None => return Err(PgnError::Syntax("unterminated tag value".to_string())),
+            }
+        };
+        let after = value[end + 1..].trim_start();
+        rest = after.strip_prefix(']').ok_or(PgnError::Syntax(format!(
+            "unterminated tag {}",
+            name.trim()
+        )))?;
+
+        tags.push((name.trim().to_string(), unescaped));
+    }
+
+    Ok((tags, rest))
+}
+
+// Returns the moves and result of the main line, with move numbers,
+// comments, NAGs and variations removed
+fn tokenize(movetext: &str) -> Result<Vec<String>, PgnError> {
+    let mut tokens = Vec::new();
+    let mut variation_depth = 0;
+    let mut chars = movetext.chars().peekable();
+    let mut line_start = true;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                line_start = true;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '%' if line_start => {
+                chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+            }
+            ';' => {
+                chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+            }
+            '{' => {
+                if !chars.by_ref().any(|c| c == '}') {
+                    return Err(PgnError::Syntax("unterminated comment".to_string()));
+                }
+            }
+            '(' => variation_depth += 1,
+            ')' => {
+                if variation_depth == 0 {
+                    return Err(PgnError::Syntax("unbalanced variation".to_string()));
+                }
+                variation_depth -= 1;
+            }
+            '$' => while chars.next_if(|c| c.is_ascii_digit()).is_some() {},
+            _ => {
+                let mut token = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"{}();".contains(*c)) {
+                    token.push(c);
+                }
+
+                // move numbers are either their own token ("12.", "12...")
+                // or glued to the move ("12.e4")
+                let token = match token.trim_start_matches(|c: char| c.is_ascii_digit()) {
+                    rest if rest.starts_with('.') => rest.trim_start_matches('.'),
+                    _ => token.as_str(),
+                };
+                if variation_depth == 0 && !token.is_empty() {
+                    tokens.push(token.to_string());
+                }
+            }
+        }
+        line_start = false;
+    }
+
+    if variation_depth != 0 {
+        return Err(PgnError::Syntax("unterminated variation".to_string()));
+    }
+    Ok(tokens)
+}
@@ -1,69 +1,367 @@
-use anyhow::Result;
-use log::{error, info};
-use shakmaty::Move;
+use anyhow::{anyhow, bail, Result};
+use dashmap::mapref::one::RefMut;
+use dashmap::DashMap;
+use log::error;
+use shakmaty::uci::Uci;
+use shakmaty::{Color, Move, Position};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
-use crate::{chess_engine::ChooseMove, chess_game::ChessGame};
+use crate::bot_registry::{self, BotInfo};
+use crate::chess_game::{GameOutcome, GameResult, Termination};
+use crate::protocol::ServerMessage;
+use crate::rating::Ratings;
+use crate::storage::{GameMode, Storage, StoredGame};
+use crate::websocket::{broadcast, GameChannel};
+use crate::{
+    chess_engine::{Engine, SearchReply, SearchRequest, SharedEngine, StopSignal},
+    chess_game::ChessGame,
+};
 
 pub struct PlayerGame {
-    bot: Box<dyn ChooseMove + Send + Sync>,
+    bot: SharedEngine,
+    /// The bot being played against
+    pub opponent: BotInfo,
     pub game: ChessGame,
+    /// When the player last made a move, games left alone for too long are
+    /// aborted
+    pub last_active: Instant,
+    /// Stops the bot if the game ends while it thinks
+    thinking: Option<StopSignal>,
+    /// Handed to the player when the game starts, only they get to play.
+    /// Spectators know the game id
+    pub seat_token: Uuid,
 }
 
 impl PlayerGame {
-    pub fn new<C: 'static + ChooseMove + Send + Sync>(bot: C) -> Self {
+    /// The player always has the white pieces. If the game starts with black
+    /// to move the bot has the first move, see `GameRunner::bot_reply`
+    pub fn new(bot: Engine, opponent: BotInfo, game: ChessGame) -> Self {
         Self {
-            bot: Box::new(bot),
-            game: ChessGame::new(),
+            bot: Arc::from(bot),
+            opponent,
+            game,
+            last_active: Instant::now(),
+            thinking: None,
+            seat_token: Uuid::new_v4(),
         }
     }
 
-    /// Takes in player move and then playes the bot move that responds to this. Also returns the move
-    pub fn play_move(&mut self, player_move: Move) -> Result<Option<Move>> {
-        self.game.make_move(&player_move);
+    /// The game as it is stored, with everything needed to pick it up again
+    pub fn stored(&self, id: Uuid) -> StoredGame {
+        StoredGame {
+            black_bot: Some(self.opponent.id.clone()),
+            seat_token: Some(self.seat_token),
+            ..StoredGame::new(id, GameMode::PlayerVsBot, &self.game)
+        }
+    }
 
-        let legal_moves = &self.game.get_legal_moves();
+    /// Plays just the player's move, the bot's reply is up to the caller
+    pub fn player_move(&mut self, player_move: &Move) -> Result<()> {
+        if let Some(outcome) = self.game.outcome() {
+            bail!("Game is already over: {outcome:?}");
+        }
+        if self.game.game.turn() != Color::White {
+            bail!("Wait for the bot to move");
+        }
 
-        // legal moves should be a stronger condition
-        // than game over, there are scenario (like the 50 move rule)
-        // where the game is over but there are still legal moves
-        if self.game.game_over() {
-            return Ok(None);
+        self.last_active = Instant::now();
+        self.game.make_move(player_move);
+        Ok(())
+    }
+
+    /// Takes back the player's last move and whatever the bot replied,
+    /// returns the ply the game goes on from
+    pub fn take_back(&mut self) -> Result<usize> {
+        if let Some(outcome) = self.game.outcome() {
+            bail!("Game is already over: {outcome:?}");
+        }
+        if self.game.game.turn() != Color::White {
+            bail!("Wait for the bot to move");
         }
 
-        // FIXME: remove unwrap. What does `None` mean for a choose move? it ran out of time?
-        let bot_move = match self.bot.choose_move(&self.game.fen(), &legal_moves) {
-            Some(m) => m,
-            None => {
-                // not really sure what we are supposed to do here
-                // this is not a mistake by the player its a mistake by the bot
-                error!(
-                    "Despite the game not being over, 
-                        the bot returned None for a move. Game FEN {}.
-                        Defaulting to a random move",
-                    self.game.fen()
-                );
-
-                // as mentioned above, the game not being over should
-                // guarantee that there are legal moves
-                if legal_moves.is_empty() {
-                    let msg = format!(
-                        "Despite the game not being over There are no legal moves. FEN {}",
-                        self.game.fen()
-                    );
-                    error!("{}", msg);
-                    return Err(anyhow::anyhow!(msg));
-                }
+        // the bot may have had the first move, that one stays
+        let first = self.game.start_position().turn();
+        let ply = (0..self.game.history().len())
+            .rev()
+            .find(|ply| ply.is_multiple_of(2) == (first == Color::White))
+            .ok_or_else(|| anyhow!("There is no move to take back"))?;
+
+        self.last_active = Instant::now();
+        self.game.take_back(ply);
+        Ok(ply)
+    }
+
+    /// What the bot needs to pick its move, so it can think without holding
+    /// on to the game
+    pub fn bot_turn(&mut self) -> (SharedEngine, SearchRequest) {
+        let request = SearchRequest::new(&self.game);
+        self.thinking = Some(request.stop.clone());
+        (self.bot.clone(), request)
+    }
+
+    /// Tells the bot to stop thinking, if it is
+    pub fn stop_bot(&mut self) {
+        if let Some(stop) = self.thinking.take() {
+            stop.stop();
+        }
+    }
+
+    /// Plays the move the bot picked for the current position, returns
+    /// `None` if the bot resigned or lost by not coming up with a legal
+    /// move. A draw it offers stands until the player moves
+    pub fn apply_bot_move(&mut self, reply: SearchReply) -> Option<Move> {
+        self.thinking = None;
+        // the player's offer went to the bot as `draw_offered`
+        if reply.offer_draw && self.game.draw_offer() == Some(Color::White) {
+            self.game
+                .end(GameOutcome::new(GameResult::Draw, Termination::Agreement));
+            return None;
+        }
+        if reply.resign {
+            self.game.end(GameOutcome::loss_for(
+                Color::Black,
+                Termination::Resignation,
+            ));
+            return None;
+        }
 
-                legal_moves[0].clone()
+        // same as in engine games, a bot that can't come up with a legal
+        // move loses
+        let bot_move = match reply.best_move {
+            Some(m) if self.game.get_legal_moves().contains(&m) => m,
+            Some(m) => {
+                error!("Bot played illegal move {m} in {}", self.game.fen());
+                self.game.end(GameOutcome::loss_for(
+                    Color::Black,
+                    Termination::IllegalMove,
+                ));
+                return None;
+            }
+            None => {
+                error!("Bot failed to move in {}", self.game.fen());
+                self.game.end(GameOutcome::loss_for(
+                    Color::Black,
+                    Termination::EngineCrash,
+                ));
+                return None;
             }
         };
 
         self.game.make_move(&bot_move);
+        if reply.offer_draw && self.game.outcome().is_none() {
+            self.game.offer_draw(Color::Black);
+        }
 
-        Ok(Some(bot_move))
+        Some(bot_move)
     }
 
     pub fn fen(&self) -> String {
         self.game.fen()
     }
 }
+
+/// Where the player sits in a game against a bot, shared by `POST /play` and
+/// the player's websocket. Everything that happens is stored and sent to
+/// whoever is watching
+#[derive(Clone)]
+pub struct PlayerSeat {
+    pub game_id: Uuid,
+    pub games: Arc<DashMap<Uuid, PlayerGame>>,
+    pub channel: GameChannel,
+    pub storage: Arc<dyn Storage>,
+    pub ratings: Arc<Ratings>,
+}
+
+impl PlayerSeat {
+    pub fn new(
+        game_id: Uuid,
+        games: Arc<DashMap<Uuid, PlayerGame>>,
+        channel: GameChannel,
+        storage: Arc<dyn Storage>,
+        ratings: Arc<Ratings>,
+    ) -> Self {
+        Self {
+            game_id,
+            games,
+            channel,
+            storage,
+            ratings,
+        }
+    }
+
+    // Nobody holds on to a game for long, the bot thinks without it, so
+    // this waits for whoever has it
+    fn game(&self) -> Result<RefMut<'_, Uuid, PlayerGame>> {
+        self.games
+            .get_mut(&self.game_id)
+            .ok_or_else(|| anyhow!("No active game for {}", self.game_id))
+    }
+
+    /// Plays the player's move, returns whether the bot has to reply
+    pub fn play(&self, uci: &Uci) -> Result<bool> {
+        let mut game = self.game()?;
+        // the player ran out of time before moving, the move doesn't count
+        // but the loss has to be stored and told about, once
+        if let Some(outcome) = game.game.end_if_flagged() {
+            game.stop_bot();
+            let ply = game.game.history().len();
+            self.publish(&game, ply);
+            bail!("Game is already over: {outcome:?}");
+        }
+        let m = uci
+            .to_move(&game.game.game)
+            .map_err(|e| anyhow!("Illegal move {uci}: {e}"))?;
+
+        let before = game.game.history().len();
+        game.player_move(&m)?;
+        self.publish(&game, before);
+
+        let bot_to_move = game.game.outcome().is_none();
+        if bot_to_move {
+            let ply = game.game.history().len();
+            broadcast(&self.channel, ServerMessage::Thinking { ply });
+        }
+        Ok(bot_to_move)
+    }
+
+    /// How long the bot waits before it starts on its reply, nothing when
+    /// it isn't its turn
+    pub fn bot_pacing(&self) -> Duration {
+        match self.games.get(&self.game_id) {
+            Some(game)
+                if game.game.outcome().is_none() && game.game.game.turn() == Color::Black =>
+            {
+                game.bot.pacing(game.game.clock().time_left().as_ref())
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Lets the bot reply to the player's move, this takes as long as the
+    /// bot thinks. The game isn't locked in the meantime, so it can be
+    /// watched or resigned. `GameRunner::bot_reply` runs this on the pool
+    pub fn bot_reply(&self) -> Result<()> {
+        let (plies, (bot, request)) = {
+            let Some(mut game) = self.games.get_mut(&self.game_id) else {
+                bail!("No active game for {}", self.game_id);
+            };
+            // whatever kept the bot waiting isn't charged to it
+            if game.game.game.turn() == Color::Black {
+                game.game.restart_clock();
+            }
+            if game.game.outcome().is_some() || game.game.game.turn() != Color::Black {
+                return Ok(());
+            }
+            (game.game.history().len(), game.bot_turn())
+        };
+        let reply = bot.search(&request.with_spectators(&self.channel));
+
+        let Some(mut game) = self.games.get_mut(&self.game_id) else {
+            bail!("No active game for {}", self.game_id);
+        };
+        // e.g. the player resigned while the bot was thinking
+        if game.game.outcome().is_some() || game.game.history().len() != plies {
+            return Ok(());
+        }
+        let offered = game.game.draw_offer() == Some(Color::White);
+        game.apply_bot_move(reply);
+        self.publish(&game, plies);
+        if offered && game.game.outcome().is_none() {
+            broadcast(&self.channel, ServerMessage::DrawDeclined);
+        }
+        Ok(())
+    }
+
+    pub fn resign(&self) -> Result<()> {
+        let mut game = self.game()?;
+        if let Some(outcome) = game.game.outcome() {
+            bail!("Game is already over: {outcome:?}");
+        }
+        game.game.end(GameOutcome::loss_for(
+            Color::White,
+            Termination::Resignation,
+        ));
+        game.stop_bot();
+        let ply = game.game.history().len();
+        self.publish(&game, ply);
+        Ok(())
+    }
+
+    pub fn offer_draw(&self) -> Result<()> {
+        let mut game = self.game()?;
+        if let Some(outcome) = game.game.outcome() {
+            bail!("Game is already over: {outcome:?}");
+        }
+        // taking the bot's own offer always works
+        if game.game.draw_offer() == Some(Color::Black) {
+            game.game
+                .end(GameOutcome::new(GameResult::Draw, Termination::Agreement));
+            game.stop_bot();
+            let ply = game.game.history().len();
+            self.publish(&game, ply);
+            return Ok(());
+        }
+        if game.game.game.turn() != Color::White {
+            bail!("Offer a draw on your turn");
+        }
+
+        // the bot answers with its reply to the player's next move
+        game.game.offer_draw(Color::White);
+        let by = Color::White.to_string();
+        broadcast(&self.channel, ServerMessage::DrawOffered { by });
+        Ok(())
+    }
+
+    pub fn take_back(&self) -> Result<()> {
+        let mut game = self.game()?;
+        let ply = game.take_back()?;
+
+        // stored moves can only be added to, so the game is saved again
+        if let Err(e) = self.storage.save_game(&game.stored(self.game_id)) {
+            error!("Failed to store game {}: {e}", self.game_id);
+        }
+        broadcast(
+            &self.channel,
+            ServerMessage::TakenBack {
+                ply,
+                fen: game.fen(),
+                clock: game.game.clock_state(),
+            },
+        );
+        Ok(())
+    }
+
+    // Stores the moves played since `before` so the game can be picked up
+    // again after a restart, and tells everyone watching. Finished games
+    // are rated
+    fn publish(&self, game: &PlayerGame, before: usize) {
+        let new_moves = &game.game.history()[before..];
+        let outcome = game.game.outcome();
+        if !new_moves.is_empty() || outcome.is_some() {
+            if let Err(e) = self.storage.record_moves(self.game_id, new_moves, outcome) {
+                error!("Failed to store moves of {}: {e}", self.game_id);
+            }
+        }
+
+        if !new_moves.is_empty() {
+            broadcast(&self.channel, ServerMessage::position(&game.game));
+            if outcome.is_none() {
+                if let Some(color) = game.game.draw_offer() {
+                    let by = color.to_string();
+                    broadcast(&self.channel, ServerMessage::DrawOffered { by });
+                }
+            }
+        }
+        if let Some(outcome) = outcome {
+            self.ratings.record(
+                self.game_id,
+                &bot_registry::player(),
+                &game.opponent,
+                outcome,
+            );
+            broadcast(&self.channel, ServerMessage::GameOver { outcome });
+        }
+    }
+}
@@ -0,0 +1,267 @@
+// The json messages sent over `/ws/{game id}`. Every message is an object
+// with the protocol version in `v` and the kind of message in `type`, e.g.
+//
+//   {"v":1,"type":"position","fen":"...","ply":1,"last_move":{"uci":"e2e4","san":"e4"}}
+//
+// The version only goes up when existing messages change. New message types
+// can be added without it, so clients should skip types they don't know.
+// client/js/protocol.js is the browser side of this.
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use shakmaty::Color;
+use uuid::Uuid;
+
+use crate::chess_engine::SearchInfo;
+use crate::chess_game::{ChessGame, GameOutcome, PlayedMove};
+use crate::clock::ClockState;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MAX_CHAT_LENGTH: usize = 500;
+pub const MAX_NAME_LENGTH: usize = 32;
+const DEFAULT_CHAT_NAME: &str = "Spectator";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LastMove {
+    pub uci: String,
+    pub san: String,
+}
+
+/// Engine evaluation from white's point of view, `{"cp": 35}` or
+/// `{"mate": -3}`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Score {
+    /// Centipawns
+    Cp(i32),
+    /// Moves until mate, negative when black is mating
+    Mate(i32),
+}
+
+/// Sent by the server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerMessage {
+    /// First message on every connection
+    Hello {
+        game_id: Uuid,
+    },
+    /// The whole game as it stands, sent after `hello` and when a client
+    /// asks to resume. Only has the moves after `from_ply`
+    Snapshot {
+        white: String,
+        black: String,
+        start_fen: String,
+        from_ply: usize,
+        moves: Vec<PlayedMove>,
+        /// Current position
+        fen: String,
+        ply: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clock: Option<ClockState>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        outcome: Option<GameOutcome>,
+    },
+    /// The position after a move
+    Position {
+        fen: String,
+        /// Half moves played so far, the position is the one after this ply
+        ply: usize,
+        /// Left out before the first move
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_move: Option<LastMove>,
+        /// Left out in games without a time control
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clock: Option<ClockState>,
+    },
+    GameOver {
+        outcome: GameOutcome,
+    },
+    /// The bot started working on its reply to the position after `ply`
+    Thinking {
+        ply: usize,
+    },
+    /// The bot turned down the player's draw offer, an accepted offer ends
+    /// the game with `gameOver` instead
+    DrawDeclined,
+    /// `by` ("white" or "black") offers a draw, it stands until the other
+    /// side moves
+    DrawOffered {
+        by: String,
+    },
+    /// The moves after `ply` were taken back, `fen` is the position the game
+    /// goes on from
+    TakenBack {
+        ply: usize,
+        fen: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clock: Option<ClockState>,
+    },
+    /// What an engine thinks of the position after `ply`, sent as it
+    /// thinks
+    Evaluation {
+        ply: usize,
+        score: Score,
+        /// Side of the engine doing the thinking, "white" or "black"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        side: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        depth: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nodes: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nps: Option<u64>,
+        /// Principal variation in UCI notation
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pv: Vec<String>,
+    },
+    Chat {
+        name: String,
+        text: String,
+        sent_at: DateTime<Utc>,
+    },
+    /// Something the client sent couldn't be handled
+    Error {
+        message: String,
+    },
+}
+
+impl ServerMessage {
+    /// The game as it stands after its last move
+    pub fn position(game: &ChessGame) -> Self {
+        ServerMessage::Position {
+            fen: game.fen(),
+            ply: game.history().len(),
+            last_move: game.history().last().map(|m| LastMove {
+                uci: m.uci.clone(),
+                san: m.san.clone(),
+            }),
+            clock: game.clock_state(),
+        }
+    }
+
+    /// Everything a spectator needs to catch up, `from_ply` is how many
+    /// moves they already have
+    pub fn snapshot(game: &ChessGame, from_ply: usize) -> Self {
+        let history = game.history();
+        let from_ply = from_ply.min(history.len());
+        ServerMessage::Snapshot {
+            white: game.white.clone(),
+            black: game.black.clone(),
+            start_fen: game.start_fen(),
+            from_ply,
+            moves: history[from_ply..].to_vec(),
+            fen: game.fen(),
+            ply: history.len(),
+            clock: game.clock_state(),
+            outcome: game.outcome(),
+        }
+    }
+
+    /// A bot's progress on the position after `ply`, turned around to
+    /// white's point of view. `None` until the bot has a score
+    pub fn evaluation(ply: usize, side: Color, info: &SearchInfo) -> Option<Self> {
+        let score = match (info.score?, side) {
+            (score, Color::White) => score,
+            (Score::Cp(cp), Color::Black) => Score::Cp(-cp),
+            (Score::Mate(moves), Color::Black) => Score::Mate(-moves),
+        };
+        Some(ServerMessage::Evaluation {
+            ply,
+            score,
+            side: Some(side.to_string()),
+            depth: info.depth,
+            nodes: info.nodes,
+            nps: info.nps,
+            pv: info.pv.clone(),
+        })
+    }
+
+    /// A chat line to pass on to everyone watching, the text is trimmed and
+    /// has to fit in `MAX_CHAT_LENGTH` characters
+    pub fn chat(name: Option<&str>, text: &str) -> Result<Self> {
+        let text = text.trim();
+        if text.is_empty() {
+            bail!("Chat messages can't be empty");
+        }
+        if text.chars().count() > MAX_CHAT_LENGTH {
+            bail!("Chat messages are limited to {MAX_CHAT_LENGTH} characters");
+        }
+        let name = match name.map(str::trim) {
+            Some(name) if !name.is_empty() => name.chars().take(MAX_NAME_LENGTH).collect(),
+            _ => DEFAULT_CHAT_NAME.to_string(),
+        };
+        Ok(ServerMessage::Chat {
+            name,
+            text: text.to_string(),
+            sent_at: Utc::now(),
+        })
+    }
+
+    pub fn error(message: impl ToString) -> Self {
+        ServerMessage::Error {
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Sent by clients
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientMessage {
+    /// Asks for a snapshot with the moves after `from_ply`, e.g. after
+    /// missing some while the connection was down
+    Resume {
+        #[serde(default)]
+        from_ply: usize,
+    },
+    Chat {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        text: String,
+    },
+    // The rest is only for the player's own connection, `/play/{game id}/ws`
+    /// A move in UCI notation, e.g. `e2e4` or `e7e8q`
+    Move {
+        uci: String,
+    },
+    Resign,
+    OfferDraw,
+    /// Asks to take back the player's last move and the bot's reply to it
+    Takeback,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    v: u32,
+    #[serde(flatten)]
+    message: T,
+}
+
+#[derive(Deserialize)]
+struct Version {
+    v: Option<u32>,
+}
+
+/// The message as it goes over the wire
+pub fn encode<T: Serialize>(message: &T) -> String {
+    serde_json::to_string(&Envelope {
+        v: PROTOCOL_VERSION,
+        message,
+    })
+    .expect("protocol messages always serialize")
+}
+
+/// Reads a message, failing if it's for another version of the protocol
+pub fn decode<T: DeserializeOwned>(text: &str) -> Result<T> {
+    let Version { v } = serde_json::from_str(text).map_err(|e| anyhow!("Invalid message: {e}"))?;
+    match v {
+        Some(PROTOCOL_VERSION) => {}
+        Some(v) => bail!("Unsupported protocol version {v}, expected {PROTOCOL_VERSION}"),
+        None => bail!("Messages need the protocol version in \"v\""),
+    }
+    let envelope: Envelope<T> =
+        serde_json::from_str(text).map_err(|e| anyhow!("Invalid message: {e}"))?;
+    Ok(envelope.message)
+}
@@ -0,0 +1,558 @@
+// Ratings for bots, updated after every finished game. Two systems are kept
+// side by side: plain Elo, which is easy to reason about, and Glicko-2, which
+// also tracks how sure we are about a rating. The leaderboard is sorted by
+// Glicko-2 and uses its deviation for the error bars.
+//
+// Glicko-2 is described in http://www.glicko.net/glicko/glicko2.pdf, every
+// game is treated as its own rating period.
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::PI;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use crate::bot_registry::BotInfo;
+use crate::chess_game::{GameOutcome, GameResult, Termination};
+use crate::storage::Storage;
+
+const INITIAL_RATING: f64 = 1500.0;
+const INITIAL_DEVIATION: f64 = 350.0;
+const INITIAL_VOLATILITY: f64 = 0.06;
+// converts between the Glicko and Glicko-2 scales
+const GLICKO2_SCALE: f64 = 173.7178;
+const CONVERGENCE_TOLERANCE: f64 = 0.000001;
+// two sided 95% confidence
+pub const Z_95: f64 = 1.959964;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RatingConfig {
+    /// How far a single Elo result moves the rating
+    pub k_factor: f64,
+    /// Glicko-2 system constant, limits how fast the volatility changes
+    pub tau: f64,
+}
+
+impl Default for RatingConfig {
+    fn default() -> Self {
+        Self {
+            k_factor: 20.0,
+            tau: 0.5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Glicko2 {
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+}
+
+impl Default for Glicko2 {
+    fn default() -> Self {
+        Self {
+            rating: INITIAL_RATING,
+            deviation: INITIAL_DEVIATION,
+            volatility: INITIAL_VOLATILITY,
+        }
+    }
+}
+
+impl Glicko2 {
+    /// Rating after a rating period with the given games, as (opponent,
+    /// score) pairs where a win scores 1
+    pub fn update(&self, results: &[(Glicko2, f64)], tau: f64) -> Glicko2 {
+        let mu = (self.rating - INITIAL_RATING) / GLICKO2_SCALE;
+        let phi = self.deviation / GLICKO2_SCALE;
+
+        // only the deviation grows when there are no games
+        if results.is_empty() {
+            let phi_star = (phi * phi + self.volatility * self.volatility).sqrt();
+            return Glicko2 {
+                deviation: phi_star * GLICKO2_SCALE,
+                ..*self
+            };
+        }
+
+        let mut v_inverse = 0.0;
+        let mut improvement = 0.0;
+        for (opponent, score) in results {
+            let mu_j = (opponent.rating - INITIAL_RATING) / GLICKO2_SCALE;
+            let phi_j = opponent.deviation / GLICKO2_SCALE;
+            let g = 1.0 / (1.0 + 3.0 * phi_j * phi_j / (PI * PI)).sqrt();
+            let expected = 1.0 / (1.0 + (-g * (mu - mu_j)).exp());
+            v_inverse += g * g * expected * (1.0 - expected);
+            improvement += g * (score - expected);
+        }
+        let v = 1.0 / v_inverse;
+        let delta = v * improvement;
+
+        let volatility = self.new_volatility(phi, v, delta, tau);
+        let phi_star = (phi * phi + volatility * volatility).sqrt();
+        let new_phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+        let new_mu = mu + new_phi * new_phi * improvement;
+
+        Glicko2 {
+            rating: new_mu * GLICKO2_SCALE + INITIAL_RATING,
+            deviation: new_phi * GLICKO2_SCALE,
+            volatility,
+        }
+    }
+
+    // Step 5 of the paper, finds the new volatility with the Illinois
+    // algorithm
+    fn new_volatility(&self, phi: f64, v: f64, delta: f64, tau: f64) -> f64 {
+        let a = (self.volatility * self.volatility).ln();
+        let f = |x: f64| {
+            let ex = x.exp();
+            let d = phi * phi + v + ex;
+            ex * (delta * delta - phi * phi - v - ex) / (2.0 * d * d) - (x - a) / (tau * tau)
+        };
+
+        let mut big_a = a;
+        let mut big_b = if delta * delta > phi * phi + v {
+            (delta * delta - phi * phi - v).ln()
+        } else {
+            let mut k = 1.0;
+            while f(a - k * tau) < 0.0 {
+                k += 1.0;
+            }
+            a - k * tau
+        };
+
+        let (mut f_a, mut f_b) = (f(big_a), f(big_b));
+        while (big_b - big_a).abs() > CONVERGENCE_TOLERANCE {
+            let big_c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+            let f_c = f(big_c);
+            if f_c * f_b <= 0.0 {
+                big_a = big_b;
+                f_a = f_b;
+            } else {
+                f_a /= 2.0;
+            }
+            big_b = big_c;
+            f_b = f_c;
+        }
+
+        (big_a / 2.0).exp()
+    }
+
+    /// 95% confidence interval of the rating
+    pub fn interval(&self) -> (f64, f64) {
+        let margin = Z_95 * self.deviation;
+        (self.rating - margin, self.rating + margin)
+    }
+}
+
+// Elo rating after scoring `score` against an opponent rated `opponent`
+pub fn elo_update(rating: f64, opponent: f64, score: f64, k_factor: f64) -> f64 {
+    let expected = 1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0));
+    rating + k_factor * (score - expected)
+}
+
+/// Elo difference that gives an expected score of `score`
+pub fn elo_from_score(score: f64) -> f64 {
+    -400.0 * (1.0 / score - 1.0).log10()
+}
+
+/// Cumulative distribution function of the standard normal distribution
+pub fn normal_cdf(x: f64) -> f64 {
+    0.5 * (1.0 + erf(x / 2f64.sqrt()))
+}
+
+// Abramowitz and Stegun 7.1.26, good to about 1e-7 which is plenty here
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let y = 1.0 - poly * (-x * x).exp();
+    if x >= 0.0 {
+        y
+    } else {
+        -y
+    }
+}
+
+/// Likelihood that the side with `wins` is the stronger one, draws don't
+/// tell us anything either way
+pub fn likelihood_of_superiority(wins: u32, losses: u32) -> f64 {
+    if wins + losses == 0 {
+        return 0.5;
+    }
+    let (w, l) = (wins as f64, losses as f64);
+    normal_cdf((w - l) / (w + l).sqrt())
+}
+
+/// Elo difference implied by a match score and its 95% error margin,
+/// `None` while one side has all the points
+pub fn elo_estimate(wins: u32, draws: u32, losses: u32) -> Option<(f64, f64)> {
+    let n = (wins + draws + losses) as f64;
+    let (w, d, l) = (wins as f64 / n, draws as f64 / n, losses as f64 / n);
+    let score = w + d / 2.0;
+    if !(score > 0.0 && score < 1.0) {
+        return None;
+    }
+
+    let variance = w * (1.0 - score).powi(2) + d * (0.5 - score).powi(2) + l * score.powi(2);
+    let error = Z_95 * (variance / n).sqrt();
+    let low = elo_from_score((score - error).max(f64::EPSILON));
+    let high = elo_from_score((score + error).min(1.0 - f64::EPSILON));
+    Some((elo_from_score(score), (high - low) / 2.0))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BotRating {
+    pub id: String,
+    pub name: String,
+    pub elo: f64,
+    pub glicko: Glicko2,
+    pub games: u32,
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+impl BotRating {
+    fn new(bot: &BotInfo) -> Self {
+        Self {
+            id: bot.id.clone(),
+            name: bot.name.clone(),
+            elo: INITIAL_RATING,
+            glicko: Glicko2::default(),
+            games: 0,
+            wins: 0,
+            draws: 0,
+            losses: 0,
+        }
+    }
+}
+
+/// Results between two bots, from the point of view of `a`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HeadToHead {
+    pub a: String,
+    pub b: String,
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+/// Everything needed to rebuild the ratings, as saved to the ratings file or
+/// the storage
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RatingsSnapshot {
+    pub bots: Vec<BotRating>,
+    pub head_to_head: Vec<HeadToHead>,
+}
+
+impl RatingsSnapshot {
+    /// Takes over the bots and pairs in `changes`, keeping the rest
+    pub fn merge(&mut self, changes: RatingsSnapshot) {
+        for bot in changes.bots {
+            match self.bots.iter_mut().find(|b| b.id == bot.id) {
+                Some(old) => *old = bot,
+                None => self.bots.push(bot),
+            }
+        }
+        for pair in changes.head_to_head {
+            match self
+                .head_to_head
+                .iter_mut()
+                .find(|h| h.a == pair.a && h.b == pair.b)
+            {
+                Some(old) => *old = pair,
+                None => self.head_to_head.push(pair),
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LeaderboardEntry {
+    pub rank: usize,
+    #[serde(flatten)]
+    pub rating: BotRating,
+    /// 95% confidence interval of the Glicko-2 rating
+    pub interval: (f64, f64),
+    /// Likelihood of being stronger than the next bot down
+    pub los_next: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Comparison {
+    pub a: String,
+    pub b: String,
+    /// Games between the two, from the point of view of `a`
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+    /// Elo difference of `a` over `b` with its 95% error margin, from the
+    /// games they played against each other
+    pub elo_difference: Option<f64>,
+    pub elo_error: Option<f64>,
+    /// Likelihood of `a` being stronger, from their games against each other
+    pub los: f64,
+    /// Same from their ratings, works without direct games
+    pub rating_los: f64,
+}
+
+#[derive(Default)]
+struct RatingsState {
+    bots: HashMap<String, BotRating>,
+    head_to_head: HashMap<(String, String), HeadToHead>,
+    // games already counted, results can be reported more than once
+    recorded: HashSet<Uuid>,
+}
+
+pub struct Ratings {
+    config: RatingConfig,
+    /// Saved after every game when set
+    path: Option<PathBuf>,
+    storage: Option<Arc<dyn Storage>>,
+    state: Mutex<RatingsState>,
+}
+
+impl RatingsState {
+    fn from_snapshot(snapshot: RatingsSnapshot) -> Self {
+        Self {
+            bots: snapshot
+                .bots
+                .into_iter()
+                .map(|b| (b.id.clone(), b))
+                .collect(),
+            head_to_head: snapshot
+                .head_to_head
+                .into_iter()
+                .map(|h| ((h.a.clone(), h.b.clone()), h))
+                .collect(),
+            recorded: HashSet::new(),
+        }
+    }
+
+    fn snapshot(&self) -> RatingsSnapshot {
+        RatingsSnapshot {
+            bots: self.bots.values().cloned().collect(),
+            head_to_head: self.head_to_head.values().cloned().collect(),
+        }
+    }
+}
+
+impl Ratings {
+    /// Ratings that only live in memory
+    pub fn new(config: RatingConfig) -> Self {
+        Self {
+            config,
+            path: None,
+            storage: None,
+            state: Mutex::new(RatingsState::default()),
+        }
+    }
+
+    /// Ratings kept in the storage, along with the games
+    pub fn with_storage(config: RatingConfig, storage: Arc<dyn Storage>) -> Result<Self> {
+        let state = RatingsState::from_snapshot(storage.ratings()?.unwrap_or_default());
+        info!("Loaded {} ratings from storage", state.bots.len());
+        Ok(Self {
+            config,
+            path: None,
+            storage: Some(storage),
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Ratings kept in a json file, starting empty if it doesn't exist yet
+    pub fn load<P: Into<PathBuf>>(path: P, config: RatingConfig) -> Result<Self> {
+        let path = path.into();
+        let file: RatingsSnapshot = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| anyhow!("Invalid ratings file {path:?}: {e}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => RatingsSnapshot::default(),
+            Err(e) => return Err(anyhow!("Failed to read ratings file {path:?}: {e}")),
+        };
+
+        let state = RatingsState::from_snapshot(file);
+        info!("Loaded {} ratings from {path:?}", state.bots.len());
+
+        Ok(Self {
+            config,
+            path: Some(path),
+            storage: None,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Updates both players' ratings, a game that was already recorded is
+    /// ignored. So are games the server ended, e.g. because it shut down,
+    /// their result says nothing about the bots
+    pub fn record(&self, game_id: Uuid, white: &BotInfo, black: &BotInfo, outcome: GameOutcome) {
+        if outcome.termination == Termination::Adjudication {
+            info!("Not rating {} vs {}, adjudicated", white.name, black.name);
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if !state.recorded.insert(game_id) {
+            return;
+        }
+
+        let white_score = match outcome.result {
+            GameResult::WhiteWins => 1.0,
+            GameResult::BlackWins => 0.0,
+            GameResult::Draw => 0.5,
+        };
+
+        let before_white = state
+            .bots
+            .get(&white.id)
+            .cloned()
+            .unwrap_or_else(|| BotRating::new(white));
+        let before_black = state
+            .bots
+            .get(&black.id)
+            .cloned()
+            .unwrap_or_else(|| BotRating::new(black));
+
+        for (player, before, opponent, score) in [
+            (white, &before_white, &before_black, white_score),
+            (black, &before_black, &before_white, 1.0 - white_score),
+        ] {
+            let mut rating = before.clone();
+            // names can change, e.g. a bot re-registered under the same id
+            rating.name = player.name.clone();
+            rating.elo = elo_update(before.elo, opponent.elo, score, self.config.k_factor);
+            rating.glicko = before
+                .glicko
+                .update(&[(opponent.glicko, score)], self.config.tau);
+            rating.games += 1;
+            match score {
+                s if s > 0.5 => rating.wins += 1,
+                s if s < 0.5 => rating.losses += 1,
+                _ => rating.draws += 1,
+            }
+            state.bots.insert(player.id.clone(), rating);
+        }
+
+        // stored once per pair, under the ids in sorted order
+        let (a, b, a_score) = if white.id <= black.id {
+            (&white.id, &black.id, white_score)
+        } else {
+            (&black.id, &white.id, 1.0 - white_score)
+        };
+        let record = state
+            .head_to_head
+            .entry((a.clone(), b.clone()))
+            .or_insert_with(|| HeadToHead {
+                a: a.clone(),
+                b: b.clone(),
+                ..Default::default()
+            });
+        match a_score {
+            s if s > 0.5 => record.wins += 1,
+            s if s < 0.5 => record.losses += 1,
+            _ => record.draws += 1,
+        }
+        let pair = record.clone();
+
+        // only what this game changed, the storage keeps the rest
+        let changes = RatingsSnapshot {
+            bots: vec![state.bots[&white.id].clone(), state.bots[&black.id].clone()],
+            head_to_head: vec![pair],
+        };
+
+        info!(
+            "Rated {} vs {}: {}",
+            white.name,
+            black.name,
+            outcome.result.as_str()
+        );
+        if let Err(e) = self.save(&state, changes) {
+            error!("Failed to save ratings: {e}");
+        }
+    }
+
+    fn save(&self, state: &RatingsState, changes: RatingsSnapshot) -> Result<()> {
+        if let Some(storage) = &self.storage {
+            storage.save_ratings(&changes)?;
+        }
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        // write next to the real file first so a crash can't leave half a file
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&state.snapshot())?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<BotRating> {
+        self.state.lock().unwrap().bots.get(id).cloned()
+    }
+
+    /// Every rated bot, strongest first
+    pub fn leaderboard(&self) -> Vec<LeaderboardEntry> {
+        let state = self.state.lock().unwrap();
+        let mut ratings: Vec<BotRating> = state.bots.values().cloned().collect();
+        ratings.sort_by(|a, b| {
+            b.glicko
+                .rating
+                .total_cmp(&a.glicko.rating)
+                .then(a.name.cmp(&b.name))
+        });
+
+        let los_next: Vec<Option<f64>> = (0..ratings.len())
+            .map(|i| ratings.get(i + 1).map(|next| rating_los(&ratings[i], next)))
+            .collect();
+
+        ratings
+            .into_iter()
+            .zip(los_next)
+            .enumerate()
+            .map(|(i, (rating, los_next))| LeaderboardEntry {
+                rank: i + 1,
+                interval: rating.glicko.interval(),
+                rating,
+                los_next,
+            })
+            .collect()
+    }
+
+    /// How two bots compare, `None` if either of them hasn't been rated
+    pub fn compare(&self, a: &str, b: &str) -> Option<Comparison> {
+        let state = self.state.lock().unwrap();
+        let (rating_a, rating_b) = (state.bots.get(a)?, state.bots.get(b)?);
+
+        let (wins, draws, losses) = match (
+            state.head_to_head.get(&(a.to_string(), b.to_string())),
+            state.head_to_head.get(&(b.to_string(), a.to_string())),
+        ) {
+            (Some(h), _) => (h.wins, h.draws, h.losses),
+            (None, Some(h)) => (h.losses, h.draws, h.wins),
+            (None, None) => (0, 0, 0),
+        };
+        let estimate = elo_estimate(wins, draws, losses);
+
+        Some(Comparison {
+            a: a.to_string(),
+            b: b.to_string(),
+            wins,
+            draws,
+            losses,
+            elo_difference: estimate.map(|(elo, _)| elo),
+            elo_error: estimate.map(|(_, error)| error),
+            los: likelihood_of_superiority(wins, losses),
+            rating_los: rating_los(rating_a, rating_b),
+        })
+    }
+}
+
+// Chance that `a` is really rated above `b`, given how uncertain both
+// ratings are
+fn rating_los(a: &BotRating, b: &BotRating) -> f64 {
+    let spread = (a.glicko.deviation.powi(2) + b.glicko.deviation.powi(2)).sqrt();
+    normal_cdf((a.glicko.rating - b.glicko.rating) / spread)
+}
@@ -0,0 +1,397 @@
+// Sequential probability ratio test between two bots, to find out whether a
+// change made a bot stronger. Games are played in pairs from the same opening
+// with colors swapped, which cancels out most of the bias of the opening, and
+// the test works on the pentanomial distribution of the pair scores: the
+// candidate scores 0, 0.5, 1, 1.5 or 2 points per pair.
+//
+// The log-likelihood ratio uses the normal approximation fishtest uses for
+// logistic Elo, the match stops as soon as it crosses one of the bounds.
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use shakmaty::Color;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+use crate::bot_registry::{BotInfo, BotRegistry};
+use crate::chess_game::GameOutcome;
+use crate::clock::TimeControl;
+use crate::game_runner::GameRunner;
+use crate::openings::{OpeningConfig, OpeningSuite};
+use crate::rating::{elo_from_score, normal_cdf, Ratings, Z_95};
+use crate::tournament::{BotGame, GameLauncher};
+
+// keeps the variance from being zero while all pairs have the same score
+const REGULARIZATION: f64 = 0.001;
+
+fn default_elo1() -> f64 {
+    5.0
+}
+
+fn default_error() -> f64 {
+    0.05
+}
+
+fn default_concurrency() -> usize {
+    2
+}
+
+fn default_max_pairs() -> u32 {
+    1000
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SprtConfig {
+    /// Bot id of the new version
+    pub candidate: String,
+    /// Bot id it's tested against
+    pub baseline: String,
+    /// Elo difference of the null hypothesis, "not stronger"
+    #[serde(default)]
+    pub elo0: f64,
+    /// Elo difference of the alternative hypothesis, "stronger"
+    #[serde(default = "default_elo1")]
+    pub elo1: f64,
+    /// Chance of accepting H1 when H0 is true
+    #[serde(default = "default_error")]
+    pub alpha: f64,
+    /// Chance of accepting H0 when H1 is true
+    #[serde(default = "default_error")]
+    pub beta: f64,
+    /// Defaults to no clock
+    pub time_control: Option<TimeControl>,
+    /// Number of pairs played at the same time
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// The match gives up without a decision after this many pairs
+    #[serde(default = "default_max_pairs")]
+    pub max_pairs: u32,
+    /// Where the starting positions come from, both games of a pair use the
+    /// same one. Defaults to a few random moves
+    #[serde(default)]
+    pub openings: OpeningConfig,
+}
+
+impl SprtConfig {
+    pub fn new(candidate: String, baseline: String) -> Self {
+        Self {
+            candidate,
+            baseline,
+            elo0: 0.0,
+            elo1: default_elo1(),
+            alpha: default_error(),
+            beta: default_error(),
+            time_control: None,
+            concurrency: default_concurrency(),
+            max_pairs: default_max_pairs(),
+            openings: OpeningConfig::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SprtStatus {
+    Running,
+    /// The candidate isn't `elo1` stronger
+    AcceptedH0,
+    /// The candidate isn't `elo0` weaker or equal
+    AcceptedH1,
+    /// `max_pairs` were played without a decision
+    Inconclusive,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SprtStats {
+    /// Finished pairs
+    pub pairs: u32,
+    /// Pairs by the candidate's score: 0, 0.5, 1, 1.5 and 2 points
+    pub pentanomial: [u32; 5],
+    /// Game results from the candidate's side
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+    /// Log-likelihood ratio, H0 is accepted at `lower_bound` and H1 at
+    /// `upper_bound`
+    pub llr: f64,
+    pub lower_bound: f64,
+    pub upper_bound: f64,
+    /// Elo difference of the candidate over the baseline, with its 95% error
+    pub elo: Option<f64>,
+    pub elo_error: Option<f64>,
+    /// Likelihood of superiority, the chance that the candidate is stronger
+    pub los: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SprtPair {
+    pub opening: String,
+    /// The candidate has white in the first game and black in the second,
+    /// ids are added as the games start
+    pub game_ids: Vec<Uuid>,
+    pub outcomes: Vec<GameOutcome>,
+    /// Candidate's points, set once both games are over
+    pub score: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SprtSummary {
+    pub id: Uuid,
+    pub candidate: String,
+    pub baseline: String,
+    pub status: SprtStatus,
+    pub pairs: u32,
+    pub llr: f64,
+    pub elo: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SprtMatch {
+    pub id: Uuid,
+    pub config: SprtConfig,
+    pub candidate: BotInfo,
+    pub baseline: BotInfo,
+    pub status: SprtStatus,
+    pub stats: SprtStats,
+    pub pairs: Vec<SprtPair>,
+    pub started_at: DateTime<Utc>,
+    #[serde(skip)]
+    suite: OpeningSuite,
+}
+
+/// Log-likelihood bounds `(lower, upper)` for the given error rates
+pub fn sprt_bounds(alpha: f64, beta: f64) -> (f64, f64) {
+    ((beta / (1.0 - alpha)).ln(), ((1.0 - beta) / alpha).ln())
+}
+
+// Expected score for a logistic Elo difference
+fn expected_score(elo: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf(-elo / 400.0))
+}
+
+// Number of pairs, mean and variance of the pair scores scaled to 0..=1
+fn pair_statistics(pentanomial: &[u32; 5], regularization: f64) -> (f64, f64, f64) {
+    let counts = pentanomial.map(|c| c as f64 + regularization);
+    let n: f64 = counts.iter().sum();
+    let score = |i: usize| i as f64 / 4.0;
+    let mean = (0..5).map(|i| counts[i] * score(i)).sum::<f64>() / n;
+    let variance = (0..5)
+        .map(|i| counts[i] * (score(i) - mean).powi(2))
+        .sum::<f64>()
+        / n;
+    (n, mean, variance)
+}
+
+/// Log-likelihood ratio of H1 (`elo1`) against H0 (`elo0`) for the pair
+/// results so far
+pub fn pentanomial_llr(pentanomial: &[u32; 5], elo0: f64, elo1: f64) -> f64 {
+    if pentanomial.iter().sum::<u32>() == 0 {
+        return 0.0;
+    }
+    let (n, mean, variance) = pair_statistics(pentanomial, REGULARIZATION);
+    let (s0, s1) = (expected_score(elo0), expected_score(elo1));
+    n * (s1 - s0) * (2.0 * mean - s0 - s1) / (2.0 * variance)
+}
+
+/// Elo difference estimated from the pair results with its 95% error, `None`
+/// while one side has scored everything
+pub fn pentanomial_elo(pentanomial: &[u32; 5]) -> Option<(f64, f64)> {
+    let (n, mean, variance) = pair_statistics(pentanomial, 0.0);
+    if !(mean > 0.0 && mean < 1.0) {
+        return None;
+    }
+    let error = Z_95 * (variance / n).sqrt();
+    let low = elo_from_score((mean - error).max(f64::EPSILON));
+    let high = elo_from_score((mean + error).min(1.0 - f64::EPSILON));
+    Some((elo_from_score(mean), (high - low) / 2.0))
+}
+
+/// Chance that the candidate is stronger, from the pair results
+pub fn pentanomial_los(pentanomial: &[u32; 5]) -> Option<f64> {
+    let (n, mean, variance) = pair_statistics(pentanomial, 0.0);
+    if n == 0.0 || variance == 0.0 {
+        return None;
+    }
+    Some(normal_cdf((mean - 0.5) / (variance / n).sqrt()))
+}
+
+// Points `color` scored in the game
+fn points(outcome: &GameOutcome, color: Color) -> f64 {
+    match outcome.result.winner() {
+        Some(winner) if winner == color => 1.0,
+        Some(_) => 0.0,
+        None => 0.5,
+    }
+}
+
+impl SprtStats {
+    fn new(config: &SprtConfig) -> Self {
+        let (lower_bound, upper_bound) = sprt_bounds(config.alpha, config.beta);
+        Self {
+            lower_bound,
+            upper_bound,
+            ..Self::default()
+        }
+    }
+
+    fn add_pair(&mut self, outcomes: &[GameOutcome], config: &SprtConfig) -> f64 {
+        let colors = [Color::White, Color::Black];
+        let mut score = 0.0;
+        for (outcome, color) in outcomes.iter().zip(colors) {
+            let p = points(outcome, color);
+            match p {
+                p if p > 0.5 => self.wins += 1,
+                p if p < 0.5 => self.losses += 1,
+                _ => self.draws += 1,
+            }
+            score += p;
+        }
+
+        self.pairs += 1;
+        self.pentanomial[(score * 2.0) as usize] += 1;
+        self.llr = pentanomial_llr(&self.pentanomial, config.elo0, config.elo1);
+        let estimate = pentanomial_elo(&self.pentanomial);
+        self.elo = estimate.map(|(elo, _)| elo);
+        self.elo_error = estimate.map(|(_, error)| error);
+        self.los = pentanomial_los(&self.pentanomial);
+        score
+    }
+}
+
+impl SprtMatch {
+    /// Checks the config against the registry
+    pub fn new(id: Uuid, config: SprtConfig, bots: &BotRegistry) -> Result<Self> {
+        if config.candidate == config.baseline {
+            bail!("A bot can't be tested against itself");
+        }
+        if config.elo0 >= config.elo1 {
+            bail!("elo0 has to be below elo1");
+        }
+        for error in [config.alpha, config.beta] {
+            if !(error > 0.0 && error < 0.5) {
+                bail!("alpha and beta have to be between 0 and 0.5");
+            }
+        }
+        if config.concurrency == 0 {
+            bail!("Concurrency must be at least 1");
+        }
+        if config.max_pairs == 0 {
+            bail!("max_pairs must be at least 1");
+        }
+        let suite = OpeningSuite::load(&config.openings)?;
+
+        Ok(Self {
+            id,
+            candidate: bots.info(&config.candidate)?,
+            baseline: bots.info(&config.baseline)?,
+            status: SprtStatus::Running,
+            stats: SprtStats::new(&config),
+            pairs: Vec::new(),
+            started_at: Utc::now(),
+            suite,
+            config,
+        })
+    }
+
+    // Claims the next pair for a worker, `None` once the match is over
+    fn next_pair(&mut self) -> Option<(usize, String)> {
+        if self.status != SprtStatus::Running || self.pairs.len() >= self.config.max_pairs as usize
+        {
+            return None;
+        }
+        let opening = self.suite.opening(self.pairs.len());
+        self.pairs.push(SprtPair {
+            opening: opening.clone(),
+            game_ids: Vec::new(),
+            outcomes: Vec::new(),
+            score: None,
+        });
+        Some((self.pairs.len() - 1, opening))
+    }
+
+    // Pairs finishing after the decision are kept but don't count
+    fn finish_pair(&mut self, index: usize, outcomes: Vec<GameOutcome>) {
+        if self.status == SprtStatus::Running {
+            let score = self.stats.add_pair(&outcomes, &self.config);
+            self.pairs[index].score = Some(score);
+
+            if self.stats.llr >= self.stats.upper_bound {
+                self.status = SprtStatus::AcceptedH1;
+            } else if self.stats.llr <= self.stats.lower_bound {
+                self.status = SprtStatus::AcceptedH0;
+            }
+        }
+        self.pairs[index].outcomes = outcomes;
+    }
+
+    pub fn summary(&self) -> SprtSummary {
+        SprtSummary {
+            id: self.id,
+            candidate: self.candidate.name.clone(),
+            baseline: self.baseline.name.clone(),
+            status: self.status,
+            pairs: self.stats.pairs,
+            llr: self.stats.llr,
+            elo: self.stats.elo,
+        }
+    }
+}
+
+/// Plays pairs until the test makes a decision or `max_pairs` is reached,
+/// with the games on `runner`. Every game is rated
+pub async fn run_sprt(
+    sprt: Arc<RwLock<SprtMatch>>,
+    bots: Arc<BotRegistry>,
+    ratings: Arc<Ratings>,
+    launcher: GameLauncher,
+    runner: GameRunner,
+) {
+    let (id, concurrency, candidate, baseline, time_control) = {
+        let s = sprt.read().unwrap();
+        (
+            s.id,
+            s.config.concurrency,
+            s.candidate.clone(),
+            s.baseline.clone(),
+            s.config.time_control,
+        )
+    };
+    info!("SPRT {id} started: {} vs {}", candidate.name, baseline.name);
+
+    join_all((0..concurrency).map(|_| async {
+        loop {
+            let Some((index, opening)) = sprt.write().unwrap().next_pair() else {
+                break;
+            };
+
+            let mut outcomes = Vec::new();
+            for (white, black) in [(&candidate, &baseline), (&baseline, &candidate)] {
+                let game = match BotGame::new(white, black, Some(&opening), time_control, &bots) {
+                    Ok(game) => game,
+                    Err(e) => {
+                        error!("Can't start pair {index} of SPRT {id}: {e}");
+                        break;
+                    }
+                };
+                sprt.write().unwrap().pairs[index].game_ids.push(game.id);
+                outcomes.push(game.play(&ratings, &launcher, &runner).await);
+            }
+            // a pair that couldn't be played in full doesn't count
+            if outcomes.len() == 2 {
+                sprt.write().unwrap().finish_pair(index, outcomes);
+            }
+        }
+    }))
+    .await;
+
+    let mut s = sprt.write().unwrap();
+    if s.status == SprtStatus::Running {
+        s.status = SprtStatus::Inconclusive;
+    }
+    info!(
+        "SPRT {id} finished: {:?}, LLR {:.2} after {} pairs",
+        s.status, s.stats.llr, s.stats.pairs
+    );
+}
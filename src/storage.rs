@@ -0,0 +1,369 @@
+// Storage for everything that should survive a restart: games with their
+// moves and results, the bots users submitted and the ratings. The server
+// only talks to the `Storage` trait, `MemoryStorage` keeps things for as long
+// as the process lives (and in tests), `FileStorage` writes them to disk.
+//
+// The file store is append-only, every change is one json line. The lines are
+// replayed when the file is opened and written back out in compacted form, so
+// a crash can at worst lose the line that was being written.
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use uuid::Uuid;
+
+use crate::chess_game::{ChessGame, GameOutcome, PlayedMove};
+use crate::clock::TimeControl;
+use crate::rating::RatingsSnapshot;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum GameMode {
+    /// Includes tournament and match games
+    BotVsBot,
+    PlayerVsBot,
+    /// Loaded from a PGN
+    Imported,
+}
+
+/// A game as it is stored, enough to rebuild the `ChessGame`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredGame {
+    pub id: Uuid,
+    pub mode: GameMode,
+    pub white: String,
+    pub black: String,
+    /// Bot ids, when the side was played by a registered bot
+    pub white_bot: Option<String>,
+    pub black_bot: Option<String>,
+    /// What the player of a game against a bot needs to get back in
+    #[serde(default)]
+    pub seat_token: Option<Uuid>,
+    pub start_fen: String,
+    pub moves: Vec<PlayedMove>,
+    pub outcome: Option<GameOutcome>,
+    pub time_control: TimeControl,
+    pub claim_draws: bool,
+    pub started_at: DateTime<Utc>,
+}
+
+impl StoredGame {
+    pub fn new(id: Uuid, mode: GameMode, game: &ChessGame) -> Self {
+        Self {
+            id,
+            mode,
+            white: game.white.clone(),
+            black: game.black.clone(),
+            white_bot: None,
+            black_bot: None,
+            seat_token: None,
+            start_fen: game.start_fen(),
+            moves: game.history().to_vec(),
+            outcome: game.outcome(),
+            time_control: game.clock().control(),
+            claim_draws: game.claim_draws,
+            started_at: game.started_at,
+        }
+    }
+
+    /// Replays the moves, an unfinished game picks up with the clocks where
+    /// they were after the last move
+    pub fn restore(&self) -> Result<ChessGame> {
+        let mut game = ChessGame::from_fen(&self.start_fen)?;
+        game.white = self.white.clone();
+        game.black = self.black.clone();
+        game.started_at = self.started_at;
+        game.claim_draws = self.claim_draws;
+        for played in &self.moves {
+            game.replay(played)
+                .map_err(|e| anyhow!("Can't restore game {}: {e}", self.id))?;
+        }
+        if self.time_control != TimeControl::Unlimited {
+            game.resume_clock(self.time_control);
+        }
+        if let Some(outcome) = self.outcome {
+            game.end(outcome);
+        }
+        Ok(game)
+    }
+}
+
+/// A lua bot submitted through `/bots`, UCI engines come from the command
+/// line and aren't stored
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StoredBot {
+    pub id: String,
+    pub name: String,
+    pub script: String,
+}
+
+pub trait Storage: Send + Sync {
+    /// Adds a game or replaces it
+    fn save_game(&self, game: &StoredGame) -> Result<()>;
+    /// Appends moves to a stored game and sets its result once it's over
+    fn record_moves(
+        &self,
+        id: Uuid,
+        moves: &[PlayedMove],
+        outcome: Option<GameOutcome>,
+    ) -> Result<()>;
+    /// Every stored game, oldest first
+    fn games(&self) -> Result<Vec<StoredGame>>;
+    fn game(&self, id: Uuid) -> Result<Option<StoredGame>>;
+    fn save_bot(&self, bot: &StoredBot) -> Result<()>;
+    fn bots(&self) -> Result<Vec<StoredBot>>;
+    /// Updates the bots and pairs in `changes`, the other ratings stay
+    fn save_ratings(&self, changes: &RatingsSnapshot) -> Result<()>;
+    fn ratings(&self) -> Result<Option<RatingsSnapshot>>;
+}
+
+// One line of the file store
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "camelCase")]
+enum Record {
+    Game(StoredGame),
+    Moves {
+        id: Uuid,
+        moves: Vec<PlayedMove>,
+        outcome: Option<GameOutcome>,
+    },
+    Bot(StoredBot),
+    Ratings(RatingsSnapshot),
+}
+
+#[derive(Default)]
+struct StoredState {
+    games: HashMap<Uuid, StoredGame>,
+    bots: HashMap<String, StoredBot>,
+    ratings: Option<RatingsSnapshot>,
+}
+
+impl StoredState {
+    fn apply(&mut self, record: Record) -> Result<()> {
+        match record {
+            Record::Game(game) => {
+                self.games.insert(game.id, game);
+            }
+            Record::Moves { id, moves, outcome } => {
+                let game = self
+                    .games
+                    .get_mut(&id)
+                    .ok_or(anyhow!("No stored game {id}"))?;
+                game.moves.extend(moves);
+                game.outcome = outcome.or(game.outcome);
+            }
+            Record::Bot(bot) => {
+                self.bots.insert(bot.id.clone(), bot);
+            }
+            Record::Ratings(changes) => self
+                .ratings
+                .get_or_insert_with(RatingsSnapshot::default)
+                .merge(changes),
+        }
+        Ok(())
+    }
+
+    // The fewest records that give back the same state
+    fn records(&self) -> Vec<Record> {
+        let mut records: Vec<Record> = self.bots.values().cloned().map(Record::Bot).collect();
+        let mut games: Vec<&StoredGame> = self.games.values().collect();
+        games.sort_by_key(|g| g.started_at);
+        records.extend(games.into_iter().cloned().map(Record::Game));
+        records.extend(self.ratings.clone().map(Record::Ratings));
+        records
+    }
+
+    fn games(&self) -> Vec<StoredGame> {
+        let mut games: Vec<StoredGame> = self.games.values().cloned().collect();
+        games.sort_by_key(|g| g.started_at);
+        games
+    }
+
+    fn bots(&self) -> Vec<StoredBot> {
+        let mut bots: Vec<StoredBot> = self.bots.values().cloned().collect();
+        bots.sort_by(|a, b| a.name.cmp(&b.name));
+        bots
+    }
+}
+
+/// Keeps everything in memory, gone when the process exits
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: RwLock<StoredState>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn apply(&self, record: Record) -> Result<()> {
+        self.state.write().unwrap().apply(record)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn save_game(&self, game: &StoredGame) -> Result<()> {
+        self.apply(Record::Game(game.clone()))
+    }
+
+    fn record_moves(
+        &self,
+        id: Uuid,
+        moves: &[PlayedMove],
+        outcome: Option<GameOutcome>,
+    ) -> Result<()> {
+        self.apply(Record::Moves {
+            id,
+            moves: moves.to_vec(),
+            outcome,
+        })
+    }
+
+    fn games(&self) -> Result<Vec<StoredGame>> {
+        Ok(self.state.read().unwrap().games())
+    }
+
+    fn game(&self, id: Uuid) -> Result<Option<StoredGame>> {
+        Ok(self.state.read().unwrap().games.get(&id).cloned())
+    }
+
+    fn save_bot(&self, bot: &StoredBot) -> Result<()> {
+        self.apply(Record::Bot(bot.clone()))
+    }
+
+    fn bots(&self) -> Result<Vec<StoredBot>> {
+        Ok(self.state.read().unwrap().bots())
+    }
+
+    fn save_ratings(&self, changes: &RatingsSnapshot) -> Result<()> {
+        self.apply(Record::Ratings(changes.clone()))
+    }
+
+    fn ratings(&self) -> Result<Option<RatingsSnapshot>> {
+        Ok(self.state.read().unwrap().ratings.clone())
+    }
+}
+
+/// Append-only json lines file
+pub struct FileStorage {
+    path: PathBuf,
+    // the file and the state are changed together, under the file's lock
+    file: Mutex<File>,
+    state: RwLock<StoredState>,
+}
+
+impl FileStorage {
+    /// Opens the store, creating it if it doesn't exist yet
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let mut state = StoredState::default();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(anyhow!("Failed to read {path:?}: {e}")),
+        };
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            // most likely the line that was being written during a crash
+            let applied = serde_json::from_str(line)
+                .map_err(anyhow::Error::from)
+                .and_then(|record| state.apply(record));
+            if let Err(e) = applied {
+                warn!("Skipping line {} of {path:?}: {e}", number + 1);
+            }
+        }
+        info!(
+            "Loaded {} games and {} bots from {path:?}",
+            state.games.len(),
+            state.bots.len()
+        );
+
+        compact(&path, &state)?;
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            state: RwLock::new(state),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn append(&self, record: Record) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        // a record that doesn't apply never makes it to the file
+        self.state.write().unwrap().apply(record)?;
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+// Rewrites the file with one record per game, bot and the ratings
+fn compact(path: &Path, state: &StoredState) -> Result<()> {
+    let mut text = String::new();
+    for record in state.records() {
+        text.push_str(&serde_json::to_string(&record)?);
+        text.push('\n');
+    }
+    // write next to the real file first so a crash can't leave half a file
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+impl Storage for FileStorage {
+    fn save_game(&self, game: &StoredGame) -> Result<()> {
+        self.append(Record::Game(game.clone()))
+    }
+
+    fn record_moves(
+        &self,
+        id: Uuid,
+        moves: &[PlayedMove],
+        outcome: Option<GameOutcome>,
+    ) -> Result<()> {
+        self.append(Record::Moves {
+            id,
+            moves: moves.to_vec(),
+            outcome,
+        })
+    }
+
+    fn games(&self) -> Result<Vec<StoredGame>> {
+        Ok(self.state.read().unwrap().games())
+    }
+
+    fn game(&self, id: Uuid) -> Result<Option<StoredGame>> {
+        Ok(self.state.read().unwrap().games.get(&id).cloned())
+    }
+
+    fn save_bot(&self, bot: &StoredBot) -> Result<()> {
+        self.append(Record::Bot(bot.clone()))
+    }
+
+    fn bots(&self) -> Result<Vec<StoredBot>> {
+        Ok(self.state.read().unwrap().bots())
+    }
+
+    fn save_ratings(&self, changes: &RatingsSnapshot) -> Result<()> {
+        self.append(Record::Ratings(changes.clone()))
+    }
+
+    fn ratings(&self) -> Result<Option<RatingsSnapshot>> {
+        Ok(self.state.read().unwrap().ratings.clone())
+    }
+}
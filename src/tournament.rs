@@ -0,0 +1,680 @@
+// Tournaments between registered bots. Pairings for round-robins and
+// gauntlets are all known up front, Swiss rounds are paired one at a time
+// from the standings. A fixed number of games are played at the same time,
+// as tasks on the server's `GameRunner`.
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use shakmaty::Color;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use uuid::Uuid;
+
+use crate::bot_registry::{BotInfo, BotRegistry};
+use crate::chess_engine::SharedEngine;
+use crate::chess_game::{ChessGame, GameOutcome, GameResult, Termination};
+use crate::clock::TimeControl;
+use crate::game_runner::GameRunner;
+use crate::openings::{OpeningConfig, OpeningSuite};
+use crate::rating::Ratings;
+use crate::websocket::GameChannel;
+
+/// Called for every game a tournament starts, so it can be spectated. Returns
+/// the channel the game's moves are reported to
+pub type GameLauncher = Arc<dyn Fn(Uuid, Arc<RwLock<ChessGame>>) -> GameChannel + Send + Sync>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TournamentFormat {
+    /// Everyone plays everyone once
+    RoundRobin,
+    /// Everyone plays everyone twice, once with each color
+    DoubleRoundRobin,
+    /// `champion` plays every other bot twice, once with each color
+    Gauntlet { champion: String },
+    /// Players with similar scores are paired against each other, defaults
+    /// to enough rounds to find a clear winner
+    Swiss { rounds: Option<u32> },
+}
+
+fn default_concurrency() -> usize {
+    2
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TournamentConfig {
+    pub name: Option<String>,
+    pub format: TournamentFormat,
+    /// Bot ids from `/bots`
+    pub bots: Vec<String>,
+    /// Defaults to no clock
+    pub time_control: Option<TimeControl>,
+    /// Number of games played at the same time
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Starting position of every game, defaults to the standard one
+    pub fen: Option<String>,
+    /// Gives every game its own starting position instead of `fen`, a
+    /// rematch with colors reversed starts from the same one
+    #[serde(default)]
+    pub openings: Option<OpeningConfig>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TournamentStatus {
+    Running,
+    Finished,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TournamentGame {
+    /// Rounds count from 1
+    pub round: u32,
+    /// Indexes into the tournament's participants
+    pub white: usize,
+    pub black: usize,
+    /// FEN of the starting position, `None` to use the config's `fen`
+    pub opening: Option<String>,
+    /// Set once the game has started, it can be watched at `/spectate/{id}`
+    pub game_id: Option<Uuid>,
+    pub outcome: Option<GameOutcome>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Bye {
+    pub round: u32,
+    pub player: usize,
+    /// Swiss byes are worth a point, round-robin byes are just a rest
+    pub points: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Standing {
+    pub player: usize,
+    pub rank: usize,
+    pub games: u32,
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+    pub score: f64,
+    pub sonneborn_berger: f64,
+    /// Points scored against the players on the same score
+    pub head_to_head: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Crosstable {
+    /// Best first
+    pub standings: Vec<Standing>,
+    /// `results[a][b]` is the points `a` scored against `b`, `None` if they
+    /// haven't finished a game yet
+    pub results: Vec<Vec<Option<f64>>>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TournamentSummary {
+    pub id: Uuid,
+    pub name: String,
+    pub format: TournamentFormat,
+    pub status: TournamentStatus,
+    pub players: usize,
+    pub games_played: usize,
+    pub games_total: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Tournament {
+    pub id: Uuid,
+    pub name: String,
+    pub config: TournamentConfig,
+    pub participants: Vec<BotInfo>,
+    pub games: Vec<TournamentGame>,
+    pub byes: Vec<Bye>,
+    pub status: TournamentStatus,
+    pub started_at: DateTime<Utc>,
+    /// Number of Swiss rounds paired so far
+    #[serde(skip)]
+    rounds_paired: u32,
+    #[serde(skip)]
+    suite: Option<OpeningSuite>,
+    #[serde(skip)]
+    openings_used: usize,
+    /// Openings waiting for the game with colors reversed, by (white, black)
+    #[serde(skip)]
+    pending_openings: HashMap<(usize, usize), String>,
+}
+
+impl Tournament {
+    /// Checks the config against the registry and schedules the games that
+    /// are known up front
+    pub fn new(id: Uuid, config: TournamentConfig, bots: &BotRegistry) -> Result<Self> {
+        if config.bots.len() < 2 {
+            bail!("A tournament needs at least two bots");
+        }
+        if config.concurrency == 0 {
+            bail!("Concurrency must be at least 1");
+        }
+        let mut seen = HashSet::new();
+        if let Some(id) = config.bots.iter().find(|id| !seen.insert(*id)) {
+            bail!("Bot {id} is entered twice");
+        }
+        if let Some(fen) = &config.fen {
+            ChessGame::from_fen(fen)?;
+        }
+        if config.fen.is_some() && config.openings.is_some() {
+            bail!("Pick either a fen or openings");
+        }
+        let suite = config
+            .openings
+            .as_ref()
+            .map(OpeningSuite::load)
+            .transpose()?;
+
+        let participants = config
+            .bots
+            .iter()
+            .map(|id| bots.info(id))
+            .collect::<Result<Vec<_>>>()?;
+
+        let name = match config.name.as_deref().map(str::trim) {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => format!("tournament-{}", &id.to_string()[..8]),
+        };
+
+        let mut tournament = Self {
+            id,
+            name,
+            config,
+            participants,
+            games: Vec::new(),
+            byes: Vec::new(),
+            status: TournamentStatus::Running,
+            started_at: Utc::now(),
+            rounds_paired: 0,
+            suite,
+            openings_used: 0,
+            pending_openings: HashMap::new(),
+        };
+
+        let n = tournament.participants.len();
+        match &tournament.config.format {
+            TournamentFormat::RoundRobin => tournament.round_robin(1),
+            TournamentFormat::DoubleRoundRobin => {
+                tournament.round_robin(1);
+                // second cycle repeats the first with colors swapped
+                let rounds = n as u32 - 1 + n as u32 % 2;
+                let first_cycle = tournament.games.clone();
+                tournament
+                    .games
+                    .extend(first_cycle.into_iter().map(|g| TournamentGame {
+                        round: g.round + rounds,
+                        white: g.black,
+                        black: g.white,
+                        ..g
+                    }));
+                let byes = tournament.byes.clone();
+                tournament.byes.extend(byes.into_iter().map(|b| Bye {
+                    round: b.round + rounds,
+                    ..b
+                }));
+            }
+            TournamentFormat::Gauntlet { champion } => {
+                let Some(champion) = tournament.config.bots.iter().position(|b| b == champion)
+                else {
+                    bail!("Champion {champion} isn't one of the bots");
+                };
+                for opponent in (0..n).filter(|&p| p != champion) {
+                    tournament.schedule(1, champion, opponent);
+                    tournament.schedule(2, opponent, champion);
+                }
+            }
+            TournamentFormat::Swiss { rounds: Some(0) } => bail!("Swiss needs at least one round"),
+            TournamentFormat::Swiss { .. } => {}
+        }
+
+        Ok(tournament)
+    }
+
+    fn schedule(&mut self, round: u32, white: usize, black: usize) {
+        let opening = match self.pending_openings.remove(&(black, white)) {
+            Some(opening) => Some(opening),
+            None => self.next_opening().inspect(|opening| {
+                self.pending_openings
+                    .insert((white, black), opening.clone());
+            }),
+        };
+        self.games.push(TournamentGame {
+            round,
+            white,
+            black,
+            opening,
+            game_id: None,
+            outcome: None,
+        });
+    }
+
+    fn next_opening(&mut self) -> Option<String> {
+        let opening = self.suite.as_ref()?.opening(self.openings_used);
+        self.openings_used += 1;
+        Some(opening)
+    }
+
+    // Circle method: one player stays put while the others rotate around it,
+    // giving every pair exactly one game over n - 1 rounds
+    fn round_robin(&mut self, first_round: u32) {
+        let n = self.participants.len();
+        let mut ring: Vec<Option<usize>> = (0..n).map(Some).collect();
+        if n % 2 == 1 {
+            ring.push(None);
+        }
+        let size = ring.len();
+        let mut balance = self.color_balance();
+
+        for round in 0..size - 1 {
+            let round_number = first_round + round as u32;
+            for board in 0..size / 2 {
+                match (ring[board], ring[size - 1 - board]) {
+                    (Some(a), Some(b)) if (round + board) % 2 == 0 => {
+                        self.schedule_balanced(round_number, a, b, &mut balance)
+                    }
+                    (Some(a), Some(b)) => self.schedule_balanced(round_number, b, a, &mut balance),
+                    (Some(player), None) | (None, Some(player)) => self.byes.push(Bye {
+                        round: round_number,
+                        player,
+                        points: 0.0,
+                    }),
+                    (None, None) => {}
+                }
+            }
+            ring[1..].rotate_right(1);
+        }
+    }
+
+    fn swiss_rounds(&self) -> u32 {
+        match self.config.format {
+            TournamentFormat::Swiss {
+                rounds: Some(rounds),
+            } => rounds,
+            // enough rounds for a single player to beat everyone else
+            TournamentFormat::Swiss { rounds: None } => {
+                (self.participants.len() as f64).log2().ceil().max(1.0) as u32
+            }
+            _ => 0,
+        }
+    }
+
+    // Pairs the next Swiss round: players are sorted by score, each one is
+    // paired with the best placed player they haven't met yet
+    fn pair_swiss_round(&mut self) {
+        self.rounds_paired += 1;
+        let round = self.rounds_paired;
+        let scores = self.scores();
+
+        let mut order: Vec<usize> = (0..self.participants.len()).collect();
+        order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]).then(a.cmp(b)));
+
+        // the lowest placed player without a bye sits out
+        if order.len() % 2 == 1 {
+            let had_bye: HashSet<usize> = self.byes.iter().map(|b| b.player).collect();
+            let pos = order
+                .iter()
+                .rposition(|p| !had_bye.contains(p))
+                .unwrap_or(order.len() - 1);
+            let player = order.remove(pos);
+            self.byes.push(Bye {
+                round,
+                player,
+                points: 1.0,
+            });
+        }
+
+        let met: HashSet<(usize, usize)> = self
+            .games
+            .iter()
+            .flat_map(|g| [(g.white, g.black), (g.black, g.white)])
+            .collect();
+        let pairs = pair_unmet(&order, &met).unwrap_or_else(|| {
+            // everyone has met already, rematches it is
+            order.chunks(2).map(|c| (c[0], c[1])).collect()
+        });
+
+        let mut balance = self.color_balance();
+        for (a, b) in pairs {
+            self.schedule_balanced(round, a, b, &mut balance);
+        }
+    }
+
+    // Whoever had white less often gets it, `a` on a tie
+    fn schedule_balanced(&mut self, round: u32, a: usize, b: usize, balance: &mut [i32]) {
+        let (white, black) = if balance[a] <= balance[b] {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        balance[white] += 1;
+        balance[black] -= 1;
+        self.schedule(round, white, black);
+    }
+
+    // Games with white minus games with black, for every player
+    fn color_balance(&self) -> Vec<i32> {
+        let mut balance = vec![0; self.participants.len()];
+        for game in &self.games {
+            balance[game.white] += 1;
+            balance[game.black] -= 1;
+        }
+        balance
+    }
+
+    /// Games to play next, pairing a new Swiss round if needed. Empty once
+    /// the tournament is over
+    pub fn next_batch(&mut self) -> Vec<usize> {
+        let pending = |games: &[TournamentGame]| -> Vec<usize> {
+            (0..games.len())
+                .filter(|&i| games[i].game_id.is_none() && games[i].outcome.is_none())
+                .collect()
+        };
+
+        let batch = pending(&self.games);
+        if !batch.is_empty() {
+            return batch;
+        }
+        if self.rounds_paired < self.swiss_rounds() {
+            self.pair_swiss_round();
+            return pending(&self.games);
+        }
+
+        self.status = TournamentStatus::Finished;
+        Vec::new()
+    }
+
+    // Total points for every player, byes included
+    fn scores(&self) -> Vec<f64> {
+        let mut scores = vec![0.0; self.participants.len()];
+        for bye in &self.byes {
+            scores[bye.player] += bye.points;
+        }
+        for game in &self.games {
+            if let Some(outcome) = game.outcome {
+                let white = white_points(outcome.result);
+                scores[game.white] += white;
+                scores[game.black] += 1.0 - white;
+            }
+        }
+        scores
+    }
+
+    pub fn crosstable(&self) -> Crosstable {
+        let n = self.participants.len();
+        let scores = self.scores();
+        let mut results = vec![vec![None; n]; n];
+        let mut standings: Vec<Standing> = (0..n)
+            .map(|player| Standing {
+                player,
+                rank: 0,
+                games: 0,
+                wins: 0,
+                draws: 0,
+                losses: 0,
+                score: scores[player],
+                sonneborn_berger: 0.0,
+                head_to_head: 0.0,
+            })
+            .collect();
+
+        for game in &self.games {
+            let Some(outcome) = game.outcome else {
+                continue;
+            };
+            for (player, opponent, color) in [
+                (game.white, game.black, Color::White),
+                (game.black, game.white, Color::Black),
+            ] {
+                let standing = &mut standings[player];
+                standing.games += 1;
+                let points = match outcome.result.winner() {
+                    Some(winner) if winner == color => {
+                        standing.wins += 1;
+                        1.0
+                    }
+                    Some(_) => {
+                        standing.losses += 1;
+                        0.0
+                    }
+                    None => {
+                        standing.draws += 1;
+                        0.5
+                    }
+                };
+                // beating a strong opponent is worth more than beating a
+                // weak one
+                standing.sonneborn_berger += points * scores[opponent];
+                *results[player][opponent].get_or_insert(0.0) += points;
+            }
+        }
+
+        for standing in standings.iter_mut() {
+            standing.head_to_head = (0..n)
+                .filter(|&o| o != standing.player && scores[o] == standing.score)
+                .filter_map(|o| results[standing.player][o])
+                .sum();
+        }
+
+        standings.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then(b.head_to_head.total_cmp(&a.head_to_head))
+                .then(b.sonneborn_berger.total_cmp(&a.sonneborn_berger))
+                .then(b.wins.cmp(&a.wins))
+                .then(a.player.cmp(&b.player))
+        });
+        for (i, standing) in standings.iter_mut().enumerate() {
+            standing.rank = i + 1;
+        }
+
+        Crosstable { standings, results }
+    }
+
+    pub fn summary(&self) -> TournamentSummary {
+        TournamentSummary {
+            id: self.id,
+            name: self.name.clone(),
+            format: self.config.format.clone(),
+            status: self.status,
+            players: self.participants.len(),
+            games_played: self.games.iter().filter(|g| g.outcome.is_some()).count(),
+            games_total: self.games.len(),
+        }
+    }
+}
+
+fn white_points(result: GameResult) -> f64 {
+    match result {
+        GameResult::WhiteWins => 1.0,
+        GameResult::BlackWins => 0.0,
+        GameResult::Draw => 0.5,
+    }
+}
+
+// Pairs players in order, each with the first player further down they
+// haven't met, backtracking when someone is left without an opponent
+fn pair_unmet(players: &[usize], met: &HashSet<(usize, usize)>) -> Option<Vec<(usize, usize)>> {
+    let Some((&first, rest)) = players.split_first() else {
+        return Some(Vec::new());
+    };
+
+    for (i, &opponent) in rest.iter().enumerate() {
+        if met.contains(&(first, opponent)) {
+            continue;
+        }
+        let mut remaining = rest.to_vec();
+        remaining.remove(i);
+        if let Some(mut pairs) = pair_unmet(&remaining, met) {
+            pairs.insert(0, (first, opponent));
+            return Some(pairs);
+        }
+    }
+    None
+}
+
+/// Plays the tournament to the end with its games on `runner`. Every
+/// finished game is rated
+pub async fn run_tournament(
+    tournament: Arc<RwLock<Tournament>>,
+    bots: Arc<BotRegistry>,
+    ratings: Arc<Ratings>,
+    launcher: GameLauncher,
+    runner: GameRunner,
+) {
+    let (id, concurrency) = {
+        let t = tournament.read().unwrap();
+        (t.id, t.config.concurrency)
+    };
+    info!("Tournament {id} started");
+
+    loop {
+        let batch = tournament.write().unwrap().next_batch();
+        if batch.is_empty() {
+            break;
+        }
+
+        let queue = Mutex::new(VecDeque::from(batch));
+        join_all((0..concurrency).map(|_| async {
+            loop {
+                let Some(index) = queue.lock().unwrap().pop_front() else {
+                    break;
+                };
+                play_game(&tournament, index, &bots, &ratings, &launcher, &runner).await;
+            }
+        }))
+        .await;
+    }
+
+    info!("Tournament {id} finished");
+}
+
+async fn play_game(
+    tournament: &RwLock<Tournament>,
+    index: usize,
+    bots: &BotRegistry,
+    ratings: &Ratings,
+    launcher: &GameLauncher,
+    runner: &GameRunner,
+) {
+    let game = {
+        let t = tournament.read().unwrap();
+        let game = &t.games[index];
+        BotGame::new(
+            &t.participants[game.white],
+            &t.participants[game.black],
+            game.opening.as_deref().or(t.config.fen.as_deref()),
+            t.config.time_control,
+            bots,
+        )
+    };
+    // left unplayed rather than played from some other position
+    let game = match game {
+        Ok(game) => game,
+        Err(e) => {
+            error!("Can't start game {index} of the tournament: {e}");
+            return;
+        }
+    };
+    tournament.write().unwrap().games[index].game_id = Some(game.id);
+    let outcome = game.play(ratings, launcher, runner).await;
+    tournament.write().unwrap().games[index].outcome = Some(outcome);
+}
+
+/// A rated game between two registered bots, shared by tournaments and
+/// matches
+pub struct BotGame {
+    pub id: Uuid,
+    pub game: Arc<RwLock<ChessGame>>,
+    white: BotInfo,
+    black: BotInfo,
+    engines: Option<(SharedEngine, SharedEngine)>,
+}
+
+impl BotGame {
+    pub fn new(
+        white: &BotInfo,
+        black: &BotInfo,
+        fen: Option<&str>,
+        time_control: Option<TimeControl>,
+        bots: &BotRegistry,
+    ) -> Result<Self> {
+        let mut game = match fen {
+            Some(fen) => ChessGame::from_fen(fen)?,
+            None => ChessGame::new(),
+        };
+        game.white = white.name.clone();
+        game.black = black.name.clone();
+
+        // a bot that can't even be started loses the game
+        let engines = match (bots.engine(&white.id), bots.engine(&black.id)) {
+            (Ok(w), Ok(b)) => Some((SharedEngine::from(w), SharedEngine::from(b))),
+            (Err(e), _) => {
+                error!("Failed to start {}: {e}", white.name);
+                game.end(GameOutcome::loss_for(
+                    Color::White,
+                    Termination::EngineCrash,
+                ));
+                None
+            }
+            (_, Err(e)) => {
+                error!("Failed to start {}: {e}", black.name);
+                game.end(GameOutcome::loss_for(
+                    Color::Black,
+                    Termination::EngineCrash,
+                ));
+                None
+            }
+        };
+        if let Some(time_control) = time_control {
+            game.set_time_control(time_control);
+        }
+
+        Ok(BotGame {
+            id: Uuid::new_v4(),
+            game: Arc::new(RwLock::new(game)),
+            white: white.clone(),
+            black: black.clone(),
+            engines,
+        })
+    }
+
+    /// Plays the game to the end on `runner` and rates it
+    pub async fn play(
+        self,
+        ratings: &Ratings,
+        launcher: &GameLauncher,
+        runner: &GameRunner,
+    ) -> GameOutcome {
+        let sender = launcher(self.id, self.game.clone());
+        if let Some((white, black)) = self.engines {
+            runner.play(self.game.clone(), white, black, sender).await;
+        }
+
+        let outcome = self
+            .game
+            .read()
+            .unwrap()
+            .outcome()
+            .unwrap_or(GameOutcome::new(
+                GameResult::Draw,
+                Termination::Adjudication,
+            ));
+        info!(
+            "Game {}: {} vs {} {}",
+            self.id,
+            self.white.name,
+            self.black.name,
+            outcome.result.as_str()
+        );
+        ratings.record(self.id, &self.white, &self.black, outcome);
+        outcome
+    }
+}
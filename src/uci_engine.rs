@@ -0,0 +1,431 @@
+// Adapter that lets any engine speaking the UCI protocol (stockfish, lc0, etc)
+// play through the `ChooseMove` trait. The engine is run as a child process,
+// we write commands to its stdin and a background thread forwards every line
+// it prints on stdout into a channel, which lets us put timeouts on every read.
+use anyhow::{anyhow, bail, Result};
+use log::{debug, error, info, warn};
+use shakmaty::uci::Uci;
+use shakmaty::{CastlingMode, Move, MoveList};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::chess_engine::{ChooseMove, SearchInfo, SearchReply, SearchRequest, StopSignal};
+use crate::clock::TimeLeft;
+use crate::protocol::Score;
+
+// How often we look at the stop signal while the engine thinks
+const STOP_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Debug)]
+pub struct UciEngineConfig {
+    /// Path to the engine binary
+    pub path: PathBuf,
+    /// Extra command line arguments passed to the engine
+    pub args: Vec<String>,
+    /// `setoption` values sent after the handshake, as (name, value) pairs
+    pub options: Vec<(String, String)>,
+    /// Time the engine is asked to think for (`go movetime`) in games
+    /// without a clock
+    pub move_time: Duration,
+    /// How long we wait for `uciok` / `readyok` before giving up on the engine
+    pub handshake_timeout: Duration,
+    /// Grace period on top of the time the engine has before we send `stop`
+    pub move_overhead: Duration,
+}
+
+impl UciEngineConfig {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            args: Vec::new(),
+            options: Vec::new(),
+            move_time: Duration::from_millis(250),
+            handshake_timeout: Duration::from_secs(5),
+            move_overhead: Duration::from_millis(1000),
+        }
+    }
+}
+
+/// A running engine process
+struct UciProcess {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+    name: Option<String>,
+}
+
+impl UciProcess {
+    fn spawn(config: &UciEngineConfig) -> Result<Self> {
+        let mut child = Command::new(&config.path)
+            .args(&config.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| anyhow!("Failed to start engine {:?}: {e}", config.path))?;
+
+        let stdin = child.stdin.take().ok_or(anyhow!("Engine has no stdin"))?;
+        let stdout = child.stdout.take().ok_or(anyhow!("Engine has no stdout"))?;
+
+        // the sender is dropped once the engine closes stdout (exits or crashes),
+        // which is how the reading side finds out the process is gone
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut process = Self {
+            child,
+            stdin,
+            lines: rx,
+            name: None,
+        };
+        process.handshake(config)?;
+        Ok(process)
+    }
+
+    fn handshake(&mut self, config: &UciEngineConfig) -> Result<()> {
+        self.send("uci")?;
+        loop {
+            let line = self.read_line(config.handshake_timeout)?;
+            if let Some(name) = line.strip_prefix("id name ") {
+                self.name = Some(name.trim().to_string());
+            } else if line.trim() == "uciok" {
+                break;
+            }
+        }
+
+        for (name, value) in &config.options {
+            self.send(&format!("setoption name {name} value {value}"))?;
+        }
+
+        self.send("ucinewgame")?;
+        self.sync(config.handshake_timeout)?;
+
+        info!(
+            "UCI engine {} ready",
+            self.name.as_deref().unwrap_or("<unnamed>")
+        );
+        Ok(())
+    }
+
+    // Blocks until the engine has processed every command sent so far
+    fn sync(&mut self, timeout: Duration) -> Result<()> {
+        self.send("isready")?;
+        let deadline = Instant::now() + timeout;
+        while self.read_line_until(deadline)?.trim() != "readyok" {}
+        Ok(())
+    }
+
+    fn send(&mut self, command: &str) -> Result<()> {
+        debug!("UCI <- {command}");
+        writeln!(self.stdin, "{command}")
+            .and_then(|_| self.stdin.flush())
+            .map_err(|e| anyhow!("Failed to write to engine: {e}"))
+    }
+
+    fn read_line(&mut self, timeout: Duration) -> Result<String> {
+        self.read_line_until(Instant::now() + timeout)
+    }
+
+    fn read_line_until(&mut self, deadline: Instant) -> Result<String> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        self.poll_line(timeout)?
+            .ok_or(anyhow!("Timed out waiting for engine"))
+    }
+
+    // Waits up to `timeout` for a line, `None` if the engine had nothing to say
+    fn poll_line(&mut self, timeout: Duration) -> Result<Option<String>> {
+        match self.lines.recv_timeout(timeout) {
+            Ok(line) => {
+                debug!("UCI -> {line}");
+                Ok(Some(line))
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                let status = self.child.wait().ok();
+                bail!("Engine process exited unexpectedly ({status:?})")
+            }
+        }
+    }
+
+    // Reads lines until `bestmove`, returning the move token. What the
+    // engine says about its search on the way ends up in `info`, and is
+    // reported as it comes in. Gives up once `stop` goes off
+    fn read_bestmove_until(
+        &mut self,
+        deadline: Instant,
+        stop: &StopSignal,
+        info: &mut SearchInfo,
+        report: &dyn Fn(&SearchInfo),
+    ) -> Result<String> {
+        loop {
+            if stop.is_stopped() {
+                bail!("Search was stopped");
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let Some(line) = self.poll_line(timeout.min(STOP_POLL))? else {
+                if timeout.is_zero() {
+                    bail!("Timed out waiting for engine");
+                }
+                continue;
+            };
+
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("bestmove") => {
+                    return tokens
+                        .next()
+                        .map(str::to_string)
+                        .ok_or(anyhow!("Engine sent bestmove without a move"))
+                }
+                Some("info") if read_info(info, &mut tokens) => report(info),
+                _ => {}
+            }
+        }
+    }
+
+    // `position` is what goes after the `position` command, e.g. `fen <fen>`
+    fn search(
+        &mut self,
+        position: &str,
+        time: Option<&TimeLeft>,
+        stop: &StopSignal,
+        report: &dyn Fn(&SearchInfo),
+        config: &UciEngineConfig,
+    ) -> Result<(String, SearchInfo)> {
+        self.send(&format!("position {position}"))?;
+        let (go, budget) = go_command(time, config.move_time);
+        self.send(&go)?;
+
+        let mut info = SearchInfo::default();
+        let deadline = Instant::now() + budget + config.move_overhead;
+        let best = match self.read_bestmove_until(deadline, stop, &mut info, report) {
+            Ok(m) => m,
+            Err(e) if self.is_running() => {
+                // engine is alive but ignoring its time budget or we want the
+                // move now, ask it to stop and give it one more grace period
+                // to answer
+                warn!("Engine did not answer ({e}), sending stop");
+                self.send("stop")?;
+                let deadline = Instant::now() + config.move_overhead;
+                self.read_bestmove_until(deadline, &StopSignal::new(), &mut info, report)?
+            }
+            Err(e) => return Err(e),
+        };
+        Ok((best, info))
+    }
+
+    fn is_running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+}
+
+// Takes the fields we care about from the tokens after `info`, fields the
+// line doesn't have keep their last value. Returns whether the line had a
+// principal variation, engines send one whenever they're done with a depth.
+// Lines for the other variations of a multi-PV search are left out, as are
+// bound scores, which only say the score is at least or at most that much
+fn read_info<'a>(info: &mut SearchInfo, tokens: impl Iterator<Item = &'a str>) -> bool {
+    let mut tokens = tokens.peekable();
+    let mut line = info.clone();
+    let mut has_pv = false;
+    while let Some(token) = tokens.next() {
+        match token {
+            "depth" => line.depth = tokens.next().and_then(|d| d.parse().ok()),
+            "nodes" => line.nodes = tokens.next().and_then(|n| n.parse().ok()),
+            "nps" => line.nps = tokens.next().and_then(|n| n.parse().ok()),
+            "multipv" if tokens.next() != Some("1") => return false,
+            "score" => {
+                let kind = tokens.next();
+                let value = tokens.next().and_then(|v| v.parse().ok());
+                if tokens
+                    .next_if(|t| matches!(*t, "lowerbound" | "upperbound"))
+                    .is_some()
+                {
+                    continue;
+                }
+                line.score = match (kind, value) {
+                    (Some("cp"), Some(cp)) => Some(Score::Cp(cp)),
+                    (Some("mate"), Some(moves)) => Some(Score::Mate(moves)),
+                    _ => line.score,
+                };
+            }
+            "pv" => {
+                line.pv = tokens.by_ref().map(str::to_string).collect();
+                has_pv = true;
+            }
+            // free text until the end of the line
+            "string" => break,
+            _ => {}
+        }
+    }
+    *info = line;
+    has_pv
+}
+
+// Builds the `go` command for the clock situation, along with how long the
+// engine may think at most
+fn go_command(time: Option<&TimeLeft>, default_move_time: Duration) -> (String, Duration) {
+    let Some(time) = time else {
+        let go = format!("go movetime {}", default_move_time.as_millis());
+        return (go, default_move_time);
+    };
+
+    if let Some(move_time) = time.move_time {
+        return (format!("go movetime {}", move_time.as_millis()), move_time);
+    }
+
+    let mut go = format!(
+        "go wtime {} btime {} winc {} binc {}",
+        time.white.as_millis(),
+        time.black.as_millis(),
+        time.increment.as_millis(),
+        time.increment.as_millis()
+    );
+    if let Some(moves_to_go) = time.moves_to_go {
+        go.push_str(&format!(" movestogo {moves_to_go}"));
+    }
+    (go, time.own() + time.increment)
+}
+
+impl Drop for UciProcess {
+    fn drop(&mut self) {
+        let _ = self.send("quit");
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub struct UciEngine {
+    config: UciEngineConfig,
+    // `None` after the engine crashed or timed out, it is respawned lazily
+    // on the next call to `choose_move`
+    process: Mutex<Option<UciProcess>>,
+}
+
+impl UciEngine {
+    /// Starts the engine and performs the uci handshake, failing if the binary
+    /// can't be started or doesn't speak UCI
+    pub fn new(config: UciEngineConfig) -> Result<Self> {
+        let process = UciProcess::spawn(&config)?;
+        Ok(Self {
+            config,
+            process: Mutex::new(Some(process)),
+        })
+    }
+
+    /// Name the engine reported during the handshake (`id name ...`)
+    pub fn name(&self) -> Option<String> {
+        let process = self.process.lock().unwrap();
+        process.as_ref().and_then(|p| p.name.clone())
+    }
+
+    fn best_move(
+        &self,
+        position: &str,
+        legal_moves: &MoveList,
+        time: Option<&TimeLeft>,
+        stop: &StopSignal,
+        report: &dyn Fn(&SearchInfo),
+    ) -> Result<(Move, SearchInfo)> {
+        let mut process = self.process.lock().unwrap();
+        if process.is_none() {
+            info!("Restarting UCI engine {:?}", self.config.path);
+            *process = Some(UciProcess::spawn(&self.config)?);
+        }
+
+        let result = process
+            .as_mut()
+            .unwrap()
+            .search(position, time, stop, report, &self.config);
+
+        let (best, info) = match result {
+            Ok(best) => best,
+            Err(e) => {
+                // the process is in an unknown state, throw it away
+                *process = None;
+                return Err(e);
+            }
+        };
+
+        let uci = Uci::from_ascii(best.as_bytes())
+            .map_err(|e| anyhow!("Engine sent malformed move {best}: {e}"))?;
+        let m = legal_moves
+            .iter()
+            .find(|m| m.to_uci(CastlingMode::Standard) == uci)
+            .cloned()
+            .ok_or(anyhow!("Engine sent illegal move {best}"))?;
+        Ok((m, info))
+    }
+}
+
+impl ChooseMove for UciEngine {
+    fn choose_move(&self, fen: &str, legal_moves: &MoveList) -> Option<Move> {
+        self.choose_move_timed(fen, legal_moves, None)
+    }
+
+    fn choose_move_timed(
+        &self,
+        fen: &str,
+        legal_moves: &MoveList,
+        time: Option<&TimeLeft>,
+    ) -> Option<Move> {
+        if legal_moves.is_empty() {
+            return None;
+        }
+
+        let position = format!("fen {fen}");
+        match self.best_move(&position, legal_moves, time, &StopSignal::new(), &|_| {}) {
+            Ok((m, _)) => Some(m),
+            Err(e) => {
+                error!("UCI engine {:?} failed to move: {e}", self.config.path);
+                None
+            }
+        }
+    }
+
+    // Sends the whole game rather than just the position, so the engine
+    // knows about repetitions
+    fn search(&self, request: &SearchRequest) -> SearchReply {
+        if request.legal_moves.is_empty() {
+            return SearchReply::default();
+        }
+
+        let mut position = format!("fen {}", request.start_fen);
+        if !request.history.is_empty() {
+            position = format!("{position} moves {}", request.history.join(" "));
+        }
+        let time = request.time.as_ref();
+        let report = |info: &SearchInfo| request.report(info);
+        match self.best_move(
+            &position,
+            &request.legal_moves,
+            time,
+            &request.stop,
+            &report,
+        ) {
+            Ok((m, info)) => SearchReply {
+                best_move: Some(m),
+                score: info.score,
+                depth: info.depth,
+                pv: info.pv,
+                ..SearchReply::default()
+            },
+            Err(e) => {
+                error!("UCI engine {:?} failed to move: {e}", self.config.path);
+                SearchReply::default()
+            }
+        }
+    }
+}
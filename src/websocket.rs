@@ -1,33 +1,170 @@
 use actix::prelude::*;
 use actix_web_actors::ws;
+use anyhow::{anyhow, Result};
+use shakmaty::uci::Uci;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use uuid::Uuid;
 
-use crate::http_server::SharedState;
+use crate::game_runner::GameRunner;
+use crate::player_vs_bot::PlayerSeat;
+use crate::protocol::{self, ClientMessage, ServerMessage};
+
+/// Updates of a game, every connection watching it has a receiver
+pub type GameChannel = broadcast::Sender<Notification>;
+
+/// Updates kept for connections that fall behind. One that misses more
+/// than this gets a fresh snapshot instead
+pub const CHANNEL_CAPACITY: usize = 256;
+
+pub fn game_channel() -> GameChannel {
+    broadcast::channel(CHANNEL_CAPACITY).0
+}
+
+/// Builds a snapshot of the game with the moves after the given ply, `None`
+/// if the game is gone
+pub type Snapshots = Box<dyn Fn(usize) -> Option<ServerMessage>>;
 
 pub struct MyWebSocket {
-    pub connections: SharedState,
+    pub game_id: Uuid,
+    pub channel: GameChannel,
+    snapshots: Snapshots,
+    /// Moves the client already had when it connected
+    from_ply: usize,
+    /// Set on the player's own connection, spectators only watch and chat.
+    /// The bot's replies run on the runner
+    seat: Option<(PlayerSeat, GameRunner)>,
 }
 
 impl MyWebSocket {
-    pub fn new(connections: SharedState) -> Self {
-        Self { connections }
+    pub fn new(game_id: Uuid, channel: GameChannel, snapshots: Snapshots, from_ply: usize) -> Self {
+        Self {
+            game_id,
+            channel,
+            snapshots,
+            from_ply,
+            seat: None,
+        }
+    }
+
+    pub fn with_seat(mut self, seat: PlayerSeat, runner: GameRunner) -> Self {
+        self.seat = Some((seat, runner));
+        self
     }
 
     fn send_message(&self, ctx: &mut <Self as Actor>::Context, message: &str) {
         ctx.text(message);
     }
+
+    fn reply(&self, ctx: &mut <Self as Actor>::Context, message: &ServerMessage) {
+        self.send_message(ctx, &protocol::encode(message));
+    }
+
+    fn send_snapshot(&self, ctx: &mut <Self as Actor>::Context, from_ply: usize) {
+        match (self.snapshots)(from_ply) {
+            Some(snapshot) => self.reply(ctx, &snapshot),
+            None => self.reply(ctx, &ServerMessage::error("The game is gone")),
+        }
+    }
+
+    fn receive(&self, ctx: &mut <Self as Actor>::Context, text: &str) {
+        let message = match protocol::decode::<ClientMessage>(text) {
+            Ok(message) => message,
+            Err(e) => return self.reply(ctx, &ServerMessage::error(e)),
+        };
+        match message {
+            ClientMessage::Resume { from_ply } => self.send_snapshot(ctx, from_ply),
+            // chat goes to everyone watching, the sender included
+            ClientMessage::Chat { name, text } => match ServerMessage::chat(name.as_deref(), &text)
+            {
+                Ok(chat) => broadcast(&self.channel, chat),
+                Err(e) => self.reply(ctx, &ServerMessage::error(e)),
+            },
+            ClientMessage::Move { uci } => self.act(ctx, |seat, runner| play(seat, runner, &uci)),
+            ClientMessage::Resign => self.act(ctx, |seat, _| seat.resign()),
+            ClientMessage::OfferDraw => self.act(ctx, |seat, _| seat.offer_draw()),
+            ClientMessage::Takeback => self.act(ctx, |seat, _| seat.take_back()),
+        }
+    }
+
+    // Whatever happens is broadcast to everyone on the game, the player only
+    // hears back about it directly when it fails
+    fn act(
+        &self,
+        ctx: &mut <Self as Actor>::Context,
+        action: impl FnOnce(&PlayerSeat, &GameRunner) -> Result<()>,
+    ) {
+        let done = match &self.seat {
+            Some((seat, runner)) => action(seat, runner),
+            None => Err(anyhow!("Spectators can't play, only the player can")),
+        };
+        if let Err(e) = done {
+            self.reply(ctx, &ServerMessage::error(e));
+        }
+    }
+}
+
+// The bot replies in a task on the runner so the connection can carry on
+// while it thinks
+fn play(seat: &PlayerSeat, runner: &GameRunner, uci: &str) -> Result<()> {
+    let uci = uci
+        .parse::<Uci>()
+        .map_err(|e| anyhow!("Invalid move {uci}: {e}"))?;
+    if seat.play(&uci)? {
+        let (seat, replier) = (seat.clone(), runner.clone());
+        runner.spawn(async move {
+            if let Err(e) = replier.bot_reply(&seat).await {
+                log::error!("Bot failed to reply in game {}: {e}", seat.game_id);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Sends a message to every connection on a game
+pub fn broadcast(channel: &GameChannel, message: ServerMessage) {
+    // only fails when nobody is watching
+    let _ = channel.send(message.into());
 }
 
 impl Actor for MyWebSocket {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let addr = ctx.address();
-        self.connections.write().unwrap().push(addr);
+        ctx.add_stream(BroadcastStream::new(self.channel.subscribe()));
+        self.reply(
+            ctx,
+            &ServerMessage::Hello {
+                game_id: self.game_id,
+            },
+        );
+        // taken after subscribing so no move falls in between, the client
+        // sorts out moves it gets twice by their ply
+        self.send_snapshot(ctx, self.from_ply);
     }
 }
 
-// How we are supposed to respond to incoming WebSocket Messages, for now
-// just ignore
+// Updates of the game, passed on as they are
+impl StreamHandler<Result<Notification, BroadcastStreamRecvError>> for MyWebSocket {
+    fn handle(
+        &mut self,
+        update: Result<Notification, BroadcastStreamRecvError>,
+        ctx: &mut Self::Context,
+    ) {
+        match update {
+            Ok(notification) => self.send_message(ctx, &notification.0),
+            // the client is too slow to keep up, start it over from scratch
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                log::debug!("Connection to {} missed {missed} updates", self.game_id);
+                self.send_snapshot(ctx, 0);
+            }
+        }
+    }
+}
+
+// Clients talk the json protocol in `protocol`, anything else gets an error
+// back
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWebSocket {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         // process websocket messages
@@ -37,8 +174,10 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWebSocket {
                 ctx.pong(&msg);
             }
             Ok(ws::Message::Pong(_)) => {}
-            Ok(ws::Message::Text(text)) => ctx.text(text),
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Ok(ws::Message::Text(text)) => self.receive(ctx, &text),
+            Ok(ws::Message::Binary(_)) => {
+                self.reply(ctx, &ServerMessage::error("Messages have to be json text"))
+            }
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
                 ctx.stop();
@@ -48,18 +187,12 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWebSocket {
     }
 }
 
-// Define messages for inter-thread communication
-#[derive(Clone)]
+// A `ServerMessage` ready to go out, encoded once for all the connections
+#[derive(Clone, Debug)]
 pub struct Notification(pub String);
 
-impl Message for Notification {
-    type Result = ();
-}
-
-impl Handler<Notification> for MyWebSocket {
-    type Result = ();
-
-    fn handle(&mut self, msg: Notification, ctx: &mut Self::Context) {
-        self.send_message(ctx, &msg.0);
+impl From<ServerMessage> for Notification {
+    fn from(message: ServerMessage) -> Self {
+        Notification(protocol::encode(&message))
     }
 }
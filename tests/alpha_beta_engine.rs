@@ -0,0 +1,108 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use server::alpha_beta_engine::{AlphaBetaConfig, AlphaBetaEngine};
+use server::bot_registry::{BotKind, BotRegistry};
+use server::chess_engine::{ChooseMove, SearchInfo, SearchRequest, StopSignal};
+use server::chess_game::ChessGame;
+use server::protocol::Score;
+
+fn search(config: AlphaBetaConfig, fen: &str) -> server::chess_engine::SearchReply {
+    let engine = AlphaBetaEngine::new(config);
+    engine.search(&SearchRequest::new(&ChessGame::from_fen(fen).unwrap()))
+}
+
+fn shallow() -> AlphaBetaConfig {
+    AlphaBetaConfig {
+        max_depth: 3,
+        ..AlphaBetaConfig::new()
+    }
+}
+
+#[test]
+fn finds_mate_in_one() {
+    let reply = search(shallow(), "6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1");
+    assert_eq!(reply.best_move.unwrap().to_string(), "Ra1-a8");
+    assert_eq!(reply.score, Some(Score::Mate(1)));
+    assert_eq!(reply.pv, ["a1a8"]);
+}
+
+#[test]
+fn takes_a_free_queen() {
+    let reply = search(shallow(), "4k3/8/8/3q4/8/8/8/3RK3 w - - 0 1");
+    assert_eq!(reply.best_move.unwrap().to_string(), "Rd1xd5");
+    assert!(matches!(reply.score, Some(Score::Cp(cp)) if cp > 300));
+}
+
+#[test]
+fn reports_every_depth() {
+    let depths = Arc::new(Mutex::new(Vec::new()));
+    let sink = depths.clone();
+    let game = ChessGame::new();
+    let request = SearchRequest {
+        on_info: Some(Arc::new(move |info: &SearchInfo| {
+            assert!(!info.pv.is_empty());
+            sink.lock().unwrap().push(info.depth.unwrap());
+        })),
+        ..SearchRequest::new(&game)
+    };
+    AlphaBetaEngine::new(shallow()).search(&request);
+    assert_eq!(*depths.lock().unwrap(), [1, 2, 3]);
+}
+
+#[test]
+fn stays_within_its_limits() {
+    let config = AlphaBetaConfig {
+        max_nodes: Some(2_000),
+        ..AlphaBetaConfig::new()
+    };
+    let reply = search(
+        config,
+        "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3",
+    );
+    assert!(reply.best_move.is_some());
+
+    // the lowest level only looks at its own move
+    let reply = search(AlphaBetaConfig::with_skill(0), &ChessGame::new().fen());
+    assert!(reply.best_move.is_some());
+    assert_eq!(reply.depth, Some(1));
+}
+
+#[test]
+fn moves_even_when_stopped() {
+    // deep enough that it would never finish on its own
+    let engine = AlphaBetaEngine::new(AlphaBetaConfig {
+        max_depth: 64,
+        ..AlphaBetaConfig::new()
+    });
+    let game = ChessGame::new();
+
+    let stopped = SearchRequest::new(&game);
+    stopped.stop.stop();
+    let out_of_time = SearchRequest {
+        stop: StopSignal::new().with_deadline(Instant::now()),
+        ..SearchRequest::new(&game)
+    };
+    for request in [stopped, out_of_time] {
+        let best = engine.search(&request).best_move.unwrap();
+        assert!(game.get_legal_moves().contains(&best));
+    }
+}
+
+#[test]
+fn levels_are_bots() {
+    let bots = BotRegistry::new();
+    let levels: Vec<_> = bots
+        .list()
+        .into_iter()
+        .filter(|b| b.kind == BotKind::Builtin && b.id.starts_with("engine-"))
+        .collect();
+    assert_eq!(levels.len(), 4);
+
+    let game = ChessGame::new();
+    let engine = bots.engine("engine-7").unwrap();
+    assert!(engine
+        .choose_move(&game.fen(), &game.get_legal_moves())
+        .is_some());
+    assert!(bots.engine("engine-21").is_err());
+}
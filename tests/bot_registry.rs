@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use server::bot_registry::{BotKind, BotRegistry, RANDOM_BOT_ID};
+use server::chess_game::ChessGame;
+use server::storage::StoredBot;
+
+fn script(body: &str) -> String {
+    format!("Bot = {{}}\nfunction Bot:chooseMove(chess_game, legal_moves)\n{body}\nend\nreturn Bot")
+}
+
+fn registry() -> BotRegistry {
+    BotRegistry::with_random_pacing(Duration::ZERO)
+}
+
+#[test]
+fn registers_lua_bots() {
+    let bots = registry();
+    let bot = bots
+        .register(
+            Some("  First Mover ".to_string()),
+            script("return legal_moves[1]"),
+        )
+        .unwrap();
+    assert_eq!(bot.name, "First Mover");
+    assert_eq!(bot.kind, BotKind::Lua);
+    assert!(bots.list().iter().any(|b| b.id == bot.id));
+    assert_eq!(bots.info(&bot.id).unwrap().name, "First Mover");
+
+    let game = ChessGame::new();
+    let engine = bots.engine(&bot.id).unwrap();
+    assert!(engine
+        .choose_move(&game.fen(), &game.get_legal_moves())
+        .is_some());
+
+    // bots without a name get one
+    let unnamed = bots
+        .register(None, script("return legal_moves[1]"))
+        .unwrap();
+    assert!(unnamed.name.starts_with("bot-"));
+
+    // and stored ones come back under their old id
+    let restored = registry().restore(&StoredBot {
+        id: bot.id.clone(),
+        name: bot.name.clone(),
+        script: script("return legal_moves[1]"),
+    });
+    assert_eq!(restored.id, bot.id);
+
+    // bots from the command line keep their id from one start to the next
+    let first = bots.register_lua("tester", script("return legal_moves[1]"));
+    let again = registry().register_lua("tester", script("return legal_moves[1]"));
+    assert_eq!(first.unwrap().id, "lua-tester");
+    assert_eq!(again.unwrap().id, "lua-tester");
+}
+
+#[test]
+fn rejects_broken_bots() {
+    let bots = registry();
+    let before = bots.list().len();
+
+    // doesn't load
+    assert!(bots.register(None, "Bot = {".to_string()).is_err());
+    // no chooseMove
+    assert!(bots.register(None, "return {}".to_string()).is_err());
+    // only blows up a few moves into the smoke game
+    let late = script(
+        r#"if chess_game.fen:sub(-2) ~= " 1" then error("out of ideas") end
+        return legal_moves[1]"#,
+    );
+    assert!(bots.register(None, late).is_err());
+    // answers with a move that isn't legal
+    assert!(bots.register(None, script(r#"return "e2e5""#)).is_err());
+    // only knows how to play white
+    let white_only = script(
+        r#"if chess_game.side == "black" then error("which way is forward") end
+        return legal_moves[1]"#,
+    );
+    assert!(bots.register(None, white_only).is_err());
+    // answers with a table that has no legal move
+    assert!(bots
+        .register(None, script(r#"return {move = "e2e5", score = 10}"#))
+        .is_err());
+    assert!(bots.register(None, script("return {score = 10}")).is_err());
+
+    assert_eq!(bots.list().len(), before);
+}
+
+#[test]
+fn unknown_ids_are_errors() {
+    let bots = registry();
+    assert!(bots.info(RANDOM_BOT_ID).is_ok());
+    assert!(bots.engine(RANDOM_BOT_ID).is_ok());
+
+    assert!(bots.info("no-such-bot").is_err());
+    assert!(bots.engine("no-such-bot").is_err());
+    assert!(bots.info("uci-missing").is_err());
+}
@@ -0,0 +1,155 @@
+use server::chess_game::{ChessGame, GameOutcome, GameResult, Termination};
+use shakmaty::Color;
+
+mod common;
+use common::play;
+
+const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+#[test]
+fn full_fen() {
+    let mut game = ChessGame::new();
+    assert_eq!(game.fen(), START);
+    assert_eq!(
+        game.board_fen(),
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR"
+    );
+
+    play(&mut game, &["e2e4", "g8f6", "e4e5", "d7d5"]);
+    assert_eq!(
+        game.fen(),
+        "rnbqkb1r/ppp1pppp/5n2/3pP3/8/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 3"
+    );
+    assert_eq!(
+        game.epd(),
+        "rnbqkb1r/ppp1pppp/5n2/3pP3/8/8/PPPP1PPP/RNBQKBNR w KQkq d6"
+    );
+}
+
+#[test]
+fn from_fen() {
+    let fen = "r3k2r/8/8/8/8/8/8/R3K2R b Kq - 12 40";
+    let game = ChessGame::from_fen(fen).unwrap();
+    assert_eq!(game.fen(), fen);
+    assert!(game.uci().contains(&"e8c8".to_string()));
+    assert!(!game.uci().contains(&"e8g8".to_string()));
+
+    assert!(ChessGame::from_fen("not a fen").is_err());
+    // side not to move is in check
+    assert!(ChessGame::from_fen("4k3/8/8/8/8/8/8/4K2r b - - 0 1").is_err());
+}
+
+const KNIGHT_SHUFFLE: [&str; 4] = ["g1f3", "g8f6", "f3g1", "f6g8"];
+
+#[test]
+fn threefold_repetition() {
+    let mut game = ChessGame::new();
+    play(&mut game, &KNIGHT_SHUFFLE);
+    assert_eq!(game.repetition_count(), 2);
+    assert_eq!(game.game_end(), None);
+
+    play(&mut game, &KNIGHT_SHUFFLE);
+    assert_eq!(game.game_end(), Some(Termination::ThreefoldRepetition));
+
+    // without claiming, only the fivefold repetition ends the game
+    game.claim_draws = false;
+    assert_eq!(game.game_end(), None);
+    play(&mut game, &KNIGHT_SHUFFLE);
+    assert_eq!(game.game_end(), None);
+    play(&mut game, &KNIGHT_SHUFFLE);
+    assert_eq!(game.game_end(), Some(Termination::FivefoldRepetition));
+}
+
+#[test]
+fn fifty_move_rule() {
+    let mut game = ChessGame::from_fen("4k3/4p3/8/8/8/8/4P3/R3K3 w - - 98 80").unwrap();
+    play(&mut game, &["a1a2"]);
+    assert_eq!(game.game_end(), None);
+    play(&mut game, &["e8d8"]);
+    assert_eq!(game.game_end(), Some(Termination::FiftyMoveRule));
+
+    game.claim_draws = false;
+    assert_eq!(game.game_end(), None);
+
+    // pawn moves reset the clock
+    let mut game = ChessGame::from_fen("4k3/4p3/8/8/8/8/4P3/R3K3 w - - 98 80").unwrap();
+    play(&mut game, &["e2e4", "e8d8"]);
+    assert_eq!(game.halfmove_clock(), 1);
+    assert_eq!(game.game_end(), None);
+}
+
+#[test]
+fn seventy_five_move_rule() {
+    let mut game = ChessGame::from_fen("4k3/4p3/8/8/8/8/4P3/R3K3 w - - 149 100").unwrap();
+    game.claim_draws = false;
+    play(&mut game, &["a1a2"]);
+    assert_eq!(game.game_end(), Some(Termination::SeventyFiveMoveRule));
+
+    // unless the last move was mate
+    let mut game = ChessGame::from_fen("7k/8/6K1/8/8/8/8/R7 w - - 149 100").unwrap();
+    play(&mut game, &["a1a8"]);
+    assert_eq!(game.game_end(), Some(Termination::Checkmate));
+}
+
+#[test]
+fn insufficient_material() {
+    let mut game = ChessGame::from_fen("4k3/8/8/8/8/8/3q4/4K3 w - - 0 1").unwrap();
+    assert_eq!(game.game_end(), None);
+    play(&mut game, &["e1d2"]);
+    assert_eq!(game.game_end(), Some(Termination::InsufficientMaterial));
+}
+
+#[test]
+fn outcome() {
+    // fool's mate
+    let mut game = ChessGame::new();
+    play(&mut game, &["f2f3", "e7e5", "g2g4"]);
+    assert_eq!(game.outcome(), None);
+    play(&mut game, &["d8h4"]);
+    assert_eq!(
+        game.outcome(),
+        Some(GameOutcome::new(
+            GameResult::BlackWins,
+            Termination::Checkmate
+        ))
+    );
+
+    let mut game = ChessGame::new();
+    game.end(GameOutcome::loss_for(
+        Color::White,
+        Termination::Resignation,
+    ));
+    assert!(game.game_over());
+    assert_eq!(game.outcome().unwrap().result, GameResult::BlackWins);
+
+    // the first ending sticks
+    game.end(GameOutcome::new(
+        GameResult::Draw,
+        Termination::Adjudication,
+    ));
+    assert_eq!(
+        game.outcome().unwrap().termination,
+        Termination::Resignation
+    );
+}
+
+#[test]
+fn take_back() {
+    let mut game = ChessGame::new();
+    game.white = "alpha".to_string();
+    play(&mut game, &KNIGHT_SHUFFLE);
+    play(&mut game, &KNIGHT_SHUFFLE);
+    assert_eq!(game.game_end(), Some(Termination::ThreefoldRepetition));
+
+    // the repetitions since are forgotten too
+    game.take_back(4);
+    assert_eq!(game.history().len(), 4);
+    assert_eq!(game.fen(), START.replace("0 1", "4 3"));
+    assert_eq!(game.repetition_count(), 2);
+    assert_eq!(game.outcome(), None);
+    assert_eq!(game.white, "alpha");
+
+    game.take_back(0);
+    assert!(game.history().is_empty());
+    assert_eq!(game.repetition_count(), 1);
+}
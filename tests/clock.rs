@@ -0,0 +1,195 @@
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use server::chess_engine::{engine_vs_engine, RandomEngine, SharedEngine};
+use server::chess_game::{ChessGame, GameResult, Termination};
+use server::clock::{Clock, TimeControl};
+use server::pgn::to_pgn;
+use server::websocket::game_channel;
+use shakmaty::Color;
+
+mod common;
+use common::play;
+
+const SLACK: Duration = Duration::from_millis(200);
+
+#[test]
+fn increment_is_added_after_the_move() {
+    let mut clock = Clock::new(TimeControl::Increment {
+        base_ms: 1000,
+        increment_ms: 500,
+    });
+    clock.start(Color::White);
+    clock.press().unwrap();
+
+    let white = clock.remaining(Color::White);
+    assert!(white > Duration::from_millis(1500) - SLACK && white <= Duration::from_millis(1500));
+    assert_eq!(clock.state().running.as_deref(), Some("black"));
+    clock.stop();
+    assert!(clock.remaining(Color::Black) > Duration::from_millis(1000) - SLACK);
+    assert_eq!(clock.state().running, None);
+}
+
+#[test]
+fn delays_give_back_short_thinks() {
+    for control in [
+        TimeControl::Bronstein {
+            base_ms: 1000,
+            delay_ms: 5000,
+        },
+        TimeControl::Delay {
+            base_ms: 1000,
+            delay_ms: 5000,
+        },
+    ] {
+        let mut clock = Clock::new(control);
+        clock.start(Color::White);
+        thread::sleep(Duration::from_millis(20));
+        clock.press().unwrap();
+        assert_eq!(clock.remaining(Color::White), Duration::from_millis(1000));
+    }
+}
+
+#[test]
+fn moves_in_tops_up_the_clock() {
+    let mut clock = Clock::new(TimeControl::MovesIn {
+        moves: 2,
+        base_ms: 1000,
+    });
+    clock.start(Color::White);
+    assert_eq!(clock.time_left().unwrap().moves_to_go, Some(2));
+
+    clock.press().unwrap();
+    clock.press().unwrap();
+    assert_eq!(clock.time_left().unwrap().moves_to_go, Some(1));
+    clock.press().unwrap();
+    clock.press().unwrap();
+
+    let white = clock.remaining(Color::White);
+    assert!(white > Duration::from_millis(2000) - SLACK);
+    assert_eq!(clock.time_left().unwrap().moves_to_go, Some(2));
+}
+
+#[test]
+fn flag_falls_on_move_time() {
+    let mut clock = Clock::new(TimeControl::MoveTime { move_ms: 10 });
+    clock.start(Color::White);
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(clock.flagged(), Some(Color::White));
+    assert_eq!(clock.press(), Err(Color::White));
+    assert!(Clock::new(TimeControl::Unlimited).time_left().is_none());
+}
+
+#[test]
+fn pgn_tags() {
+    let increment = TimeControl::Increment {
+        base_ms: 300_000,
+        increment_ms: 2000,
+    };
+    assert_eq!(increment.pgn_tag(), "300+2");
+    assert_eq!(TimeControl::MoveTime { move_ms: 500 }.pgn_tag(), "1/0.5");
+    let moves_in = TimeControl::MovesIn {
+        moves: 40,
+        base_ms: 5_400_000,
+    };
+    assert_eq!(moves_in.pgn_tag(), "40/5400");
+    assert_eq!(TimeControl::Unlimited.pgn_tag(), "-");
+}
+
+#[test]
+fn late_move_forfeits_the_game() {
+    let mut game = ChessGame::new();
+    game.set_time_control(TimeControl::MoveTime { move_ms: 10 });
+    play(&mut game, &["e2e4"]);
+    assert!(game.history()[0].clock_ms.is_some());
+
+    thread::sleep(Duration::from_millis(30));
+    let outcome = game.outcome().unwrap();
+    assert_eq!(outcome.result, GameResult::WhiteWins);
+    assert_eq!(outcome.termination, Termination::TimeForfeit);
+
+    play(&mut game, &["e7e5"]);
+    assert_eq!(game.history().len(), 1);
+    assert_eq!(game.outcome(), Some(outcome));
+    assert_eq!(game.clock_state().unwrap().running, None);
+}
+
+#[test]
+fn flag_against_lone_king_is_a_draw() {
+    let mut game = ChessGame::from_fen("4k3/8/8/8/8/8/8/4K2R w - - 0 1").unwrap();
+    game.set_time_control(TimeControl::MoveTime { move_ms: 10 });
+    thread::sleep(Duration::from_millis(30));
+    play(&mut game, &["h1h8"]);
+
+    let outcome = game.outcome().unwrap();
+    assert_eq!(outcome.result, GameResult::Draw);
+    assert_eq!(outcome.termination, Termination::TimeForfeit);
+}
+
+#[test]
+fn engines_play_on_the_clock() {
+    let mut game = ChessGame::new();
+    game.set_time_control(TimeControl::Increment {
+        base_ms: 2000,
+        increment_ms: 0,
+    });
+    let game = Arc::new(RwLock::new(game));
+    let white: SharedEngine = Arc::new(RandomEngine::with_pacing(Duration::from_secs(10)));
+    let black: SharedEngine = Arc::new(RandomEngine::with_pacing(Duration::ZERO));
+    let channel = game_channel();
+    let mut rx = channel.subscribe();
+
+    // the random engine paces itself to the clock, so a 10s pacing doesn't
+    // make it lose on time
+    thread::spawn(move || engine_vs_engine(game, white, black, channel));
+    thread::sleep(Duration::from_secs(1));
+    let first = rx.try_recv().unwrap();
+    assert!(first.0.contains("\"clock\""));
+    assert!(first.0.contains("\"running\":\"black\""));
+}
+
+#[test]
+fn clock_times_in_pgn() {
+    let mut game = ChessGame::new();
+    game.set_time_control(TimeControl::Increment {
+        base_ms: 300_000,
+        increment_ms: 0,
+    });
+    play(&mut game, &["e2e4"]);
+    play(&mut game, &["e7e5"]);
+
+    let pgn = to_pgn(&game);
+    assert!(pgn.contains("[TimeControl \"300+0\"]\n"));
+    assert!(pgn.contains("1. e4 {[%clk 0:04:59]} 1... e5 {[%clk 0:04:59]} *"));
+}
+
+#[test]
+fn parses_pgn_tags() {
+    for control in [
+        TimeControl::Unlimited,
+        TimeControl::Increment {
+            base_ms: 300_000,
+            increment_ms: 2_500,
+        },
+        TimeControl::MoveTime { move_ms: 500 },
+        TimeControl::MovesIn {
+            moves: 40,
+            base_ms: 5_400_000,
+        },
+    ] {
+        assert_eq!(
+            TimeControl::from_pgn_tag(&control.pgn_tag()).unwrap(),
+            control
+        );
+    }
+    assert_eq!(
+        TimeControl::from_pgn_tag("60").unwrap(),
+        TimeControl::Increment {
+            base_ms: 60_000,
+            increment_ms: 0
+        }
+    );
+    assert!(TimeControl::from_pgn_tag("0/10").is_err());
+    assert!(TimeControl::from_pgn_tag("fast").is_err());
+}
@@ -0,0 +1,15 @@
+// Helpers shared by the integration tests
+use server::chess_game::ChessGame;
+use shakmaty::CastlingMode;
+
+/// Plays `moves`, given in UCI, panics on the first one that isn't legal
+pub fn play(game: &mut ChessGame, moves: &[&str]) {
+    for uci in moves {
+        let m = game
+            .get_legal_moves()
+            .into_iter()
+            .find(|m| m.to_uci(CastlingMode::Standard).to_string() == *uci)
+            .unwrap_or_else(|| panic!("{uci} is not legal in {}", game.fen()));
+        game.make_move(&m);
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use server::chess_engine::{
+    engine_vs_engine, ChooseMove, SearchInfo, SearchReply, SearchRequest, SharedEngine,
+};
+use server::chess_game::{ChessGame, GameOutcome, GameResult, Termination};
+use server::lua_engine::LuaEngine;
+use server::protocol::{decode, Score, ServerMessage};
+use server::uci_engine::{UciEngine, UciEngineConfig};
+use server::websocket::game_channel;
+use shakmaty::{Color, Move, MoveList};
+
+const FIRST_MOVE_BOT: &str = r#"
+Bot = {}
+function Bot:chooseMove(chess_game, legal_moves)
+    return legal_moves[1]
+end
+return Bot
+"#;
+
+fn scripted_uci(reply: &str) -> UciEngine {
+    let mut config = UciEngineConfig::new("sh");
+    config.args = vec![
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/scripted_uci_engine.sh"
+        )
+        .to_string(),
+        "play".to_string(),
+        reply.to_string(),
+    ];
+    config.move_time = Duration::from_millis(10);
+    UciEngine::new(config).unwrap()
+}
+
+#[test]
+fn lua_bot_against_uci_engine() {
+    let game = Arc::new(RwLock::new(ChessGame::new()));
+    let white: SharedEngine = Arc::new(LuaEngine::new(FIRST_MOVE_BOT).unwrap());
+    // only knows one move, so it fails on its second turn
+    let black: SharedEngine = Arc::new(scripted_uci("e7e5"));
+    let channel = game_channel();
+    let mut rx = channel.subscribe();
+
+    engine_vs_engine(game.clone(), white, black, channel);
+
+    let game = game.read().unwrap();
+    assert_eq!(game.history().len(), 3);
+    assert_eq!(game.history()[1].uci, "e7e5");
+    let outcome = game.outcome().unwrap();
+    assert_eq!(outcome.result, GameResult::WhiteWins);
+    assert_eq!(outcome.termination, Termination::EngineCrash);
+
+    // one notification per move, what the uci engine thought on both of
+    // its turns and the game over
+    assert_eq!(std::iter::from_fn(|| rx.try_recv().ok()).count(), 6);
+}
+
+#[test]
+fn black_moves_first_from_position() {
+    let fen = "4k3/4p3/8/8/8/8/4P3/4K3 b - - 0 1";
+    let game = Arc::new(RwLock::new(ChessGame::from_fen(fen).unwrap()));
+    let white: SharedEngine = Arc::new(scripted_uci("e1d1"));
+    let black: SharedEngine = Arc::new(scripted_uci("e8d8"));
+    engine_vs_engine(game.clone(), white, black, game_channel());
+
+    let game = game.read().unwrap();
+    let moves: Vec<_> = game.history().iter().map(|m| m.uci.as_str()).collect();
+    assert_eq!(moves, ["e8d8", "e1d1"]);
+    assert_eq!(game.outcome().unwrap().result, GameResult::WhiteWins);
+}
+
+// Says when it starts thinking, then takes its time and gives up
+struct SlowEngine {
+    thinking: Mutex<mpsc::Sender<()>>,
+    think_time: Duration,
+}
+
+impl ChooseMove for SlowEngine {
+    fn choose_move(&self, _fen: &str, _legal_moves: &MoveList) -> Option<Move> {
+        self.thinking.lock().unwrap().send(()).unwrap();
+        thread::sleep(self.think_time);
+        None
+    }
+}
+
+#[test]
+fn spectators_can_read_while_an_engine_thinks() {
+    let game = Arc::new(RwLock::new(ChessGame::new()));
+    let (thinking_tx, thinking) = mpsc::channel();
+    let slow: SharedEngine = Arc::new(SlowEngine {
+        thinking: Mutex::new(thinking_tx),
+        think_time: Duration::from_secs(2),
+    });
+    let playing = {
+        let game = game.clone();
+        thread::spawn(move || engine_vs_engine(game, slow.clone(), slow, game_channel()))
+    };
+
+    thinking.recv_timeout(Duration::from_secs(1)).unwrap();
+    // what `spectate_game` does, it would wait out the whole think time if
+    // the engine held the lock
+    let (read_tx, read) = mpsc::channel();
+    let spectator = game.clone();
+    thread::spawn(move || read_tx.send(spectator.read().unwrap().fen()).unwrap());
+    let fen = read.recv_timeout(Duration::from_millis(500)).unwrap();
+    assert_eq!(fen, ChessGame::new().fen());
+
+    playing.join().unwrap();
+    assert_eq!(
+        game.read().unwrap().outcome().unwrap().termination,
+        Termination::EngineCrash
+    );
+}
+
+// Keeps every request it gets, answers with the first legal move and
+// whatever `answer` adds to it
+struct RecordingEngine {
+    requests: Mutex<Vec<SearchRequest>>,
+    answer: fn(&SearchRequest) -> SearchReply,
+}
+
+impl RecordingEngine {
+    fn new(answer: fn(&SearchRequest) -> SearchReply) -> Arc<Self> {
+        Arc::new(Self {
+            requests: Mutex::new(Vec::new()),
+            answer,
+        })
+    }
+}
+
+impl ChooseMove for RecordingEngine {
+    fn choose_move(&self, _fen: &str, legal_moves: &MoveList) -> Option<Move> {
+        legal_moves.first().cloned()
+    }
+
+    fn search(&self, request: &SearchRequest) -> SearchReply {
+        self.requests.lock().unwrap().push(request.clone());
+        SearchReply {
+            best_move: request.legal_moves.first().cloned(),
+            ..(self.answer)(request)
+        }
+    }
+}
+
+#[test]
+fn engines_see_the_game_and_can_resign() {
+    let mut game = ChessGame::new();
+    game.white = "Alice".to_string();
+    game.black = "Bob".to_string();
+    let game = Arc::new(RwLock::new(game));
+    let white = RecordingEngine::new(|_| SearchReply::default());
+    let black = RecordingEngine::new(|request| SearchReply {
+        resign: request.history.len() > 2,
+        ..SearchReply::default()
+    });
+    engine_vs_engine(game.clone(), white.clone(), black.clone(), game_channel());
+
+    let game = game.read().unwrap();
+    assert_eq!(
+        game.outcome(),
+        Some(GameOutcome::loss_for(
+            Color::Black,
+            Termination::Resignation
+        ))
+    );
+    // the resignation came instead of black's second move
+    assert_eq!(game.history().len(), 3);
+
+    let requests = black.requests.lock().unwrap();
+    let last = requests.last().unwrap();
+    assert_eq!(last.side(), Color::Black);
+    assert_eq!(last.opponent, "Alice");
+    let played: Vec<_> = game.history().iter().map(|m| m.uci.clone()).collect();
+    assert_eq!(last.history, played);
+    assert_eq!(last.start_fen, ChessGame::new().fen());
+    assert_eq!(white.requests.lock().unwrap()[0].opponent, "Bob");
+}
+
+#[test]
+fn engines_can_agree_to_a_draw() {
+    let game = Arc::new(RwLock::new(ChessGame::new()));
+    let peaceful = RecordingEngine::new(|_| SearchReply {
+        offer_draw: true,
+        ..SearchReply::default()
+    });
+    let channel = game_channel();
+    let mut rx = channel.subscribe();
+    engine_vs_engine(game.clone(), peaceful.clone(), peaceful, channel);
+
+    let game = game.read().unwrap();
+    assert_eq!(game.history().len(), 1);
+    assert_eq!(
+        game.outcome(),
+        Some(GameOutcome::new(GameResult::Draw, Termination::Agreement))
+    );
+    let updates: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+        .map(|update| decode::<ServerMessage>(&update.0).unwrap())
+        .collect();
+    assert_eq!(
+        updates[1],
+        ServerMessage::DrawOffered {
+            by: "white".to_string()
+        }
+    );
+}
+
+#[test]
+fn spectators_see_engines_think() {
+    let game = Arc::new(RwLock::new(ChessGame::new()));
+    // both sides think they're a pawn up, then agree to a draw
+    let optimist = RecordingEngine::new(|request| {
+        request.report(&SearchInfo {
+            depth: Some(4),
+            score: Some(Score::Cp(100)),
+            pv: vec!["a2a3".to_string()],
+            ..SearchInfo::default()
+        });
+        SearchReply {
+            offer_draw: true,
+            ..SearchReply::default()
+        }
+    });
+    let channel = game_channel();
+    let mut rx = channel.subscribe();
+    engine_vs_engine(game, optimist.clone(), optimist, channel);
+
+    let evaluations: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+        .filter_map(|update| match decode(&update.0).unwrap() {
+            ServerMessage::Evaluation {
+                ply, score, side, ..
+            } => Some((ply, score, side.unwrap())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        evaluations,
+        [
+            (0, Score::Cp(100), "white".to_string()),
+            (1, Score::Cp(-100), "black".to_string()),
+        ]
+    );
+}
@@ -0,0 +1,61 @@
+#!/bin/sh
+# Tiny stand-in for a UCI engine, used by the integration tests.
+#
+# usage: scripted_uci_engine.sh <mode> [move]
+#   play    answer every `go` with `bestmove <move>`
+#   multipv like play, but also send bound scores and lines for other moves
+#   option  answer every `go` with the value of the `Move` option
+#   clock   answer `go wtime ...` with `bestmove <move>`, any other `go` with
+#           a null move
+#   slow    ignore `go`, only answer with `bestmove <move>` after `stop`
+#   hang    never answer `go` or `stop`
+#   crash   exit as soon as a search is requested
+#   silent  never finish the `uci` handshake
+mode=$1
+reply=$2
+
+while read -r line; do
+    case "$line" in
+        uci)
+            [ "$mode" = silent ] && continue
+            echo "id name Scripted"
+            echo "option name Move type string default 0000"
+            echo "uciok"
+            ;;
+        isready)
+            echo "readyok"
+            ;;
+        "setoption name Move value "*)
+            [ "$mode" = option ] && reply=${line#setoption name Move value }
+            ;;
+        go*)
+            case "$mode" in
+                crash) exit 3 ;;
+                slow | hang) ;;
+                multipv)
+                    echo "info depth 1 multipv 1 score cp 30 pv $reply"
+                    echo "info depth 1 multipv 2 score cp -50 pv a2a3"
+                    echo "info depth 2 score cp 900 lowerbound nodes 40"
+                    echo "info depth 2 multipv 1 score cp 25 upperbound pv $reply e7e5"
+                    echo "bestmove $reply"
+                    ;;
+                clock)
+                    case "$line" in
+                        "go wtime "*) echo "bestmove $reply" ;;
+                        *) echo "bestmove 0000" ;;
+                    esac
+                    ;;
+                *)
+                    echo "info depth 1 score cp 0 pv $reply"
+                    echo "bestmove $reply"
+                    ;;
+            esac
+            ;;
+        stop)
+            [ "$mode" = slow ] && echo "bestmove $reply"
+            ;;
+        quit)
+            exit 0
+            ;;
+    esac
+done
@@ -0,0 +1,138 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use server::chess_engine::{ChooseMove, RandomEngine, SharedEngine};
+use server::chess_game::ChessGame;
+use server::clock::TimeControl;
+use server::game_runner::GameRunner;
+use server::protocol::{decode, ServerMessage};
+use server::websocket::{game_channel, CHANNEL_CAPACITY};
+use shakmaty::{Move, MoveList};
+use tokio::sync::broadcast::error::TryRecvError;
+
+// Random mover that keeps track of how many of its kind think at once
+struct CountingEngine {
+    thinking: Arc<AtomicUsize>,
+    most: Arc<AtomicUsize>,
+}
+
+impl ChooseMove for CountingEngine {
+    fn choose_move(&self, fen: &str, legal_moves: &MoveList) -> Option<Move> {
+        let now = self.thinking.fetch_add(1, Ordering::SeqCst) + 1;
+        self.most.fetch_max(now, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(1));
+        let chosen = RandomEngine::with_pacing(Duration::ZERO).choose_move(fen, legal_moves);
+        self.thinking.fetch_sub(1, Ordering::SeqCst);
+        chosen
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn many_games_share_a_few_threads() {
+    let runner = GameRunner::new(2);
+    let thinking = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+    let engine: SharedEngine = Arc::new(CountingEngine {
+        thinking: thinking.clone(),
+        most: most.clone(),
+    });
+
+    let games: Vec<_> = (0..50)
+        .map(|_| Arc::new(RwLock::new(ChessGame::new())))
+        .collect();
+    let tasks: Vec<_> = games
+        .iter()
+        .map(|game| {
+            let (player, game, engine) = (runner.clone(), game.clone(), engine.clone());
+            runner.spawn(async move {
+                player
+                    .play(game, engine.clone(), engine, game_channel())
+                    .await
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    assert!(games.iter().all(|g| g.read().unwrap().outcome().is_some()));
+    assert_eq!(most.load(Ordering::SeqCst), 2);
+}
+
+// Thinks for a while on its first move only
+struct SlowStarter {
+    started: AtomicBool,
+}
+
+impl ChooseMove for SlowStarter {
+    fn choose_move(&self, fen: &str, legal_moves: &MoveList) -> Option<Move> {
+        if !self.started.swap(true, Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(500));
+        }
+        RandomEngine::with_pacing(Duration::ZERO).choose_move(fen, legal_moves)
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn waiting_for_a_slot_is_free() {
+    let runner = GameRunner::new(1);
+    let slow: SharedEngine = Arc::new(SlowStarter {
+        started: AtomicBool::new(false),
+    });
+    let busy = runner.spawn({
+        let runner = runner.clone();
+        let game = Arc::new(RwLock::new(ChessGame::new()));
+        async move { runner.play(game, slow.clone(), slow, game_channel()).await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // waits longer for the only slot than it has on the clock
+    let mut game = ChessGame::new();
+    game.set_time_control(TimeControl::Increment {
+        base_ms: 200,
+        increment_ms: 100,
+    });
+    let game = Arc::new(RwLock::new(game));
+    let random: SharedEngine = Arc::new(RandomEngine::with_pacing(Duration::ZERO));
+    runner
+        .play(game.clone(), random.clone(), random, game_channel())
+        .await;
+    busy.await.unwrap();
+
+    let game = game.read().unwrap();
+    assert!(!game.history().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn every_receiver_gets_the_updates() {
+    let runner = GameRunner::new(1);
+    let channel = game_channel();
+    let mut receivers = [channel.subscribe(), channel.subscribe()];
+    let game = Arc::new(RwLock::new(ChessGame::new()));
+    let random: SharedEngine = Arc::new(RandomEngine::with_pacing(Duration::ZERO));
+
+    runner
+        .play(game.clone(), random.clone(), random, channel)
+        .await;
+
+    let plies = game.read().unwrap().history().len();
+    for receiver in &mut receivers {
+        // long games overflow the channel, the oldest updates are dropped
+        let updates: Vec<ServerMessage> = std::iter::from_fn(|| loop {
+            match receiver.try_recv() {
+                Ok(update) => return Some(update),
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => return None,
+            }
+        })
+        .map(|update| decode(&update.0).unwrap())
+        .collect();
+        assert_eq!(updates.len(), (plies + 1).min(CHANNEL_CAPACITY));
+        assert!(matches!(
+            updates.last(),
+            Some(ServerMessage::GameOver { .. })
+        ));
+    }
+}
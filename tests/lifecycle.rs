@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use server::bot_registry::{BotRegistry, RANDOM_BOT_ID};
+use server::chess_engine::{engine_vs_engine, SharedEngine};
+use server::chess_game::{ChessGame, GameOutcome, GameResult, Termination};
+use server::http_server::GameMap;
+use server::lifecycle::{Lifecycle, LifecycleConfig, ShutdownReport};
+use server::player_vs_bot::PlayerGame;
+use server::protocol::{self, ServerMessage};
+use server::rating::{RatingConfig, Ratings};
+use server::storage::{GameMode, MemoryStorage, Storage, StoredGame};
+use server::websocket::{game_channel, GameChannel};
+use shakmaty::Color;
+use uuid::Uuid;
+
+struct Server {
+    games: Arc<GameMap>,
+    player_games: Arc<DashMap<Uuid, PlayerGame>>,
+    connections: Arc<DashMap<Uuid, GameChannel>>,
+    storage: Arc<dyn Storage>,
+    ratings: Arc<Ratings>,
+    lifecycle: Lifecycle,
+}
+
+fn server(config: LifecycleConfig) -> Server {
+    let games = Arc::new(GameMap::new());
+    let player_games = Arc::new(DashMap::new());
+    let connections = Arc::new(DashMap::new());
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let ratings = Arc::new(Ratings::new(RatingConfig::default()));
+    let lifecycle = Lifecycle::new(
+        config,
+        games.clone(),
+        player_games.clone(),
+        connections.clone(),
+        Arc::new(Mutex::new(HashMap::new())),
+        storage.clone(),
+        ratings.clone(),
+    );
+    Server {
+        games,
+        player_games,
+        connections,
+        storage,
+        ratings,
+        lifecycle,
+    }
+}
+
+#[test]
+fn aborts_idle_player_games() {
+    let server = server(LifecycleConfig {
+        idle_timeout: Duration::ZERO,
+        archive_after: Duration::from_secs(60),
+        ..LifecycleConfig::default()
+    });
+    let bots = BotRegistry::with_random_pacing(Duration::ZERO);
+    let game = PlayerGame::new(
+        bots.engine(RANDOM_BOT_ID).unwrap(),
+        bots.info(RANDOM_BOT_ID).unwrap(),
+        ChessGame::new(),
+    );
+    let id = Uuid::new_v4();
+    server
+        .storage
+        .save_game(&StoredGame::new(id, GameMode::PlayerVsBot, &game.game))
+        .unwrap();
+    server.player_games.insert(id, game);
+    let channel = game_channel();
+    let mut updates = channel.subscribe();
+    server.connections.insert(id, channel);
+
+    let report = server.lifecycle.sweep();
+    assert_eq!(report.abandoned, 1);
+    assert_eq!(report.archived, 0);
+    let outcome = GameOutcome::loss_for(Color::White, Termination::Abandoned);
+    assert_eq!(
+        server.player_games.get(&id).unwrap().game.outcome(),
+        Some(outcome)
+    );
+    assert_eq!(
+        server.storage.game(id).unwrap().unwrap().outcome,
+        Some(outcome)
+    );
+    assert_eq!(server.ratings.get(RANDOM_BOT_ID).unwrap().wins, 1);
+    // the player hears about it
+    let sent = updates.try_recv().unwrap();
+    assert_eq!(
+        protocol::decode::<ServerMessage>(&sent.0).unwrap(),
+        ServerMessage::GameOver { outcome }
+    );
+
+    // already over, nothing left to abort
+    assert_eq!(server.lifecycle.sweep().abandoned, 0);
+}
+
+#[test]
+fn archives_finished_games() {
+    let server = server(LifecycleConfig {
+        archive_after: Duration::ZERO,
+        ..LifecycleConfig::default()
+    });
+    let mut game = ChessGame::new();
+    game.end(GameOutcome::loss_for(
+        Color::Black,
+        Termination::Resignation,
+    ));
+    let id = Uuid::new_v4();
+    server
+        .storage
+        .save_game(&StoredGame::new(id, GameMode::BotVsBot, &game))
+        .unwrap();
+    server.games.insert(id, Arc::new(RwLock::new(game)));
+    let running = Uuid::new_v4();
+    server
+        .games
+        .insert(running, Arc::new(RwLock::new(ChessGame::new())));
+
+    assert_eq!(server.lifecycle.sweep().archived, 1);
+    assert!(!server.games.contains_key(&id));
+    assert!(server.games.contains_key(&running));
+
+    // archived games are loaded back when they're asked for
+    let restored = server.lifecycle.game(id).unwrap();
+    assert_eq!(
+        restored.read().unwrap().outcome().unwrap().result,
+        GameResult::WhiteWins
+    );
+    assert!(server.games.contains_key(&id));
+    assert!(server.lifecycle.game(Uuid::new_v4()).is_none());
+}
+
+#[test]
+fn shutdown_adjudicates_unfinished_games() {
+    let server = server(LifecycleConfig {
+        shutdown_grace: Duration::from_secs(2),
+        ..LifecycleConfig::default()
+    });
+    let bots = BotRegistry::with_random_pacing(Duration::ZERO);
+
+    // random bots without pacing finish well within the grace period
+    let playing = Arc::new(RwLock::new(ChessGame::new()));
+    server.games.insert(Uuid::new_v4(), playing.clone());
+    let white: SharedEngine = Arc::from(bots.engine(RANDOM_BOT_ID).unwrap());
+    let black: SharedEngine = Arc::from(bots.engine(RANDOM_BOT_ID).unwrap());
+    thread::spawn(move || engine_vs_engine(playing, white, black, game_channel()));
+
+    // nobody is playing this one
+    let stuck = Uuid::new_v4();
+    server
+        .games
+        .insert(stuck, Arc::new(RwLock::new(ChessGame::new())));
+
+    assert_eq!(
+        server.lifecycle.shutdown(),
+        ShutdownReport {
+            finished: 1,
+            adjudicated: 1
+        }
+    );
+    let outcome = GameOutcome::new(GameResult::Draw, Termination::Adjudication);
+    assert_eq!(
+        server.games.get(&stuck).unwrap().read().unwrap().outcome(),
+        Some(outcome)
+    );
+    assert_eq!(
+        server.storage.game(stuck).unwrap().unwrap().outcome,
+        Some(outcome)
+    );
+}
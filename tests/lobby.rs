@@ -0,0 +1,73 @@
+use actix_web::web::Query;
+use chrono::{Duration, TimeZone, Utc};
+use server::chess_game::{ChessGame, GameOutcome, GameResult, Termination};
+use server::lobby::{list_games, GameFilter, GameStatus, GameSummary, MAX_PAGE_SIZE};
+use server::storage::{GameMode, StoredGame};
+use uuid::Uuid;
+
+// a game started `days` days into 2024, played by `white` and `black`
+fn summary(days: i64, mode: GameMode, white: &str, black: &str) -> GameSummary {
+    let mut game = ChessGame::new();
+    game.white = white.to_string();
+    game.black = black.to_string();
+    game.started_at = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap() + Duration::days(days);
+    let mut stored = StoredGame::new(Uuid::new_v4(), mode, &game);
+    stored.white_bot = Some(format!("lua-{white}"));
+    GameSummary::new(&stored)
+}
+
+fn filter(query: &str) -> GameFilter {
+    Query::<GameFilter>::from_query(query).unwrap().into_inner()
+}
+
+fn games() -> Vec<GameSummary> {
+    let mut finished = summary(3, GameMode::BotVsBot, "alpha", "beta");
+    let mut game = ChessGame::new();
+    game.end(GameOutcome::new(
+        GameResult::Draw,
+        Termination::Adjudication,
+    ));
+    finished.update(&game);
+    vec![
+        summary(0, GameMode::BotVsBot, "alpha", "gamma"),
+        summary(1, GameMode::PlayerVsBot, "Player", "beta"),
+        summary(2, GameMode::Imported, "Carlsen", "Nepo"),
+        finished,
+    ]
+}
+
+#[test]
+fn filters_games() {
+    let page = list_games(games(), &GameFilter::default());
+    assert_eq!(page.total, 4);
+    // newest first
+    assert_eq!(page.games[0].status, GameStatus::Finished);
+    assert_eq!(page.games[3].black, "gamma");
+
+    let live_bots = list_games(games(), &filter("mode=botVsBot&status=live"));
+    assert_eq!(live_bots.total, 1);
+    assert_eq!(live_bots.games[0].black, "gamma");
+
+    // by name or bot id, on either side
+    assert_eq!(list_games(games(), &filter("bot=BETA")).total, 2);
+    assert_eq!(list_games(games(), &filter("bot=lua-alpha")).total, 2);
+
+    let range = list_games(games(), &filter("from=2024-01-02&to=2024-01-03T12:00:00Z"));
+    assert_eq!(range.total, 1);
+    assert_eq!(range.games[0].mode, GameMode::PlayerVsBot);
+
+    assert!(Query::<GameFilter>::from_query("from=yesterday").is_err());
+    assert!(Query::<GameFilter>::from_query("status=paused").is_err());
+}
+
+#[test]
+fn pages_through_games() {
+    let page = list_games(games(), &filter("offset=1&limit=2"));
+    assert_eq!(page.total, 4);
+    assert_eq!(page.games.len(), 2);
+    assert_eq!(page.games[0].mode, GameMode::Imported);
+
+    assert!(list_games(games(), &filter("offset=10")).games.is_empty());
+    assert_eq!(filter("limit=100000").limit(), MAX_PAGE_SIZE);
+    assert_eq!(filter("limit=0").limit(), 1);
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use server::chess_engine::{ChooseMove, SearchRequest};
+use server::chess_game::ChessGame;
+use server::lua_engine::LuaEngine;
+use server::protocol::Score;
+use shakmaty::uci::Uci;
+
+fn bot(body: &str) -> String {
+    format!("Bot = {{}}\nfunction Bot:chooseMove(chess_game, legal_moves)\n{body}\nend\nreturn Bot")
+}
+
+fn choose(engine: &LuaEngine) -> Option<String> {
+    let game = ChessGame::new();
+    engine
+        .choose_move(&game.fen(), &game.get_legal_moves())
+        .map(|m| m.to_string())
+}
+
+#[test]
+fn runs_template() {
+    let script = std::fs::read_to_string("./client/template.lua").unwrap();
+    let engine = LuaEngine::new(&script).unwrap();
+    assert!(choose(&engine).is_some());
+}
+
+#[test]
+fn passes_position_and_uci_moves() {
+    let engine = LuaEngine::new(&bot(r#"assert(chess_game.fen:find(" w KQkq ") ~= nil)
+           for _, m in ipairs(legal_moves) do
+               if m == "g1f3" then return m end
+           end"#))
+    .unwrap();
+    assert_eq!(choose(&engine).as_deref(), Some("Ng1-f3"));
+}
+
+#[test]
+fn sees_the_game_and_answers_with_a_table() {
+    let engine = LuaEngine::new(&bot(r#"assert(chess_game.side == "black")
+           assert(chess_game.history[1] == "e2e4")
+           assert(chess_game.opponent == "Alice")
+           return {move = "e7e5", score = -20, depth = 2, pv = {"e7e5", "g1f3"}}"#))
+    .unwrap();
+    let mut game = ChessGame::new();
+    game.white = "Alice".to_string();
+    let e4 = "e2e4".parse::<Uci>().unwrap().to_move(&game.game).unwrap();
+    game.make_move(&e4);
+
+    let reply = engine.search(&SearchRequest::new(&game));
+    assert_eq!(reply.best_move.unwrap().to_string(), "e7-e5");
+    assert_eq!(reply.score, Some(Score::Cp(-20)));
+    assert_eq!(reply.depth, Some(2));
+    assert_eq!(reply.pv, ["e7e5", "g1f3"]);
+}
+
+#[test]
+fn rejects_bad_answers() {
+    for body in [
+        "return 'e2e5'",
+        "return 'nonsense'",
+        "return 42",
+        "error('boom')",
+    ] {
+        let engine = LuaEngine::new(&bot(body)).unwrap();
+        assert!(choose(&engine).is_none(), "{body}");
+    }
+}
+
+#[test]
+fn rejects_bad_scripts() {
+    assert!(LuaEngine::new("this is not lua").is_err());
+    assert!(LuaEngine::new("return 1").is_err());
+    assert!(LuaEngine::new("return {}").is_err());
+}
+
+#[test]
+fn sandboxed() {
+    for body in [
+        "return os.execute('true')",
+        "return io.open('/etc/passwd')",
+        "return dofile('/etc/passwd')",
+        "return require('os')",
+    ] {
+        let engine = LuaEngine::new(&bot(body)).unwrap();
+        assert!(choose(&engine).is_none(), "{body}");
+    }
+}
+
+#[test]
+fn stops_runaway_scripts() {
+    let engine =
+        LuaEngine::with_time_limit(&bot("while true do end"), Duration::from_millis(50)).unwrap();
+    assert!(choose(&engine).is_none());
+    assert!(LuaEngine::with_time_limit("while true do end", Duration::from_millis(50)).is_err());
+}
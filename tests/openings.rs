@@ -0,0 +1,157 @@
+use std::fs;
+
+use server::chess_game::ChessGame;
+use server::openings::{
+    parse_epd, parse_pgn_openings, OpeningConfig, OpeningOrder, OpeningSource, OpeningSuite,
+    PolyglotBook,
+};
+use shakmaty::fen::Fen;
+use shakmaty::zobrist::{Zobrist64, ZobristHash};
+use shakmaty::{CastlingMode, Chess, EnPassantMode, Move};
+
+const START_KEY: u64 = 0x463b96181691fc9c;
+const AFTER_E4_KEY: u64 = 0x823c9b50fd114196;
+
+fn entry(key: u64, chess_move: u16, weight: u16) -> Vec<u8> {
+    let mut bytes = key.to_be_bytes().to_vec();
+    bytes.extend(chess_move.to_be_bytes());
+    bytes.extend(weight.to_be_bytes());
+    bytes.extend([0; 4]);
+    bytes
+}
+
+// Polyglot move from square indexes, a1 = 0 and h8 = 63
+fn book_move(from: u16, to: u16) -> u16 {
+    from << 6 | to
+}
+
+fn position(fen: &str) -> Chess {
+    fen.parse::<Fen>()
+        .unwrap()
+        .into_position(CastlingMode::Standard)
+        .unwrap()
+}
+
+#[test]
+fn reads_epd() {
+    let text = "# a comment\n\
+        rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - bm d5; id \"e4\";\n\
+        \n\
+        4k3/8/8/8/8/8/8/4K3 w - - 12 40\n";
+    let positions = parse_epd(text).unwrap();
+    assert_eq!(
+        positions,
+        [
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+            "4k3/8/8/8/8/8/8/4K3 w - - 12 40",
+        ]
+    );
+    assert!(parse_epd("8/8/8/8 w - -").is_err());
+}
+
+#[test]
+fn reads_pgn_games() {
+    let text = "[Event \"one\"]\n\n1. e4 e5 2. Nf3 *\n\n\
+        [Event \"two\"]\n[Result \"1/2-1/2\"]\n\n1. d4 d5 1/2-1/2\n";
+    let after_moves = parse_pgn_openings(text, None).unwrap();
+    assert_eq!(after_moves.len(), 2);
+    assert_eq!(
+        after_moves[0],
+        "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2"
+    );
+
+    let first_ply = parse_pgn_openings(text, Some(1)).unwrap();
+    assert!(first_ply[1].starts_with("rnbqkbnr/pppppppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b"));
+}
+
+#[test]
+fn walks_polyglot_book() {
+    let mut bytes = entry(AFTER_E4_KEY, book_move(52, 36), 1);
+    bytes.extend(entry(START_KEY, book_move(12, 28), 10));
+    // entries don't have to be sorted in the file
+    let book = PolyglotBook::from_bytes(&bytes).unwrap();
+    assert_eq!(book.len(), 2);
+
+    let fen = book.walk(6);
+    assert_eq!(
+        fen,
+        "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2"
+    );
+    assert_eq!(
+        book.walk(1),
+        "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"
+    );
+
+    assert!(PolyglotBook::from_bytes(&[0; 15]).is_err());
+}
+
+#[test]
+fn book_castling_moves() {
+    let pos = position("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+    let key: Zobrist64 = pos.zobrist_hash(EnPassantMode::Legal);
+    // the king "captures" the h1 rook
+    let book = PolyglotBook::from_bytes(&entry(key.0, book_move(4, 7), 1)).unwrap();
+    let moves = book.moves(&pos);
+    assert_eq!(moves.len(), 1);
+    assert!(matches!(moves[0].0, Move::Castle { .. }));
+}
+
+#[test]
+fn sequential_suite() {
+    let config = OpeningConfig {
+        source: OpeningSource::Positions {
+            positions: vec![
+                "4k3/8/8/8/8/8/8/4K3 w - - 0 1".to_string(),
+                "4k3/8/8/8/8/8/8/3K4 w - - 0 1".to_string(),
+            ],
+        },
+        order: OpeningOrder::Sequential,
+    };
+    let suite = OpeningSuite::load(&config).unwrap();
+    assert_eq!(suite.position_count(), Some(2));
+    assert_eq!(suite.opening(0), suite.opening(2));
+    assert_ne!(suite.opening(0), suite.opening(1));
+
+    let random = OpeningSuite::load(&OpeningConfig::default()).unwrap();
+    assert_eq!(random.position_count(), None);
+    assert!(ChessGame::from_fen(&random.opening(0)).is_ok());
+
+    assert!(matches!(
+        OpeningSource::from_path("book.bin", None).unwrap(),
+        OpeningSource::Polyglot { plies: 8, .. }
+    ));
+    assert!(OpeningSource::from_path("openings.doc", None).is_err());
+}
+
+#[test]
+fn files_stay_in_the_openings_directory() {
+    let dir = std::env::temp_dir().join(format!("openings-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("suite.epd"), "4k3/8/8/8/8/8/8/4K3 w - -\n").unwrap();
+    let file = |path: &str| OpeningConfig {
+        source: OpeningSource::Epd {
+            path: path.to_string(),
+        },
+        order: OpeningOrder::Sequential,
+    };
+
+    let inside = file("suite.epd").within(Some(&dir)).unwrap();
+    assert_eq!(
+        OpeningSuite::load(&inside).unwrap().position_count(),
+        Some(1)
+    );
+    for outside in ["/etc/passwd", "../../etc/passwd", "missing.epd"] {
+        assert!(file(outside).within(Some(&dir)).is_err(), "{outside}");
+    }
+    // without a directory only positions sent along are allowed
+    assert!(file("suite.epd").within(None).is_err());
+    assert!(OpeningConfig::default().within(None).is_ok());
+
+    // parse errors don't repeat what they failed to read
+    let error = parse_epd("# header\nroot:x:0:0:root:/root:/bin/sh\n")
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("line 2"), "{error}");
+    assert!(!error.contains("root"), "{error}");
+    fs::remove_dir_all(dir).unwrap();
+}
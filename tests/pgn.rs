@@ -0,0 +1,158 @@
+use server::chess_game::{ChessGame, GameResult, Termination};
+use server::pgn::{from_pgn, to_pgn, PgnError};
+
+mod common;
+use common::play;
+
+#[test]
+fn export_finished_game() {
+    let mut game = ChessGame::new();
+    game.white = "Alice".to_string();
+    game.black = "Bob \"the bot\"".to_string();
+    play(&mut game, &["f2f3", "e7e5", "g2g4", "d8h4"]);
+
+    let pgn = to_pgn(&game);
+    let date = game.started_at.format("%Y.%m.%d").to_string();
+    assert!(pgn.starts_with(&format!(
+        "[Event \"Rustiator game\"]\n[Site \"Rustiator\"]\n[Date \"{date}\"]\n[Round \"-\"]\n\
+         [White \"Alice\"]\n[Black \"Bob \\\"the bot\\\"\"]\n[Result \"0-1\"]\n"
+    )));
+    assert!(pgn.contains("[Termination \"normal\"]\n"));
+    assert!(!pgn.contains("[FEN"));
+    assert!(pgn.ends_with("\n\n1. f3 e5 2. g4 Qh4# {Black wins by checkmate} 0-1\n"));
+}
+
+#[test]
+fn export_game_in_progress_from_position() {
+    let fen = "r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 3 20";
+    let mut game = ChessGame::from_fen(fen).unwrap();
+    play(&mut game, &["e8c8", "e1g1"]);
+
+    let pgn = to_pgn(&game);
+    assert!(pgn.contains("[Result \"*\"]\n"));
+    assert!(pgn.contains("[Termination \"unterminated\"]\n"));
+    assert!(pgn.contains(&format!("[SetUp \"1\"]\n[FEN \"{fen}\"]\n")));
+    assert!(pgn.ends_with("\n\n20... O-O-O 21. O-O *\n"));
+}
+
+#[test]
+fn wraps_long_games() {
+    let mut game = ChessGame::new();
+    game.claim_draws = false;
+    for _ in 0..10 {
+        play(&mut game, &["g1f3", "g8f6", "f3g1", "f6g8"]);
+    }
+
+    let pgn = to_pgn(&game);
+    let movetext = pgn.split("\n\n").nth(1).unwrap();
+    assert!(movetext.lines().count() > 1);
+    assert!(movetext.lines().all(|l| l.len() < 80));
+}
+
+#[test]
+fn round_trip() {
+    let mut game = ChessGame::from_fen("r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 3 20").unwrap();
+    game.white = "Alice".to_string();
+    play(&mut game, &["e8c8", "e1g1", "d8d1", "f1d1"]);
+
+    let imported = from_pgn(&to_pgn(&game)).unwrap();
+    assert_eq!(imported.white, "Alice");
+    assert_eq!(imported.start_fen(), game.start_fen());
+    assert_eq!(imported.fen(), game.fen());
+    assert_eq!(imported.history().len(), 4);
+    assert_eq!(imported.outcome(), None);
+}
+
+#[test]
+fn skips_comments_variations_and_nags() {
+    let pgn = r#"[Event "Casual"]
+[White "A"]
+[Black "B"]
+[Result "1-0"]
+
+% escaped line
+1.e4 {best by test} e5 $1 2. Nf3 (2. f4 exf4 (2... d5) 3. Nf3) 2... Nc6 ; line comment
+3.Bb5!? a6?! 1-0
+"#;
+    let game = from_pgn(pgn).unwrap();
+    let sans: Vec<_> = game.history().iter().map(|m| m.san.as_str()).collect();
+    assert_eq!(sans, ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6"]);
+
+    // no Termination tag to say how
+    let outcome = game.outcome().unwrap();
+    assert_eq!(outcome.result, GameResult::WhiteWins);
+    assert_eq!(outcome.termination, Termination::Unknown);
+    assert!(to_pgn(&game).ends_with(" a6 {White wins} 1-0\n"));
+}
+
+#[test]
+fn reads_castling_with_zeros_and_terminations() {
+    let import = |termination: &str, result: &str| {
+        let pgn = format!(
+            "[Termination \"{termination}\"]\n\n1. e4 e5 2. Nf3 Nc6 3. Bc4 Bc5 4. 0-0 {result}"
+        );
+        from_pgn(&pgn).unwrap().outcome().unwrap()
+    };
+
+    let game = from_pgn("1. e4 e5 2. Nf3 Nc6 3. Bc4 Bc5 4. 0-0 *").unwrap();
+    assert_eq!(game.history().last().unwrap().san, "O-O");
+
+    assert_eq!(
+        import("normal", "1-0").termination,
+        Termination::Resignation
+    );
+    assert_eq!(
+        import("normal", "1/2-1/2").termination,
+        Termination::Agreement
+    );
+    assert_eq!(
+        import("Time forfeit", "0-1").termination,
+        Termination::TimeForfeit
+    );
+    assert_eq!(
+        import("abandoned", "0-1").termination,
+        Termination::Abandoned
+    );
+    assert_eq!(import("death", "0-1").termination, Termination::Abandoned);
+    assert_eq!(
+        import("rules infraction", "1-0").termination,
+        Termination::IllegalMove
+    );
+    assert_eq!(
+        import("adjudication", "1/2-1/2").termination,
+        Termination::Adjudication
+    );
+    assert_eq!(
+        import("emergency", "1/2-1/2").termination,
+        Termination::Adjudication
+    );
+    assert_eq!(
+        import("something else", "1-0").termination,
+        Termination::Unknown
+    );
+}
+
+#[test]
+fn reports_illegal_moves() {
+    let err = from_pgn("1. e4 e5 2. Ke3 *").err().unwrap();
+    assert!(matches!(err, PgnError::IllegalMove { ply: 3, ref san, .. } if san == "Ke3"));
+
+    let err = from_pgn("1. e4 e5 2. Zz9 *").err().unwrap();
+    assert!(matches!(err, PgnError::IllegalMove { ply: 3, .. }));
+
+    let err = from_pgn("1. f3 e5 2. g4 Qh4# 3. a3 0-1").err().unwrap();
+    assert!(matches!(err, PgnError::IllegalMove { ply: 5, .. }));
+
+    assert!(matches!(
+        from_pgn("[FEN \"8/8/8/8/8/8/8/8 w - - 0 1\"]\n\n*"),
+        Err(PgnError::InvalidSetup(_))
+    ));
+    assert!(matches!(
+        from_pgn("1. e4 (1. d4 *"),
+        Err(PgnError::Syntax(_))
+    ));
+    assert!(matches!(
+        from_pgn("[White \"A]\n1. e4 *"),
+        Err(PgnError::Syntax(_))
+    ));
+}
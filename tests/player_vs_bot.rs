@@ -0,0 +1,194 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use server::bot_registry::{BotRegistry, RANDOM_BOT_ID};
+use server::chess_engine::{ChooseMove, Engine, SearchReply, SearchRequest};
+use server::chess_game::{ChessGame, GameOutcome, GameResult, Termination};
+use server::clock::TimeControl;
+use server::player_vs_bot::{PlayerGame, PlayerSeat};
+use server::protocol::{decode, ServerMessage};
+use server::rating::{RatingConfig, Ratings};
+use server::storage::{MemoryStorage, Storage};
+use server::websocket::game_channel;
+use shakmaty::uci::Uci;
+use shakmaty::{Color, Move, MoveList};
+use uuid::Uuid;
+
+// a game against the random bot, already stored like `/new_game` does
+fn seat(game: ChessGame) -> PlayerSeat {
+    let bots = BotRegistry::with_random_pacing(Duration::ZERO);
+    seat_against(bots.engine(RANDOM_BOT_ID).unwrap(), game)
+}
+
+fn seat_against(bot: Engine, game: ChessGame) -> PlayerSeat {
+    let bots = BotRegistry::with_random_pacing(Duration::ZERO);
+    let game = PlayerGame::new(bot, bots.info(RANDOM_BOT_ID).unwrap(), game);
+    let id = Uuid::new_v4();
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    storage.save_game(&game.stored(id)).unwrap();
+    let games = Arc::new(DashMap::new());
+    games.insert(id, game);
+    PlayerSeat::new(
+        id,
+        games,
+        game_channel(),
+        storage,
+        Arc::new(Ratings::new(RatingConfig::default())),
+    )
+}
+
+fn uci(text: &str) -> Uci {
+    text.parse().unwrap()
+}
+
+fn history(seat: &PlayerSeat) -> Vec<String> {
+    let game = seat.games.get(&seat.game_id).unwrap();
+    game.game.history().iter().map(|m| m.uci.clone()).collect()
+}
+
+#[test]
+fn plays_moves_and_takes_them_back() {
+    let seat = seat(ChessGame::new());
+    assert!(seat.play(&uci("e2e4")).unwrap());
+    // the bot hasn't replied yet
+    assert!(seat.play(&uci("d2d4")).is_err());
+    assert!(seat.take_back().is_err());
+
+    seat.bot_reply().unwrap();
+    assert!(seat.play(&uci("e7e5")).is_err());
+    seat.play(&uci("d2d4")).unwrap();
+    seat.bot_reply().unwrap();
+    assert_eq!(history(&seat).len(), 4);
+    let stored = seat.storage.game(seat.game_id).unwrap().unwrap();
+    assert_eq!(stored.moves.len(), 4);
+
+    seat.take_back().unwrap();
+    assert_eq!(history(&seat)[0], "e2e4");
+    assert_eq!(history(&seat).len(), 2);
+    let stored = seat.storage.game(seat.game_id).unwrap().unwrap();
+    assert_eq!(stored.moves.len(), 2);
+    // saving it again keeps the player's seat
+    let token = seat.games.get(&seat.game_id).unwrap().seat_token;
+    assert_eq!(stored.seat_token, Some(token));
+
+    seat.take_back().unwrap();
+    assert!(history(&seat).is_empty());
+    assert!(seat.take_back().is_err());
+}
+
+#[test]
+fn resigning_ends_and_rates_the_game() {
+    let seat = seat(ChessGame::new());
+    seat.resign().unwrap();
+    let outcome = GameOutcome::loss_for(Color::White, Termination::Resignation);
+    assert_eq!(
+        seat.storage.game(seat.game_id).unwrap().unwrap().outcome,
+        Some(outcome)
+    );
+    assert_eq!(seat.ratings.get(RANDOM_BOT_ID).unwrap().wins, 1);
+
+    assert!(seat.resign().is_err());
+    // stray moves after the end aren't stored or announced again
+    let mut updates = seat.channel.subscribe();
+    assert!(seat.play(&uci("e2e4")).is_err());
+    assert!(updates.try_recv().is_err());
+}
+
+#[test]
+fn moves_after_the_flag_fell_lose_once() {
+    let mut game = ChessGame::new();
+    game.set_time_control(TimeControl::Increment {
+        base_ms: 50,
+        increment_ms: 0,
+    });
+    let seat = seat(game);
+    seat.play(&uci("e2e4")).unwrap();
+    seat.bot_reply().unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let mut updates = seat.channel.subscribe();
+    assert!(seat.play(&uci("d2d4")).is_err());
+    let outcome = GameOutcome::loss_for(Color::White, Termination::TimeForfeit);
+    assert_eq!(
+        seat.storage.game(seat.game_id).unwrap().unwrap().outcome,
+        Some(outcome)
+    );
+    assert_eq!(history(&seat).len(), 2);
+    let told = std::iter::from_fn(|| updates.try_recv().ok())
+        .filter(|update| matches!(decode(&update.0).unwrap(), ServerMessage::GameOver { .. }))
+        .count();
+    assert_eq!(told, 1);
+
+    assert!(seat.play(&uci("d2d4")).is_err());
+    assert!(updates.try_recv().is_err());
+}
+
+// Plays its first legal move, takes a draw when it's offered one if `accepts`
+struct DrawTaker {
+    accepts: bool,
+}
+
+impl ChooseMove for DrawTaker {
+    fn choose_move(&self, _fen: &str, legal_moves: &MoveList) -> Option<Move> {
+        legal_moves.first().cloned()
+    }
+
+    fn search(&self, request: &SearchRequest) -> SearchReply {
+        SearchReply {
+            offer_draw: self.accepts && request.draw_offered,
+            ..SearchReply::new(request.legal_moves.first().cloned())
+        }
+    }
+}
+
+#[test]
+fn bots_decide_on_draw_offers() {
+    let taker = seat_against(Box::new(DrawTaker { accepts: true }), ChessGame::new());
+    taker.offer_draw().unwrap();
+    taker.play(&uci("e2e4")).unwrap();
+    taker.bot_reply().unwrap();
+    assert_eq!(history(&taker), ["e2e4"]);
+    assert_eq!(
+        taker.storage.game(taker.game_id).unwrap().unwrap().outcome,
+        Some(GameOutcome::new(GameResult::Draw, Termination::Agreement))
+    );
+
+    // the offer is declined by moving on, and has to be made on the
+    // player's turn
+    let decliner = seat_against(Box::new(DrawTaker { accepts: false }), ChessGame::new());
+    decliner.offer_draw().unwrap();
+    decliner.play(&uci("e2e4")).unwrap();
+    decliner.bot_reply().unwrap();
+    assert_eq!(history(&decliner).len(), 2);
+    let game = decliner.games.get(&decliner.game_id).unwrap();
+    assert_eq!(game.game.outcome(), None);
+    assert_eq!(game.game.draw_offer(), None);
+    drop(game);
+    decliner.play(&uci("d2d4")).unwrap();
+    assert!(decliner.offer_draw().is_err());
+}
+
+// Never comes up with a move
+struct StuckBot;
+
+impl ChooseMove for StuckBot {
+    fn choose_move(&self, _fen: &str, _legal_moves: &MoveList) -> Option<Move> {
+        None
+    }
+}
+
+#[test]
+fn bots_without_a_move_lose() {
+    let seat = seat_against(Box::new(StuckBot), ChessGame::new());
+    seat.play(&uci("e2e4")).unwrap();
+    seat.bot_reply().unwrap();
+
+    let outcome = GameOutcome::loss_for(Color::Black, Termination::EngineCrash);
+    assert_eq!(history(&seat).len(), 1);
+    assert_eq!(
+        seat.storage.game(seat.game_id).unwrap().unwrap().outcome,
+        Some(outcome)
+    );
+}
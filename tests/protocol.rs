@@ -0,0 +1,155 @@
+use serde_json::{json, Value};
+use server::chess_game::{ChessGame, GameOutcome, GameResult, Termination};
+use server::clock::TimeControl;
+use server::protocol::{
+    decode, encode, ClientMessage, Score, ServerMessage, MAX_CHAT_LENGTH, PROTOCOL_VERSION,
+};
+use shakmaty::uci::Uci;
+use uuid::Uuid;
+
+fn wire(message: &ServerMessage) -> Value {
+    serde_json::from_str(&encode(message)).unwrap()
+}
+
+#[test]
+fn server_messages_on_the_wire() {
+    let mut game = ChessGame::new();
+    let e4 = "e2e4".parse::<Uci>().unwrap().to_move(&game.game).unwrap();
+    game.make_move(&e4);
+    assert_eq!(
+        wire(&ServerMessage::position(&game)),
+        json!({
+            "v": PROTOCOL_VERSION,
+            "type": "position",
+            "fen": "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+            "ply": 1,
+            "last_move": {"uci": "e2e4", "san": "e4"},
+        })
+    );
+
+    // the clock only shows up in games that have one
+    game.set_time_control(TimeControl::MoveTime { move_ms: 1000 });
+    assert_eq!(
+        wire(&ServerMessage::position(&game))["clock"]["running"],
+        "black"
+    );
+
+    let outcome = GameOutcome::new(GameResult::BlackWins, Termination::TimeForfeit);
+    assert_eq!(
+        wire(&ServerMessage::GameOver { outcome }),
+        json!({
+            "v": PROTOCOL_VERSION,
+            "type": "gameOver",
+            "outcome": {"result": "blackWins", "termination": "timeForfeit"},
+        })
+    );
+
+    let eval = ServerMessage::Evaluation {
+        ply: 1,
+        score: Score::Mate(-3),
+        side: None,
+        depth: Some(12),
+        nodes: None,
+        nps: None,
+        pv: vec!["e7e5".to_string()],
+    };
+    assert_eq!(wire(&eval)["score"], json!({"mate": -3}));
+    assert_eq!(decode::<ServerMessage>(&encode(&eval)).unwrap(), eval);
+
+    let hello = ServerMessage::Hello {
+        game_id: Uuid::nil(),
+    };
+    assert_eq!(wire(&hello)["game_id"], Uuid::nil().to_string());
+}
+
+#[test]
+fn reads_client_messages() {
+    let chat = decode::<ClientMessage>(r#"{"v":1,"type":"chat","text":"nice move"}"#).unwrap();
+    assert_eq!(
+        chat,
+        ClientMessage::Chat {
+            name: None,
+            text: "nice move".to_string()
+        }
+    );
+
+    let play = decode::<ClientMessage>(r#"{"v":1,"type":"move","uci":"e2e4"}"#).unwrap();
+    assert_eq!(
+        play,
+        ClientMessage::Move {
+            uci: "e2e4".to_string()
+        }
+    );
+    assert_eq!(
+        decode::<ClientMessage>(r#"{"v":1,"type":"offerDraw"}"#).unwrap(),
+        ClientMessage::OfferDraw
+    );
+
+    let error = |text: &str| decode::<ClientMessage>(text).unwrap_err().to_string();
+    assert!(error(r#"{"v":2,"type":"chat","text":"hi"}"#).contains("version 2"));
+    assert!(error(r#"{"type":"chat","text":"hi"}"#).contains("\"v\""));
+    assert!(error(r#"{"v":1,"type":"pause"}"#).contains("Invalid message"));
+    assert!(error("e2e4").contains("Invalid message"));
+}
+
+#[test]
+fn checks_chat() {
+    let Ok(ServerMessage::Chat { name, text, .. }) = ServerMessage::chat(Some("  "), " gg ") else {
+        panic!("chat rejected");
+    };
+    assert_eq!(name, "Spectator");
+    assert_eq!(text, "gg");
+
+    assert!(ServerMessage::chat(None, "   ").is_err());
+    assert!(ServerMessage::chat(None, &"a".repeat(MAX_CHAT_LENGTH + 1)).is_err());
+
+    let start = ServerMessage::Position {
+        fen: String::new(),
+        ply: 0,
+        last_move: None,
+        clock: None,
+    };
+    // optional fields are left out rather than sent as null
+    assert!(!encode(&start).contains("null"));
+}
+
+#[test]
+fn snapshots_resume_from_a_ply() {
+    let mut game = ChessGame::new();
+    game.white = "alpha".to_string();
+    for uci in ["e2e4", "e7e5", "g1f3"] {
+        let m = uci.parse::<Uci>().unwrap().to_move(&game.game).unwrap();
+        game.make_move(&m);
+    }
+
+    let ServerMessage::Snapshot {
+        white,
+        from_ply,
+        moves,
+        ply,
+        outcome,
+        ..
+    } = ServerMessage::snapshot(&game, 1)
+    else {
+        panic!("not a snapshot");
+    };
+    assert_eq!(white, "alpha");
+    assert_eq!((from_ply, ply), (1, 3));
+    let sans: Vec<_> = moves.iter().map(|m| m.san.as_str()).collect();
+    assert_eq!(sans, ["e5", "Nf3"]);
+    assert_eq!(outcome, None);
+
+    // asking for more than was played gives an empty catch up
+    let snapshot = wire(&ServerMessage::snapshot(&game, 10));
+    assert_eq!(snapshot["from_ply"], 3);
+    assert_eq!(snapshot["moves"], json!([]));
+
+    assert_eq!(
+        decode::<ClientMessage>(r#"{"v":1,"type":"resume","from_ply":2}"#).unwrap(),
+        ClientMessage::Resume { from_ply: 2 }
+    );
+    assert_eq!(
+        decode::<ClientMessage>(r#"{"v":1,"type":"resume"}"#).unwrap(),
+        ClientMessage::Resume { from_ply: 0 }
+    );
+}
@@ -0,0 +1,122 @@
+use server::bot_registry::{BotInfo, BotKind};
+use server::chess_game::{GameOutcome, GameResult, Termination};
+use server::rating::{
+    elo_estimate, elo_update, likelihood_of_superiority, Glicko2, RatingConfig, Ratings,
+};
+use uuid::Uuid;
+
+fn bot(id: &str) -> BotInfo {
+    BotInfo {
+        id: id.to_string(),
+        name: id.to_uppercase(),
+        kind: BotKind::Lua,
+    }
+}
+
+fn result(result: GameResult) -> GameOutcome {
+    GameOutcome::new(result, Termination::Checkmate)
+}
+
+fn close(a: f64, b: f64, tolerance: f64) -> bool {
+    (a - b).abs() < tolerance
+}
+
+#[test]
+fn glicko2_matches_the_paper() {
+    // worked example from section 3 of Glickman's paper
+    let player = Glicko2 {
+        rating: 1500.0,
+        deviation: 200.0,
+        volatility: 0.06,
+    };
+    let opponent = |rating, deviation| Glicko2 {
+        rating,
+        deviation,
+        volatility: 0.06,
+    };
+    let updated = player.update(
+        &[
+            (opponent(1400.0, 30.0), 1.0),
+            (opponent(1550.0, 100.0), 0.0),
+            (opponent(1700.0, 300.0), 0.0),
+        ],
+        0.5,
+    );
+
+    assert!(close(updated.rating, 1464.06, 0.01), "{updated:?}");
+    assert!(close(updated.deviation, 151.52, 0.01), "{updated:?}");
+    assert!(close(updated.volatility, 0.05999, 0.00001), "{updated:?}");
+}
+
+#[test]
+fn elo_and_match_statistics() {
+    assert_eq!(elo_update(1500.0, 1500.0, 1.0, 20.0), 1510.0);
+    assert!(close(
+        elo_update(1500.0, 1900.0, 0.0, 32.0),
+        1500.0 - 32.0 / 11.0,
+        1e-9
+    ));
+
+    assert!(close(likelihood_of_superiority(10, 10), 0.5, 1e-9));
+    assert!(close(likelihood_of_superiority(60, 40), 0.977, 0.001));
+
+    // 55% is about +35 Elo
+    let (elo, error) = elo_estimate(40, 30, 30).unwrap();
+    assert!(close(elo, 34.86, 0.01), "{elo}");
+    assert!(error > 0.0 && error < 100.0);
+    assert_eq!(elo_estimate(3, 0, 0), None);
+}
+
+#[test]
+fn records_games_once() {
+    let ratings = Ratings::new(RatingConfig::default());
+    let (a, b) = (bot("a"), bot("b"));
+    let game = Uuid::new_v4();
+    ratings.record(game, &a, &b, result(GameResult::WhiteWins));
+    ratings.record(game, &a, &b, result(GameResult::WhiteWins));
+    ratings.record(Uuid::new_v4(), &b, &a, result(GameResult::Draw));
+
+    let rating_a = ratings.get("a").unwrap();
+    assert_eq!((rating_a.games, rating_a.wins, rating_a.draws), (2, 1, 1));
+    assert!(rating_a.elo > 1500.0);
+    assert!(rating_a.glicko.deviation < 350.0);
+
+    let leaderboard = ratings.leaderboard();
+    assert_eq!(leaderboard[0].rating.id, "a");
+    assert_eq!(leaderboard[0].rank, 1);
+    assert!(leaderboard[0].los_next.unwrap() > 0.5);
+    assert!(leaderboard[0].interval.0 < leaderboard[0].rating.glicko.rating);
+
+    let comparison = ratings.compare("b", "a").unwrap();
+    assert_eq!(
+        (comparison.wins, comparison.draws, comparison.losses),
+        (0, 1, 1)
+    );
+    assert!(comparison.los < 0.5);
+    assert!(ratings.compare("a", "c").is_none());
+
+    // draws the server made up on shutdown don't count
+    let stopped = GameOutcome::new(GameResult::Draw, Termination::Adjudication);
+    ratings.record(Uuid::new_v4(), &a, &b, stopped);
+    assert_eq!(ratings.get("a").unwrap().games, 2);
+}
+
+#[test]
+fn ratings_survive_a_restart() {
+    let path = std::env::temp_dir().join(format!("ratings-{}.json", Uuid::new_v4()));
+    let ratings = Ratings::load(&path, RatingConfig::default()).unwrap();
+    ratings.record(
+        Uuid::new_v4(),
+        &bot("a"),
+        &bot("b"),
+        result(GameResult::BlackWins),
+    );
+
+    let reloaded = Ratings::load(&path, RatingConfig::default()).unwrap();
+    assert_eq!(
+        reloaded.get("b").unwrap().elo,
+        ratings.get("b").unwrap().elo
+    );
+    assert_eq!(reloaded.compare("a", "b").unwrap().losses, 1);
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use server::bot_registry::{BotRegistry, RANDOM_BOT_ID};
+use server::game_runner::GameRunner;
+use server::openings::{OpeningConfig, OpeningOrder, OpeningSource};
+use server::rating::{RatingConfig, Ratings};
+use server::sprt::{
+    pentanomial_elo, pentanomial_llr, pentanomial_los, run_sprt, sprt_bounds, SprtConfig,
+    SprtMatch, SprtStatus,
+};
+use server::tournament::GameLauncher;
+use server::websocket::game_channel;
+use uuid::Uuid;
+
+const FIRST_MOVE_BOT: &str = r#"
+Bot = {}
+function Bot:chooseMove(chess_game, legal_moves)
+    return legal_moves[1]
+end
+return Bot
+"#;
+
+// only kings left, every game is drawn before it starts
+const BARE_KINGS: &str = "4k3/8/8/8/8/8/8/4K3 w - - 0 1";
+
+fn positions(fens: &[&str]) -> OpeningConfig {
+    OpeningConfig {
+        source: OpeningSource::Positions {
+            positions: fens.iter().map(|f| f.to_string()).collect(),
+        },
+        order: OpeningOrder::Sequential,
+    }
+}
+
+fn setup() -> (Arc<BotRegistry>, SprtConfig) {
+    let bots = BotRegistry::with_random_pacing(Duration::ZERO);
+    let candidate = bots
+        .register(Some("candidate".to_string()), FIRST_MOVE_BOT.to_string())
+        .unwrap();
+    let config = SprtConfig::new(candidate.id, RANDOM_BOT_ID.to_string());
+    (Arc::new(bots), config)
+}
+
+#[test]
+fn bounds_and_llr() {
+    let (lower, upper) = sprt_bounds(0.05, 0.05);
+    assert!((lower + 2.944).abs() < 0.001);
+    assert!((upper - 2.944).abs() < 0.001);
+
+    assert_eq!(pentanomial_llr(&[0; 5], 0.0, 5.0), 0.0);
+    // scoring well supports H1, scoring badly H0
+    assert!(pentanomial_llr(&[5, 20, 40, 30, 10], 0.0, 5.0) > 0.0);
+    assert!(pentanomial_llr(&[10, 30, 40, 20, 5], 0.0, 5.0) < 0.0);
+    // more of the same evidence gives a stronger result
+    let few = pentanomial_llr(&[5, 20, 40, 30, 10], 0.0, 5.0);
+    let many = pentanomial_llr(&[50, 200, 400, 300, 100], 0.0, 5.0);
+    assert!(many > 5.0 * few);
+}
+
+#[test]
+fn elo_estimate_from_pairs() {
+    let (elo, error) = pentanomial_elo(&[0, 0, 10, 10, 0]).unwrap();
+    // a score of 62.5%
+    assert!((elo - 88.7).abs() < 0.1, "{elo}");
+    assert!(error > 0.0);
+
+    let (mirrored, _) = pentanomial_elo(&[0, 10, 10, 0, 0]).unwrap();
+    assert!((elo + mirrored).abs() < 0.001);
+    assert_eq!(pentanomial_elo(&[0, 0, 0, 0, 4]), None);
+
+    assert!(pentanomial_los(&[0, 0, 10, 10, 0]).unwrap() > 0.99);
+    assert_eq!(pentanomial_los(&[0, 0, 7, 0, 0]), None);
+}
+
+#[test]
+fn rejects_bad_config() {
+    let (bots, config) = setup();
+    let mut same = config.clone();
+    same.baseline = same.candidate.clone();
+    assert!(SprtMatch::new(Uuid::new_v4(), same, &bots).is_err());
+
+    let mut backwards = config.clone();
+    backwards.elo0 = 10.0;
+    assert!(SprtMatch::new(Uuid::new_v4(), backwards, &bots).is_err());
+
+    let mut alpha = config.clone();
+    alpha.alpha = 0.0;
+    assert!(SprtMatch::new(Uuid::new_v4(), alpha, &bots).is_err());
+
+    let mut opening = config;
+    opening.openings = positions(&["not a fen"]);
+    assert!(SprtMatch::new(Uuid::new_v4(), opening, &bots).is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn draws_accept_h0() {
+    let (bots, mut config) = setup();
+    config.openings = positions(&[BARE_KINGS]);
+    config.max_pairs = 100;
+    let sprt = Arc::new(RwLock::new(
+        SprtMatch::new(Uuid::new_v4(), config, &bots).unwrap(),
+    ));
+
+    let launcher: GameLauncher = Arc::new(|_, _| game_channel());
+    let ratings = Arc::new(Ratings::new(RatingConfig::default()));
+    run_sprt(sprt.clone(), bots, ratings, launcher, GameRunner::new(2)).await;
+
+    let sprt = sprt.read().unwrap();
+    assert_eq!(sprt.status, SprtStatus::AcceptedH0);
+    assert!(sprt.stats.llr <= sprt.stats.lower_bound);
+    assert!(sprt.stats.pairs < 20, "{} pairs", sprt.stats.pairs);
+    assert_eq!(sprt.stats.pentanomial, [0, 0, sprt.stats.pairs, 0, 0]);
+    assert_eq!(sprt.stats.draws, 2 * sprt.stats.pairs);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pairs_swap_colors() {
+    let (bots, mut config) = setup();
+    config.max_pairs = 2;
+    let candidate = bots.info(&config.candidate).unwrap().name;
+    let sprt = Arc::new(RwLock::new(
+        SprtMatch::new(Uuid::new_v4(), config, &bots).unwrap(),
+    ));
+
+    let games = Arc::new(Mutex::new(HashMap::new()));
+    let started = games.clone();
+    let launcher: GameLauncher = Arc::new(move |id, game| {
+        started.lock().unwrap().insert(id, game);
+        game_channel()
+    });
+    let ratings = Arc::new(Ratings::new(RatingConfig::default()));
+    run_sprt(
+        sprt.clone(),
+        bots,
+        ratings.clone(),
+        launcher,
+        GameRunner::new(2),
+    )
+    .await;
+
+    let sprt = sprt.read().unwrap();
+    assert_ne!(sprt.status, SprtStatus::Running);
+    assert_eq!(sprt.pairs.len(), 2);
+    let games = games.lock().unwrap();
+    for pair in &sprt.pairs {
+        assert_eq!(pair.game_ids.len(), 2);
+        assert!(pair.score.is_some());
+        let first = games[&pair.game_ids[0]].read().unwrap();
+        let second = games[&pair.game_ids[1]].read().unwrap();
+        assert_eq!(first.white, candidate);
+        assert_eq!(second.black, candidate);
+        assert_eq!(first.start_fen(), pair.opening);
+        assert_eq!(second.start_fen(), pair.opening);
+    }
+    assert_eq!(ratings.leaderboard().len(), 2);
+}
@@ -0,0 +1,147 @@
+use std::fs;
+use std::sync::Arc;
+
+use server::bot_registry::{BotInfo, BotKind};
+use server::chess_game::{ChessGame, GameOutcome, GameResult, Termination};
+use server::clock::TimeControl;
+use server::rating::{RatingConfig, Ratings};
+use server::storage::{FileStorage, GameMode, MemoryStorage, Storage, StoredBot, StoredGame};
+use shakmaty::Color;
+use uuid::Uuid;
+
+mod common;
+use common::play;
+
+fn data_file() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("storage-{}.jsonl", Uuid::new_v4()))
+}
+
+fn bot(id: &str) -> BotInfo {
+    BotInfo {
+        id: id.to_string(),
+        name: id.to_uppercase(),
+        kind: BotKind::Lua,
+    }
+}
+
+#[test]
+fn restores_games() {
+    let mut game = ChessGame::new();
+    game.white = "Player".to_string();
+    game.black = "Random".to_string();
+    game.set_time_control(TimeControl::Increment {
+        base_ms: 60_000,
+        increment_ms: 1_000,
+    });
+    play(&mut game, &["e2e4", "e7e5", "g1f3"]);
+
+    let stored = StoredGame::new(Uuid::new_v4(), GameMode::PlayerVsBot, &game);
+    let restored = stored.restore().unwrap();
+    assert_eq!(restored.fen(), game.fen());
+    assert_eq!(restored.history(), game.history());
+    assert_eq!(restored.white, "Player");
+    assert_eq!(restored.outcome(), None);
+    // both sides got their increments back
+    assert_eq!(restored.clock().control(), game.clock().control());
+    assert!(restored.clock().remaining(Color::White).as_millis() > 60_000);
+
+    let mut finished = stored.clone();
+    let outcome = GameOutcome::new(GameResult::WhiteWins, Termination::Resignation);
+    finished.outcome = Some(outcome);
+    assert_eq!(finished.restore().unwrap().outcome(), Some(outcome));
+
+    let mut broken = stored;
+    broken.moves[1].uci = "e7e4".to_string();
+    assert!(broken.restore().is_err());
+}
+
+#[test]
+fn memory_storage() {
+    let storage = MemoryStorage::new();
+    let mut game = ChessGame::new();
+    let id = Uuid::new_v4();
+    storage
+        .save_game(&StoredGame::new(id, GameMode::PlayerVsBot, &game))
+        .unwrap();
+
+    play(&mut game, &["d2d4"]);
+    storage.record_moves(id, game.history(), None).unwrap();
+    assert_eq!(storage.games().unwrap()[0].moves.len(), 1);
+    assert!(storage.record_moves(Uuid::new_v4(), &[], None).is_err());
+    assert!(storage.ratings().unwrap().is_none());
+}
+
+#[test]
+fn file_storage_survives_reopening() {
+    let path = data_file();
+    let storage = FileStorage::open(&path).unwrap();
+
+    let mut game = ChessGame::new();
+    let id = Uuid::new_v4();
+    storage
+        .save_game(&StoredGame::new(id, GameMode::PlayerVsBot, &game))
+        .unwrap();
+    play(&mut game, &["e2e4"]);
+    storage.record_moves(id, game.history(), None).unwrap();
+    play(&mut game, &["e7e5"]);
+    let outcome = GameOutcome::new(GameResult::Draw, Termination::Adjudication);
+    storage
+        .record_moves(id, &game.history()[1..], Some(outcome))
+        .unwrap();
+    let bot = StoredBot {
+        id: "lua-1".to_string(),
+        name: "Bot".to_string(),
+        script: "return {}".to_string(),
+    };
+    storage.save_bot(&bot).unwrap();
+    drop(storage);
+
+    // half a line, like a crash in the middle of a write
+    let mut text = fs::read_to_string(&path).unwrap();
+    text.push_str("{\"record\":\"moves\",\"id\":");
+    fs::write(&path, text).unwrap();
+
+    let storage = FileStorage::open(&path).unwrap();
+    let games = storage.games().unwrap();
+    assert_eq!(games.len(), 1);
+    assert_eq!(games[0].moves, game.history());
+    assert_eq!(games[0].outcome, Some(outcome));
+    assert_eq!(storage.bots().unwrap(), [bot]);
+
+    // reopening compacted the file to one line per game and bot
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn ratings_in_storage() {
+    let path = data_file();
+    let storage: Arc<dyn Storage> = Arc::new(FileStorage::open(&path).unwrap());
+    let ratings = Ratings::with_storage(RatingConfig::default(), storage).unwrap();
+    ratings.record(
+        Uuid::new_v4(),
+        &bot("a"),
+        &bot("b"),
+        GameOutcome::new(GameResult::WhiteWins, Termination::Checkmate),
+    );
+    ratings.record(
+        Uuid::new_v4(),
+        &bot("c"),
+        &bot("d"),
+        GameOutcome::new(GameResult::Draw, Termination::Stalemate),
+    );
+
+    // a game only stores the two ratings it changed
+    let written = fs::read_to_string(&path).unwrap();
+    let last: serde_json::Value = serde_json::from_str(written.lines().last().unwrap()).unwrap();
+    assert_eq!(last["bots"].as_array().unwrap().len(), 2);
+    assert_eq!(last["head_to_head"].as_array().unwrap().len(), 1);
+
+    let storage: Arc<dyn Storage> = Arc::new(FileStorage::open(&path).unwrap());
+    let reloaded = Ratings::with_storage(RatingConfig::default(), storage).unwrap();
+    assert_eq!(reloaded.get("a").unwrap().wins, 1);
+    assert_eq!(reloaded.get("b").unwrap().losses, 1);
+    assert_eq!(reloaded.get("d").unwrap().draws, 1);
+    assert_eq!(reloaded.leaderboard().len(), 4);
+    fs::remove_file(path).unwrap();
+}
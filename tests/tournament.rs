@@ -0,0 +1,289 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use server::bot_registry::{BotRegistry, RANDOM_BOT_ID};
+use server::chess_game::{GameOutcome, GameResult, Termination};
+use server::game_runner::GameRunner;
+use server::openings::{OpeningConfig, OpeningOrder, OpeningSource};
+use server::rating::{RatingConfig, Ratings};
+use server::tournament::{
+    run_tournament, BotGame, GameLauncher, Tournament, TournamentConfig, TournamentFormat,
+    TournamentStatus,
+};
+use server::websocket::game_channel;
+use uuid::Uuid;
+
+const FIRST_MOVE_BOT: &str = r#"
+Bot = {}
+function Bot:chooseMove(chess_game, legal_moves)
+    return legal_moves[1]
+end
+return Bot
+"#;
+
+// random bot plus `n - 1` lua bots
+fn registry(n: usize) -> (BotRegistry, Vec<String>) {
+    let bots = BotRegistry::with_random_pacing(Duration::ZERO);
+    let mut ids = vec![RANDOM_BOT_ID.to_string()];
+    for i in 1..n {
+        let bot = bots
+            .register(Some(format!("bot {i}")), FIRST_MOVE_BOT.to_string())
+            .unwrap();
+        ids.push(bot.id);
+    }
+    (bots, ids)
+}
+
+fn config(format: TournamentFormat, bots: Vec<String>) -> TournamentConfig {
+    TournamentConfig {
+        name: None,
+        format,
+        bots,
+        time_control: None,
+        concurrency: 2,
+        fen: None,
+        openings: None,
+    }
+}
+
+fn pairs(tournament: &Tournament) -> Vec<(usize, usize)> {
+    tournament
+        .games
+        .iter()
+        .map(|g| (g.white, g.black))
+        .collect()
+}
+
+// Finishes the next unplayed game between `a` and `b`, `None` is a draw
+fn finish(tournament: &mut Tournament, a: usize, b: usize, winner: Option<usize>) {
+    let game = tournament
+        .games
+        .iter_mut()
+        .find(|g| {
+            g.outcome.is_none() && ((g.white, g.black) == (a, b) || (g.white, g.black) == (b, a))
+        })
+        .unwrap();
+    let result = match winner {
+        Some(w) if w == game.white => GameResult::WhiteWins,
+        Some(_) => GameResult::BlackWins,
+        None => GameResult::Draw,
+    };
+    game.outcome = Some(GameOutcome::new(result, Termination::Adjudication));
+}
+
+#[test]
+fn round_robin_pairs_everyone_once() {
+    let (bots, ids) = registry(4);
+    let tournament = Tournament::new(
+        Uuid::new_v4(),
+        config(TournamentFormat::RoundRobin, ids),
+        &bots,
+    )
+    .unwrap();
+
+    let games = pairs(&tournament);
+    assert_eq!(games.len(), 6);
+    let unique: HashSet<_> = games.iter().map(|&(w, b)| (w.min(b), w.max(b))).collect();
+    assert_eq!(unique.len(), 6);
+    assert_eq!(tournament.games.iter().map(|g| g.round).max(), Some(3));
+    for player in 0..4 {
+        let whites = games.iter().filter(|g| g.0 == player).count();
+        assert!(
+            (1..=2).contains(&whites),
+            "player {player} has {whites} whites"
+        );
+    }
+}
+
+#[test]
+fn odd_round_robin_has_byes() {
+    let (bots, ids) = registry(3);
+    let tournament = Tournament::new(
+        Uuid::new_v4(),
+        config(TournamentFormat::DoubleRoundRobin, ids),
+        &bots,
+    )
+    .unwrap();
+
+    let games = pairs(&tournament);
+    assert_eq!(games.len(), 6);
+    // the second cycle swaps colors
+    for &(white, black) in &games {
+        assert!(games.contains(&(black, white)));
+    }
+    assert_eq!(tournament.byes.len(), 6);
+    assert!(tournament.byes.iter().all(|b| b.points == 0.0));
+}
+
+#[test]
+fn gauntlet_plays_both_colors() {
+    let (bots, ids) = registry(3);
+    let champion = ids[1].clone();
+    let tournament = Tournament::new(
+        Uuid::new_v4(),
+        config(TournamentFormat::Gauntlet { champion }, ids.clone()),
+        &bots,
+    )
+    .unwrap();
+    assert_eq!(pairs(&tournament), [(1, 0), (0, 1), (1, 2), (2, 1)]);
+
+    let unknown = config(
+        TournamentFormat::Gauntlet {
+            champion: "nobody".to_string(),
+        },
+        ids.clone(),
+    );
+    assert!(Tournament::new(Uuid::new_v4(), unknown, &bots).is_err());
+    let twice = config(
+        TournamentFormat::RoundRobin,
+        vec![ids[0].clone(), ids[0].clone()],
+    );
+    assert!(Tournament::new(Uuid::new_v4(), twice, &bots).is_err());
+}
+
+#[test]
+fn crosstable_tiebreaks() {
+    let (bots, ids) = registry(3);
+    let mut tournament = Tournament::new(
+        Uuid::new_v4(),
+        config(TournamentFormat::RoundRobin, ids),
+        &bots,
+    )
+    .unwrap();
+    finish(&mut tournament, 0, 1, Some(0));
+    finish(&mut tournament, 0, 2, None);
+    finish(&mut tournament, 1, 2, Some(1));
+
+    let crosstable = tournament.crosstable();
+    let order: Vec<_> = crosstable.standings.iter().map(|s| s.player).collect();
+    assert_eq!(order, [0, 1, 2]);
+    let first = &crosstable.standings[0];
+    assert_eq!(
+        (first.score, first.wins, first.draws, first.losses),
+        (1.5, 1, 1, 0)
+    );
+    // 1 point against a player on 1, half a point against one on 0.5
+    assert_eq!(first.sonneborn_berger, 1.25);
+    assert_eq!(crosstable.results[0][1], Some(1.0));
+    assert_eq!(crosstable.results[2][0], Some(0.5));
+}
+
+#[test]
+fn head_to_head_breaks_ties() {
+    let (bots, ids) = registry(4);
+    let mut tournament = Tournament::new(
+        Uuid::new_v4(),
+        config(TournamentFormat::RoundRobin, ids),
+        &bots,
+    )
+    .unwrap();
+    // 2 and 3 both end on 1.5 with the same Sonneborn-Berger, 3 won their
+    // game
+    finish(&mut tournament, 0, 1, Some(0));
+    finish(&mut tournament, 0, 2, None);
+    finish(&mut tournament, 0, 3, Some(0));
+    finish(&mut tournament, 1, 2, Some(2));
+    finish(&mut tournament, 1, 3, None);
+    finish(&mut tournament, 2, 3, Some(3));
+
+    let crosstable = tournament.crosstable();
+    let order: Vec<_> = crosstable.standings.iter().map(|s| s.player).collect();
+    assert_eq!(order, [0, 3, 2, 1]);
+    assert_eq!(crosstable.standings[1].head_to_head, 1.0);
+    assert_eq!(crosstable.standings[2].head_to_head, 0.0);
+    assert_eq!(
+        crosstable.standings[1].sonneborn_berger,
+        crosstable.standings[2].sonneborn_berger
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn swiss_runs_to_the_end() {
+    let (bots, ids) = registry(4);
+    let config = config(TournamentFormat::Swiss { rounds: Some(2) }, ids);
+    let tournament = Arc::new(RwLock::new(
+        Tournament::new(Uuid::new_v4(), config, &bots).unwrap(),
+    ));
+
+    let started = Arc::new(AtomicUsize::new(0));
+    let counter = started.clone();
+    let launcher: GameLauncher = Arc::new(move |_, _| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        game_channel()
+    });
+    let ratings = Arc::new(Ratings::new(RatingConfig::default()));
+    run_tournament(
+        tournament.clone(),
+        Arc::new(bots),
+        ratings.clone(),
+        launcher,
+        GameRunner::new(2),
+    )
+    .await;
+
+    let tournament = tournament.read().unwrap();
+    assert_eq!(tournament.status, TournamentStatus::Finished);
+    assert_eq!(started.load(Ordering::SeqCst), 4);
+    assert!(tournament.games.iter().all(|g| g.outcome.is_some()));
+
+    // no rematches in the second round
+    let games = pairs(&tournament);
+    let unique: HashSet<_> = games.iter().map(|&(w, b)| (w.min(b), w.max(b))).collect();
+    assert_eq!(unique.len(), 4);
+
+    let crosstable = tournament.crosstable();
+    let total: f64 = crosstable.standings.iter().map(|s| s.score).sum();
+    assert_eq!(total, 4.0);
+    assert_eq!(ratings.leaderboard().len(), 4);
+}
+
+#[test]
+fn rematches_share_openings() {
+    let (bots, ids) = registry(3);
+    let mut config = config(TournamentFormat::DoubleRoundRobin, ids);
+    config.openings = Some(OpeningConfig {
+        source: OpeningSource::Positions {
+            positions: vec![
+                "4k3/8/8/8/8/8/8/R3K3 w - - 0 1".to_string(),
+                "4k3/8/8/8/8/8/8/1R2K3 w - - 0 1".to_string(),
+                "4k3/8/8/8/8/8/8/2R1K3 w - - 0 1".to_string(),
+            ],
+        },
+        order: OpeningOrder::Sequential,
+    });
+    let tournament = Tournament::new(Uuid::new_v4(), config, &bots).unwrap();
+
+    let openings: HashSet<_> = tournament.games.iter().map(|g| &g.opening).collect();
+    assert_eq!(openings.len(), 3);
+    for game in &tournament.games {
+        let rematch = tournament
+            .games
+            .iter()
+            .find(|g| (g.white, g.black) == (game.black, game.white))
+            .unwrap();
+        assert!(game.opening.is_some());
+        assert_eq!(game.opening, rematch.opening);
+    }
+}
+
+#[test]
+fn bad_starting_positions_are_refused() {
+    let (bots, ids) = registry(2);
+    let (white, black) = (bots.info(&ids[0]).unwrap(), bots.info(&ids[1]).unwrap());
+    assert!(BotGame::new(&white, &black, Some("not a fen"), None, &bots).is_err());
+    assert!(BotGame::new(&white, &black, None, None, &bots).is_ok());
+
+    let mut with_fen = config(TournamentFormat::RoundRobin, ids.clone());
+    with_fen.fen = Some("not a fen".to_string());
+    assert!(Tournament::new(Uuid::new_v4(), with_fen, &bots).is_err());
+    let mut with_openings = config(TournamentFormat::RoundRobin, ids);
+    with_openings.openings = Some(OpeningConfig {
+        source: OpeningSource::Positions {
+            positions: vec!["8/8/8/8 w - -".to_string()],
+        },
+        order: OpeningOrder::Sequential,
+    });
+    assert!(Tournament::new(Uuid::new_v4(), with_openings, &bots).is_err());
+}
@@ -0,0 +1,165 @@
+use shakmaty::Color;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use server::chess_engine::{ChooseMove, SearchInfo, SearchRequest};
+use server::chess_game::ChessGame;
+use server::clock::TimeLeft;
+use server::protocol::Score;
+use server::uci_engine::{UciEngine, UciEngineConfig};
+
+fn scripted(mode: &str, reply: &str) -> UciEngineConfig {
+    let mut config = UciEngineConfig::new("sh");
+    config.args = vec![
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/scripted_uci_engine.sh"
+        )
+        .to_string(),
+        mode.to_string(),
+        reply.to_string(),
+    ];
+    config.move_time = Duration::from_millis(10);
+    config.move_overhead = Duration::from_millis(200);
+    config.handshake_timeout = Duration::from_millis(500);
+    config
+}
+
+fn start_position() -> (String, shakmaty::MoveList) {
+    let game = ChessGame::new();
+    (game.fen(), game.get_legal_moves())
+}
+
+#[test]
+fn plays_bestmove() {
+    let engine = UciEngine::new(scripted("play", "e2e4")).unwrap();
+    assert_eq!(engine.name().as_deref(), Some("Scripted"));
+
+    let (fen, moves) = start_position();
+    let m = engine.choose_move(&fen, &moves).unwrap();
+    assert_eq!(m.to_string(), "e2-e4");
+}
+
+#[test]
+fn forwards_options() {
+    let mut config = scripted("option", "0000");
+    config.options = vec![("Move".to_string(), "g1f3".to_string())];
+    let engine = UciEngine::new(config).unwrap();
+
+    let (fen, moves) = start_position();
+    let m = engine.choose_move(&fen, &moves).unwrap();
+    assert_eq!(m.to_string(), "Ng1-f3");
+}
+
+#[test]
+fn rejects_illegal_move() {
+    let engine = UciEngine::new(scripted("play", "e2e5")).unwrap();
+    let (fen, moves) = start_position();
+    assert!(engine.choose_move(&fen, &moves).is_none());
+}
+
+#[test]
+fn stops_slow_engine() {
+    let engine = UciEngine::new(scripted("slow", "d2d4")).unwrap();
+    let (fen, moves) = start_position();
+    let m = engine.choose_move(&fen, &moves).unwrap();
+    assert_eq!(m.to_string(), "d2-d4");
+}
+
+#[test]
+fn gives_up_on_hung_engine() {
+    let engine = UciEngine::new(scripted("hang", "d2d4")).unwrap();
+    let (fen, moves) = start_position();
+    assert!(engine.choose_move(&fen, &moves).is_none());
+}
+
+#[test]
+fn survives_crash() {
+    let engine = UciEngine::new(scripted("crash", "e2e4")).unwrap();
+    let (fen, moves) = start_position();
+    assert!(engine.choose_move(&fen, &moves).is_none());
+    // the engine is restarted for the next request, and crashes again
+    assert!(engine.choose_move(&fen, &moves).is_none());
+}
+
+#[test]
+fn fails_without_handshake() {
+    assert!(UciEngine::new(scripted("silent", "e2e4")).is_err());
+    assert!(UciEngine::new(UciEngineConfig::new("/does/not/exist")).is_err());
+}
+
+#[test]
+fn sends_clock_times() {
+    let engine = UciEngine::new(scripted("clock", "e2e4")).unwrap();
+    let (fen, moves) = start_position();
+    assert!(engine.choose_move(&fen, &moves).is_none());
+
+    let time = TimeLeft {
+        side_to_move: Color::White,
+        white: Duration::from_millis(100),
+        black: Duration::from_millis(100),
+        increment: Duration::ZERO,
+        moves_to_go: Some(10),
+        move_time: None,
+    };
+    let m = engine.choose_move_timed(&fen, &moves, Some(&time)).unwrap();
+    assert_eq!(m.to_string(), "e2-e4");
+}
+
+#[test]
+fn reports_what_it_thinks() {
+    let engine = UciEngine::new(scripted("play", "e2e4")).unwrap();
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let sink = reports.clone();
+    let request = SearchRequest {
+        on_info: Some(Arc::new(move |info: &SearchInfo| {
+            sink.lock().unwrap().push(info.clone())
+        })),
+        ..SearchRequest::new(&ChessGame::new())
+    };
+    let reply = engine.search(&request);
+    assert_eq!(reply.best_move.unwrap().to_string(), "e2-e4");
+    assert_eq!(reply.score, Some(Score::Cp(0)));
+    assert_eq!(reply.depth, Some(1));
+    assert_eq!(reply.pv, ["e2e4"]);
+    // the fixture sends one info line before its move
+    assert_eq!(reports.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn keeps_to_the_best_line_and_exact_scores() {
+    let engine = UciEngine::new(scripted("multipv", "e2e4")).unwrap();
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let sink = reports.clone();
+    let request = SearchRequest {
+        on_info: Some(Arc::new(move |info: &SearchInfo| {
+            sink.lock().unwrap().push(info.clone())
+        })),
+        ..SearchRequest::new(&ChessGame::new())
+    };
+    let reply = engine.search(&request);
+    assert_eq!(reply.best_move.unwrap().to_string(), "e2-e4");
+    // the second variation and both bounds don't count
+    assert_eq!(reply.score, Some(Score::Cp(30)));
+    assert_eq!(reply.depth, Some(2));
+    assert_eq!(reply.pv, ["e2e4", "e7e5"]);
+    assert_eq!(reports.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn stops_when_asked() {
+    let mut config = scripted("slow", "e2e4");
+    config.move_time = Duration::from_secs(5);
+    let engine = UciEngine::new(config).unwrap();
+
+    let request = SearchRequest::new(&ChessGame::new());
+    let stop = request.stop.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        stop.stop();
+    });
+    let started = Instant::now();
+    let reply = engine.search(&request);
+    assert_eq!(reply.best_move.unwrap().to_string(), "e2-e4");
+    assert!(started.elapsed() < Duration::from_secs(2));
+}